@@ -0,0 +1,67 @@
+//! Programs alarm thresholds into ds18b20 sensors and finds alarming ones with alarm search.
+//! All sensors are connected to gpio6, the led (gpio18) is on while any sensor is alarming.
+//!
+//! `cargo run --example ds18b20_alarm`
+
+use esp32_c3_examples::temp_sensor::alarm::{AlarmEvent, AlarmMonitor, AlarmThresholds};
+use esp32_c3_examples::temp_sensor::{TempSensors, TempSensorsConfig};
+use esp_idf_svc::hal::delay::FreeRtos;
+use esp_idf_svc::hal::gpio::PinDriver;
+use esp_idf_svc::hal::prelude::*;
+
+fn main() -> eyre::Result<()> {
+    // It is necessary to call this function once. Otherwise some patches to the runtime
+    // implemented by esp-idf-sys might not link properly. See https://github.com/esp-rs/esp-idf-template/issues/71
+    esp_idf_svc::sys::link_patches();
+
+    // Bind the log crate to the ESP Logging facilities
+    esp_idf_svc::log::EspLogger::initialize_default();
+
+    let peripherals = Peripherals::take()?;
+    let mut led = PinDriver::output(peripherals.pins.gpio18)?;
+
+    let pin6 = PinDriver::input_output(peripherals.pins.gpio6)?;
+    let mut sensors = TempSensors::new(pin6, TempSensorsConfig::default())?;
+
+    // comfortable room temperature
+    let thresholds = AlarmThresholds::new(18.0, 26.0)?;
+    sensors.discover()?;
+    let addresses = sensors
+        .sensors()
+        .iter()
+        .map(|s| s.address())
+        .collect::<Vec<_>>();
+    for address in &addresses {
+        if let Err(err) = sensors.set_alarm(address, thresholds, false) {
+            log::error!("[{address:?}] can't set alarm: {err}");
+        }
+    }
+
+    let mut monitor = AlarmMonitor::new();
+    loop {
+        for reading in sensors.measure() {
+            log::info!("[{:?}] Temp is: {:?}", reading.address, reading.temperature);
+        }
+
+        match sensors.alarm_search() {
+            Ok(alarming) => {
+                for event in monitor.update(&alarming) {
+                    // publish to MQTT here if needed
+                    match event {
+                        AlarmEvent::Raised(address) => log::warn!("[{address:?}] alarm!"),
+                        AlarmEvent::Cleared(address) => log::info!("[{address:?}] alarm cleared"),
+                    }
+                }
+            }
+            Err(err) => log::warn!("Alarm search failed: {err}"),
+        }
+
+        if monitor.alarming().is_empty() {
+            led.set_low()?;
+        } else {
+            led.set_high()?;
+        }
+
+        FreeRtos::delay_ms(sensors.next_measurement_in().as_millis() as u32);
+    }
+}
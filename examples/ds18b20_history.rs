@@ -0,0 +1,78 @@
+//! Keeps 24 hours of ds18b20 history, saves it to NVS every hour and restores after reboot.
+//! All sensors are connected to gpio6.
+//!
+//! Type `stats`, `csv` or `json` in the serial console (`espflash flash --monitor`).
+//!
+//! `cargo run --example ds18b20_history`
+
+use esp32_c3_examples::temp_sensor::history::{History, HistoryConfig, NVS_NAMESPACE};
+use esp32_c3_examples::temp_sensor::{TempSensors, TempSensorsConfig};
+use esp_idf_svc::hal::delay::FreeRtos;
+use esp_idf_svc::hal::gpio::PinDriver;
+use esp_idf_svc::hal::prelude::*;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+const SAVE_EVERY: Duration = Duration::from_secs(60 * 60);
+
+fn main() -> eyre::Result<()> {
+    // It is necessary to call this function once. Otherwise some patches to the runtime
+    // implemented by esp-idf-sys might not link properly. See https://github.com/esp-rs/esp-idf-template/issues/71
+    esp_idf_svc::sys::link_patches();
+
+    // Bind the log crate to the ESP Logging facilities
+    esp_idf_svc::log::EspLogger::initialize_default();
+
+    let peripherals = Peripherals::take()?;
+    let mut nvs = EspNvs::new(EspDefaultNvsPartition::take()?, NVS_NAMESPACE, true)?;
+    let mut history = History::new(HistoryConfig::default());
+    history.load(&nvs)?;
+
+    let pin6 = PinDriver::input_output(peripherals.pins.gpio6)?;
+    let mut sensors = TempSensors::new(pin6, TempSensorsConfig::default())?;
+
+    // the console is read in the separate thread, stdin blocks
+    let (commands_tx, commands) = mpsc::channel::<String>();
+    std::thread::Builder::new()
+        .stack_size(4096)
+        .spawn(move || {
+            for line in std::io::stdin().lines().map_while(Result::ok) {
+                if commands_tx.send(line).is_err() {
+                    break;
+                }
+            }
+        })?;
+
+    let mut last_save = Instant::now();
+    loop {
+        let now = Instant::now();
+        history.record_readings(&sensors.measure(), now);
+
+        if now.duration_since(last_save) >= SAVE_EVERY {
+            last_save = now;
+            if let Err(err) = history.save(&mut nvs) {
+                log::warn!("Failed to save history: {err}");
+            }
+        }
+
+        for line in commands.try_iter() {
+            match line.trim() {
+                "csv" => println!("{}", history.to_csv()),
+                "json" => println!("{}", history.to_json()),
+                "stats" => {
+                    for sensor in history.sensors() {
+                        println!(
+                            "[{:?}] {:?}",
+                            sensor.address(),
+                            history.stats(&sensor.address())
+                        );
+                    }
+                }
+                other => println!("Unknown command '{other}', use stats, csv or json"),
+            }
+        }
+
+        FreeRtos::delay_ms(sensors.next_measurement_in().as_millis() as u32);
+    }
+}
@@ -0,0 +1,175 @@
+//! Reads ds18b20 sensors and logs them by user assigned names with calibration offsets.
+//! All sensors are connected to gpio6.
+//!
+//! Names are edited from the serial console (`espflash flash --monitor`), type `list`,
+//! `name <address> <name>`, `offset <address> <offset>` or `forget <address>`.
+//! See `temp_sensor::registry` for details. Commands are queued with the way back for
+//! their reply, so other transports can feed the same queue.
+//!
+//! With `mqtt_host` set in `cfg.toml` the same commands are taken from MQTT, replies go
+//! to `esp32c3/<mqtt_client_id>/registry/reply`:
+//! `mosquitto_pub -h HOST -t esp32c3/esp32-c3/registry/cmd -m list`.
+//!
+//! `cargo run --example ds18b20_registry`
+
+use embedded_svc::mqtt::client::QoS;
+use esp32_c3_examples::lock_recover;
+use esp32_c3_examples::mqtt::broker::BrokerConfig;
+use esp32_c3_examples::mqtt::{Mqtt, MqttConfig};
+use esp32_c3_examples::temp_sensor::registry::{self, Registry, RegistryEvent};
+use esp32_c3_examples::temp_sensor::{SensorStatus, TempSensors, TempSensorsConfig};
+use esp32_c3_examples::wifi;
+use esp32_c3_examples::wifi::netif::NetifConfig;
+use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_svc::hal::delay::FreeRtos;
+use esp_idf_svc::hal::gpio::PinDriver;
+use esp_idf_svc::hal::prelude::*;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs};
+use std::sync::{mpsc, Mutex};
+use std::time::Duration;
+
+/// A command line and where its reply goes.
+type Command = (String, Box<dyn FnOnce(String) + Send>);
+
+fn main() -> eyre::Result<()> {
+    // It is necessary to call this function once. Otherwise some patches to the runtime
+    // implemented by esp-idf-sys might not link properly. See https://github.com/esp-rs/esp-idf-template/issues/71
+    esp_idf_svc::sys::link_patches();
+
+    // Bind the log crate to the ESP Logging facilities
+    esp_idf_svc::log::EspLogger::initialize_default();
+
+    let peripherals = Peripherals::take()?;
+    let nvs = EspDefaultNvsPartition::take()?;
+    let mut registry = Registry::load(EspNvs::new(nvs.clone(), registry::NVS_NAMESPACE, true)?)?;
+
+    let pin6 = PinDriver::input_output(peripherals.pins.gpio6)?;
+    let mut sensors = TempSensors::new(pin6, TempSensorsConfig::default())?;
+
+    // the console is read in the separate thread, stdin blocks
+    let (commands_tx, commands) = mpsc::channel::<Command>();
+    let console_tx = commands_tx.clone();
+    std::thread::Builder::new()
+        .stack_size(4096)
+        .spawn(move || {
+            for line in std::io::stdin().lines().map_while(Result::ok) {
+                let reply = Box::new(|text: String| println!("{text}"));
+                if console_tx.send((line, reply)).is_err() {
+                    break;
+                }
+            }
+        })?;
+
+    // MQTT commands are queued for the main loop too, the registry is owned by it
+    if !CONFIG.mqtt_host.is_empty() {
+        let sys_loop = EspSystemEventLoop::take()?;
+        let (wifi, _wifi_thread) = wifi::connect_from_config(
+            peripherals.modem,
+            &sys_loop,
+            nvs,
+            CONFIG.wifi_ssid,
+            CONFIG.wifi_password,
+            NetifConfig::default(),
+        )?;
+        if !wifi.wait_up(Duration::from_secs(60)) {
+            eyre::bail!("Wifi is not connected");
+        }
+
+        let broker = BrokerConfig {
+            scheme: CONFIG.mqtt_scheme.parse()?,
+            port: Some(CONFIG.mqtt_port).filter(|port| *port != 0),
+            client_id: CONFIG.mqtt_client_id.to_string(),
+            username: Some(CONFIG.mqtt_user.to_string()).filter(|user| !user.is_empty()),
+            password: Some(CONFIG.mqtt_password.to_string()).filter(|pass| !pass.is_empty()),
+            ..BrokerConfig::new(CONFIG.mqtt_host)
+        };
+        broker.validate()?;
+        let (mqtt, _mqtt_thread) = Mqtt::start(
+            &broker.url(),
+            &broker.client_configuration(),
+            MqttConfig::default(),
+        )?;
+
+        let base = format!("esp32c3/{}/registry", CONFIG.mqtt_client_id);
+        let (replies, reply_topic) = (mqtt.clone(), format!("{base}/reply"));
+        let mqtt_tx = Mutex::new(commands_tx);
+        mqtt.subscribe(
+            &format!("{base}/cmd"),
+            QoS::AtLeastOnce,
+            move |_, payload| {
+                let line = String::from_utf8_lossy(payload).into_owned();
+                let (mqtt, topic) = (replies.clone(), reply_topic.clone());
+                let reply = Box::new(move |text: String| {
+                    if let Err(err) = mqtt.publish(&topic, QoS::AtLeastOnce, false, text) {
+                        log::warn!("Registry reply failed: {err}");
+                    }
+                });
+                let _ = lock_recover(&mqtt_tx).send((line, reply));
+            },
+        )?;
+        log::info!("Registry commands: {base}/cmd");
+    }
+
+    loop {
+        let readings = sensors.measure();
+
+        let present = sensors
+            .sensors()
+            .iter()
+            .filter(|s| s.status() != SensorStatus::Offline)
+            .map(|s| s.address())
+            .collect::<Vec<_>>();
+        for event in registry.reconcile(&present)? {
+            match event {
+                RegistryEvent::New(address) => log::warn!("New sensor {address:?}, name it"),
+                RegistryEvent::Missing(address) => {
+                    log::warn!("Sensor '{}' is missing", registry.name(&address))
+                }
+                RegistryEvent::Returned(address) => {
+                    log::info!("Sensor '{}' is back", registry.name(&address))
+                }
+            }
+        }
+
+        for reading in readings {
+            let name = registry.name(&reading.address);
+            match reading.temperature {
+                Ok(temp) => log::info!(
+                    "[{name}] Temp is: {:.2}",
+                    registry.calibrate(&reading.address, temp)
+                ),
+                Err(err) => log::warn!("[{name}] {err}"),
+            }
+        }
+
+        for (line, reply) in commands.try_iter() {
+            reply(match registry.execute(&line) {
+                Ok(text) => text,
+                Err(err) => err.to_string(),
+            });
+        }
+
+        FreeRtos::delay_ms(sensors.next_measurement_in().as_millis() as u32);
+    }
+}
+
+#[derive(Debug)]
+#[toml_cfg::toml_config]
+struct Config {
+    #[default("NO SSID")]
+    wifi_ssid: &'static str,
+    #[default("NO PASSWORD")]
+    wifi_password: &'static str,
+    #[default("mqtt")]
+    mqtt_scheme: &'static str,
+    #[default("")]
+    mqtt_host: &'static str,
+    #[default(0)]
+    mqtt_port: u16,
+    #[default("esp32-c3")]
+    mqtt_client_id: &'static str,
+    #[default("")]
+    mqtt_user: &'static str,
+    #[default("")]
+    mqtt_password: &'static str,
+}
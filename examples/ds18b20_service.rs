@@ -0,0 +1,46 @@
+//! Reads all ds18b20 sensors with retries, a broken sensor doesn't reboot the board.
+//! All sensors are connected to gpio6, sensors can be plugged and unplugged on the fly.
+//! Sensors are switched to 10 bits resolution: 0.25°C step, but 4 times faster than 12 bits.
+//! DS18S20 and DS1822 probes can share the bus, other devices are reported and skipped.
+//!
+//! `cargo run --example ds18b20_service`
+
+use esp32_c3_examples::temp_sensor::{Resolution, TempSensors, TempSensorsConfig};
+use esp_idf_svc::hal::delay::FreeRtos;
+use esp_idf_svc::hal::gpio::PinDriver;
+use esp_idf_svc::hal::prelude::*;
+use std::time::Duration;
+
+fn main() -> eyre::Result<()> {
+    // It is necessary to call this function once. Otherwise some patches to the runtime
+    // implemented by esp-idf-sys might not link properly. See https://github.com/esp-rs/esp-idf-template/issues/71
+    esp_idf_svc::sys::link_patches();
+
+    // Bind the log crate to the ESP Logging facilities
+    esp_idf_svc::log::EspLogger::initialize_default();
+
+    let peripherals = Peripherals::take()?;
+
+    let pin6 = PinDriver::input_output(peripherals.pins.gpio6)?;
+    let config = TempSensorsConfig {
+        rediscover_every: Duration::from_secs(30),
+        sample_period: Duration::from_secs(1),
+        resolution: Some(Resolution::Bits10),
+        ..Default::default()
+    };
+    let mut sensors = TempSensors::new(pin6, config)?;
+
+    loop {
+        for reading in sensors.measure() {
+            match reading.temperature {
+                Ok(temp) => log::info!("[{:?}] Temp is: {temp}", reading.address),
+                Err(err) => log::warn!(
+                    "[{:?}] {err}, status: {:?}",
+                    reading.address,
+                    reading.status
+                ),
+            }
+        }
+        FreeRtos::delay_ms(sensors.next_measurement_in().as_millis() as u32);
+    }
+}
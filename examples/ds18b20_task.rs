@@ -0,0 +1,47 @@
+//! Reads ds18b20 sensors in the background thread, the main loop blinks the led (gpio18)
+//! without any delay caused by the bus and prints the latest readings.
+//! All sensors are connected to gpio6.
+//!
+//! `cargo run --example ds18b20_task`
+
+use esp32_c3_examples::temp_sensor::{task, TempSensors, TempSensorsConfig};
+use esp_idf_svc::hal::delay::FreeRtos;
+use esp_idf_svc::hal::gpio::PinDriver;
+use esp_idf_svc::hal::prelude::*;
+
+fn main() -> eyre::Result<()> {
+    // It is necessary to call this function once. Otherwise some patches to the runtime
+    // implemented by esp-idf-sys might not link properly. See https://github.com/esp-rs/esp-idf-template/issues/71
+    esp_idf_svc::sys::link_patches();
+
+    // Bind the log crate to the ESP Logging facilities
+    esp_idf_svc::log::EspLogger::initialize_default();
+
+    let peripherals = Peripherals::take()?;
+    let mut led = PinDriver::output(peripherals.pins.gpio18)?;
+
+    let pin6 = PinDriver::input_output(peripherals.pins.gpio6)?;
+    let sensors = TempSensors::new(pin6, TempSensorsConfig::default())?;
+    let (readings, _handle) = task::spawn(sensors)?;
+
+    let mut last_round = 0;
+    loop {
+        led.toggle()?;
+        FreeRtos::delay_ms(250);
+
+        if readings.round() == last_round {
+            continue;
+        }
+        let snapshot = readings.snapshot();
+        last_round = snapshot.round;
+        for sensor in &snapshot.sensors {
+            log::info!(
+                "[{:?}] {:?} {:?}, read {:?} ago",
+                sensor.address,
+                sensor.temperature,
+                sensor.status,
+                sensor.read_at.map(|at| at.elapsed())
+            );
+        }
+    }
+}
@@ -0,0 +1,46 @@
+//! Heating thermostat: relay on gpio7 (active low module) keeps the first found ds18b20
+//! sensor at 21 Celsius. All sensors are connected to gpio6.
+//!
+//! The relay is turned off when the sensor fails, see `thermostat::ThermostatConfig`.
+//!
+//! `cargo run --example ds18b20_thermostat`
+
+use esp32_c3_examples::temp_sensor::{task, TempSensors, TempSensorsConfig};
+use esp32_c3_examples::thermostat::{Relay, Thermostat, ThermostatConfig};
+use esp_idf_svc::hal::delay::FreeRtos;
+use esp_idf_svc::hal::gpio::PinDriver;
+use esp_idf_svc::hal::prelude::*;
+use std::time::Instant;
+
+fn main() -> eyre::Result<()> {
+    // It is necessary to call this function once. Otherwise some patches to the runtime
+    // implemented by esp-idf-sys might not link properly. See https://github.com/esp-rs/esp-idf-template/issues/71
+    esp_idf_svc::sys::link_patches();
+
+    // Bind the log crate to the ESP Logging facilities
+    esp_idf_svc::log::EspLogger::initialize_default();
+
+    let peripherals = Peripherals::take()?;
+    let relay = Relay::new(PinDriver::output(peripherals.pins.gpio7)?, true)?;
+
+    let pin6 = PinDriver::input_output(peripherals.pins.gpio6)?;
+    let mut sensors = TempSensors::new(pin6, TempSensorsConfig::default())?;
+    sensors.discover()?;
+    let sensor = sensors
+        .sensors()
+        .first()
+        .map(|s| s.address())
+        .ok_or_else(|| eyre::eyre!("No temperature sensor found"))?;
+    let (readings, _handle) = task::spawn(sensors)?;
+
+    let mut thermostat = Thermostat::new(ThermostatConfig::default(), sensor, relay)?;
+    loop {
+        let snapshot = readings.snapshot();
+        let power = thermostat.update(&snapshot, Instant::now())?;
+        log::info!(
+            "Temp: {:?}, heater: {power}",
+            snapshot.get(&sensor).and_then(|s| s.temperature)
+        );
+        FreeRtos::delay_ms(1000);
+    }
+}
@@ -0,0 +1,96 @@
+//! Fetches a page over HTTPS, the server is checked by the certificate bundle,
+//! a pinned CA or a pinned certificate fingerprint.
+//!
+//! Copy `cfg.toml.example` into `cfg.toml` and fill wifi and https settings.
+//! `cargo run --example https_client`
+//!
+//! To check pinning against a local server with a self-signed CA:
+//!
+//!     openssl req -x509 -newkey rsa:2048 -nodes -days 365 -subj "/CN=lab-ca" \
+//!         -keyout ca.key -out ca.pem
+//!     openssl req -newkey rsa:2048 -nodes -subj "/CN=192.168.1.10" \
+//!         -addext "subjectAltName=IP:192.168.1.10" -keyout server.key -out server.csr
+//!     openssl x509 -req -in server.csr -CA ca.pem -CAkey ca.key -CAcreateserial \
+//!         -copy_extensions copy -days 365 -out server.pem
+//!     openssl s_server -accept 8443 -cert server.pem -key server.key -www
+//!
+//! Put `ca.pem` into `https_ca_pem` or the output of
+//! `openssl x509 -in server.pem -noout -fingerprint -sha256` into `https_fingerprint`,
+//! and `https://192.168.1.10:8443/` into `https_url`. A wrong CA or fingerprint ends with
+//! `TLS error: certificate not trusted` or `... rejected, e.g. fingerprint mismatch`.
+
+use esp32_c3_examples::clock::{Clock, ClockConfig};
+use esp32_c3_examples::http_client::tls::ServerTrust;
+use esp32_c3_examples::http_client::{HttpClient, HttpConfig, HttpError};
+use esp32_c3_examples::wifi;
+use esp32_c3_examples::wifi::netif::NetifConfig;
+use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_svc::hal::prelude::*;
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use std::time::Duration;
+
+fn main() -> eyre::Result<()> {
+    // It is necessary to call this function once. Otherwise some patches to the runtime
+    // implemented by esp-idf-sys might not link properly. See https://github.com/esp-rs/esp-idf-template/issues/71
+    esp_idf_svc::sys::link_patches();
+
+    // Bind the log crate to the ESP Logging facilities
+    esp_idf_svc::log::EspLogger::initialize_default();
+
+    let peripherals = Peripherals::take()?;
+    let sys_loop = EspSystemEventLoop::take()?;
+    let nvs = EspDefaultNvsPartition::take()?;
+
+    let (wifi, _wifi_thread) = wifi::connect_from_config(
+        peripherals.modem,
+        &sys_loop,
+        nvs,
+        CONFIG.wifi_ssid,
+        CONFIG.wifi_password,
+        NetifConfig::default(),
+    )?;
+    if !wifi.wait_up(Duration::from_secs(60)) {
+        eyre::bail!("Wifi is not connected");
+    }
+
+    // certificates are valid from/until a date, 1970 fails the check
+    let clock = Clock::start(&ClockConfig::default())?;
+    if !clock.wait_valid(Duration::from_secs(30)) {
+        log::warn!("Time is not synced, certificate dates can't be checked");
+    }
+
+    let trust = if !CONFIG.https_fingerprint.is_empty() {
+        ServerTrust::fingerprint(CONFIG.https_fingerprint)?
+    } else if !CONFIG.https_ca_pem.is_empty() {
+        ServerTrust::Ca(CONFIG.https_ca_pem.to_string())
+    } else {
+        ServerTrust::Bundle
+    };
+    log::info!("Server trust: {trust:?}");
+    let mut client = HttpClient::new(HttpConfig {
+        trust,
+        ..Default::default()
+    })?;
+
+    match client.get(CONFIG.https_url) {
+        Ok(body) => log::info!("Body: {}", String::from_utf8_lossy(&body)),
+        Err(HttpError::Certificate(err)) => log::error!("Server is not trusted: {err}"),
+        Err(err) => log::error!("Request failed: {err}"),
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+#[toml_cfg::toml_config]
+struct Config {
+    #[default("NO SSID")]
+    wifi_ssid: &'static str,
+    #[default("NO PASSWORD")]
+    wifi_password: &'static str,
+    #[default("https://example.com")]
+    https_url: &'static str,
+    #[default("")]
+    https_ca_pem: &'static str,
+    #[default("")]
+    https_fingerprint: &'static str,
+}
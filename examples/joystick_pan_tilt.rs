@@ -0,0 +1,92 @@
+//! Joystick controls pan/tilt head made of 2 sg90 servos.
+//!
+//! * io0, io1 - joystick x, y axes
+//! * io8 - joystick button: click returns the head to the center, long press saves preset
+//! * io6 - pan servo
+//! * io7 - tilt servo
+//!
+//! `cargo run --example joystick_pan_tilt`
+
+use esp32_c3_examples::joystick::{AxisCalibration, Button, ButtonEvent};
+use esp32_c3_examples::ledc_servo_lib::{Servo, ServoConfig};
+use esp32_c3_examples::pan_tilt::{Mode, PanTilt, PanTiltConfig, NVS_NAMESPACE};
+use esp_idf_svc::hal::adc::config::Config;
+use esp_idf_svc::hal::adc::{attenuation, AdcChannelDriver, AdcDriver};
+use esp_idf_svc::hal::delay::FreeRtos;
+use esp_idf_svc::hal::gpio::{PinDriver, Pull};
+use esp_idf_svc::hal::ledc;
+use esp_idf_svc::hal::prelude::*;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs};
+use std::time::{Duration, Instant};
+
+fn main() -> eyre::Result<()> {
+    // It is necessary to call this function once. Otherwise some patches to the runtime
+    // implemented by esp-idf-sys might not link properly. See https://github.com/esp-rs/esp-idf-template/issues/71
+    esp_idf_svc::sys::link_patches();
+
+    // Bind the log crate to the ESP Logging facilities
+    esp_idf_svc::log::EspLogger::initialize_default();
+
+    let peripherals = Peripherals::take()?;
+    let mut nvs = EspNvs::new(EspDefaultNvsPartition::take()?, NVS_NAMESPACE, true)?;
+
+    // joystick
+    let mut adc = AdcDriver::new(peripherals.adc1, &Config::new().calibration(true))?;
+    let mut adc_pin_x: AdcChannelDriver<{ attenuation::DB_11 }, _> =
+        AdcChannelDriver::new(peripherals.pins.gpio0)?;
+    let mut adc_pin_y: AdcChannelDriver<{ attenuation::DB_11 }, _> =
+        AdcChannelDriver::new(peripherals.pins.gpio1)?;
+    let mut button_pin = PinDriver::input(peripherals.pins.gpio8)?;
+    button_pin.set_pull(Pull::Down)?;
+    let axis = AxisCalibration::default();
+    let mut button = Button::new(Duration::from_secs(2));
+
+    // servos
+    let pan = Servo::new(
+        ServoConfig::sg90(ledc::SpeedMode::LowSpeed),
+        peripherals.ledc.timer0,
+        peripherals.ledc.channel0,
+        peripherals.pins.gpio6,
+    )?;
+    let tilt = Servo::new(
+        ServoConfig::sg90(ledc::SpeedMode::LowSpeed),
+        peripherals.ledc.timer1,
+        peripherals.ledc.channel1,
+        peripherals.pins.gpio7,
+    )?;
+    let config = PanTiltConfig {
+        mode: Mode::Rate,
+        ..Default::default()
+    };
+    let mut head = PanTilt::new(pan, tilt, config)?;
+    head.recall_preset(&nvs, 0)?;
+
+    let mut last_update = Instant::now();
+    loop {
+        FreeRtos::delay_ms(20);
+        let now = Instant::now();
+
+        // untrusted stick holds the head where it is
+        let x = axis
+            .normalize(adc.read(&mut adc_pin_x)?)
+            .unwrap_or_else(|err| {
+                log::warn!("X axis: {err}");
+                0.0
+            });
+        let y = axis
+            .normalize(adc.read(&mut adc_pin_y)?)
+            .unwrap_or_else(|err| {
+                log::warn!("Y axis: {err}");
+                0.0
+            });
+        head.update(x, y, now - last_update)?;
+        last_update = now;
+
+        // the button pulls the pin low when pressed
+        match button.update(button_pin.is_low(), now) {
+            Some(ButtonEvent::Click) => head.recall_center(),
+            Some(ButtonEvent::LongPress) => head.save_preset(&mut nvs, 0)?,
+            None => {}
+        }
+    }
+}
@@ -1,15 +1,23 @@
 //! Post to MQTT topic and read value back.
 //!
+//! Messages published while the broker is away are queued, the subscription is
+//! renewed after each reconnect. Copy `cfg.toml.example` into `cfg.toml` and fill wifi
+//! and `mqtt_*` settings. `esp32c3/<id>/status` is `online` or `offline`, a heartbeat
+//! goes to `esp32c3/<id>/heartbeat`.
 //! `cargo run --example mqtt`
 
-use embedded_svc::mqtt::client::{Event, QoS};
-use embedded_svc::wifi::{AuthMethod, ClientConfiguration, Configuration};
+use embedded_svc::mqtt::client::QoS;
+use esp32_c3_examples::mqtt::availability;
+use esp32_c3_examples::mqtt::broker::BrokerConfig;
+use esp32_c3_examples::mqtt::discovery::{self, DeviceInfo};
+use esp32_c3_examples::mqtt::{Mqtt, MqttConfig};
+use esp32_c3_examples::wifi;
+use esp32_c3_examples::wifi::netif::NetifConfig;
 use esp_idf_svc::eventloop::EspSystemEventLoop;
 use esp_idf_svc::hal::delay::FreeRtos;
 use esp_idf_svc::hal::prelude::*;
-use esp_idf_svc::mqtt::client::{EspMqttClient, MqttClientConfiguration};
 use esp_idf_svc::nvs::EspDefaultNvsPartition;
-use esp_idf_svc::wifi::{BlockingWifi, EspWifi};
+use std::time::Duration;
 
 fn main() -> eyre::Result<()> {
     // It is necessary to call this function once. Otherwise some patches to the runtime
@@ -24,41 +32,61 @@ fn main() -> eyre::Result<()> {
     let peripherals = Peripherals::take()?;
     let sys_loop = EspSystemEventLoop::take()?;
     let nvs = EspDefaultNvsPartition::take()?;
-    let mut wifi = BlockingWifi::wrap(
-        EspWifi::new(peripherals.modem, sys_loop.clone(), Some(nvs))?,
-        sys_loop,
+    // cfg.toml network is stored on the first boot, more can be added later
+    let (wifi, _wifi_thread) = wifi::connect_from_config(
+        peripherals.modem,
+        &sys_loop,
+        nvs,
+        CONFIG.wifi_ssid,
+        CONFIG.wifi_password,
+        NetifConfig::default(),
     )?;
-    connect_wifi(&mut wifi)?;
+    if !wifi.wait_up(Duration::from_secs(60)) {
+        eyre::bail!("Wifi is not connected");
+    }
 
     // MQTT
-    let conf = MqttClientConfiguration {
-        username: Some(CONFIG.mqtt_user),
-        password: Some(CONFIG.mqtt_password),
-        ..Default::default()
+    let broker = BrokerConfig {
+        scheme: CONFIG.mqtt_scheme.parse()?,
+        port: Some(CONFIG.mqtt_port).filter(|port| *port != 0),
+        client_id: CONFIG.mqtt_client_id.to_string(),
+        keepalive: Duration::from_secs(CONFIG.mqtt_keepalive_secs),
+        clean_session: CONFIG.mqtt_clean_session,
+        username: Some(CONFIG.mqtt_user.to_string()).filter(|user| !user.is_empty()),
+        password: Some(CONFIG.mqtt_password.to_string()).filter(|pass| !pass.is_empty()),
+        ..BrokerConfig::new(CONFIG.mqtt_host)
     };
-    let mut mqtt = EspMqttClient::new(CONFIG.mqtt_url, &conf, |res| {
-        log::info!("Mqtt > {:?}", res);
-        match res {
-            Ok(Event::Received(msg)) => {
-                log::info!("> recieved msg: {:?}", String::from_utf8_lossy(msg.data()))
-            }
-            _ => {}
-        }
-    })
-    .unwrap();
+    broker.validate()?;
+    log::info!("Broker: {}", broker.url());
+    // `offline` from the broker when the board drops, `online` after each connect
+    let device = DeviceInfo::new(discovery::station_mac()?, CONFIG.mqtt_client_id);
+    let status_topic = device.availability_topic();
+    let mut conf = broker.client_configuration();
+    availability::configure(&status_topic, &mut conf);
+    let config = MqttConfig {
+        birth: Some(availability::birth(&status_topic)),
+        ..MqttConfig::default()
+    };
+    let (mqtt, _mqtt_thread) = Mqtt::start(&broker.url(), &conf, config)?;
+    let period = Duration::from_secs(CONFIG.mqtt_heartbeat_secs);
+    let _heartbeat_thread = availability::spawn_heartbeat(&mqtt, &wifi, &device, period)?;
 
     let topic = "esp32c3/rust/example";
-    mqtt.subscribe(topic, QoS::AtLeastOnce).unwrap();
+    mqtt.subscribe(topic, QoS::AtLeastOnce, |topic, payload| {
+        log::info!("> received {topic}: {:?}", String::from_utf8_lossy(payload))
+    })?;
 
     loop {
         FreeRtos::delay_ms(5000);
-        mqtt.publish(
+        if let Err(err) = mqtt.publish(
             topic,
             QoS::AtLeastOnce,
             false,
-            "Hello from Rust and Esp32-C3!".as_bytes(),
-        )
-        .unwrap();
+            "Hello from Rust and Esp32-C3!",
+        ) {
+            log::warn!("Publish failed: {err}");
+        }
+        log::info!("Mqtt status: {:?}", mqtt.status());
     }
 }
 
@@ -70,35 +98,22 @@ struct Config {
     #[default("NO PASSWORD")]
     wifi_password: &'static str,
 
-    #[default(1883)]
+    #[default("mqtt")]
+    mqtt_scheme: &'static str,
+    #[default("NO MQTT HOST")]
+    mqtt_host: &'static str,
+    #[default(0)]
     mqtt_port: u16,
-    #[default("NO MQTT URL")]
-    mqtt_url: &'static str,
-    #[default("NO MQTT USER")]
+    #[default("esp32-c3")]
+    mqtt_client_id: &'static str,
+    #[default(60)]
+    mqtt_keepalive_secs: u64,
+    #[default(true)]
+    mqtt_clean_session: bool,
+    #[default("")]
     mqtt_user: &'static str,
-    #[default("NO MQTT PASSWORD")]
+    #[default("")]
     mqtt_password: &'static str,
-}
-
-fn connect_wifi(wifi: &mut BlockingWifi<EspWifi<'static>>) -> eyre::Result<()> {
-    let wifi_configuration: Configuration = Configuration::Client(ClientConfiguration {
-        ssid: CONFIG.wifi_ssid.into(),
-        bssid: None,
-        auth_method: AuthMethod::WPA2Personal,
-        password: CONFIG.wifi_password.into(),
-        channel: None,
-    });
-
-    wifi.set_configuration(&wifi_configuration)?;
-
-    wifi.start()?;
-    log::info!("Wifi started");
-
-    wifi.connect()?;
-    log::info!("Wifi connected");
-
-    wifi.wait_netif_up()?;
-    log::info!("Wifi netif up");
-
-    Ok(())
+    #[default(60)]
+    mqtt_heartbeat_secs: u64,
 }
@@ -0,0 +1,342 @@
+//! Board devices in Home Assistant via MQTT discovery, no YAML needed.
+//!
+//! * io4 - ds18b20 sensors, one `sensor` each
+//! * io6, io7 - servos sg90, `number` 0 and 1
+//! * io0-io3 - IN1-IN4 of ULN2003 with `28byj-48` motor, `number` move and `button` stop
+//! * io18, io19 - LEDs, `light` 0 and 1
+//! * io8 - joystick button, `binary_sensor`
+//!
+//! Copy `cfg.toml.example` into `cfg.toml` and fill wifi and `mqtt_*` settings, the broker
+//! is the one of the Home Assistant MQTT integration. The board shows up in
+//! Settings > Devices as `ha_device_name`. Watch with
+//! `mosquitto_sub -h HOST -t 'homeassistant/+/esp32c3_+/#' -t 'esp32c3/#' -v`, the
+//! heartbeat is at `esp32c3/<id>/heartbeat`.
+//! `cargo run --example mqtt_home_assistant`
+
+use embedded_svc::mqtt::client::QoS;
+use esp32_c3_examples::ledc_servo_lib::{Servo, ServoConfig};
+use esp32_c3_examples::lock_recover;
+use esp32_c3_examples::mqtt::availability;
+use esp32_c3_examples::mqtt::broker::BrokerConfig;
+use esp32_c3_examples::mqtt::discovery::{self, DeviceInfo, Discovery, Entity, OFF, ON};
+use esp32_c3_examples::mqtt::{Mqtt, MqttConfig};
+use esp32_c3_examples::rest_api::{self, steppers, SensorSource, ServoControl, StepperControl};
+use esp32_c3_examples::temp_sensor::{task, TempSensors, TempSensorsConfig};
+use esp32_c3_examples::wifi;
+use esp32_c3_examples::wifi::netif::NetifConfig;
+use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_svc::hal::delay::{Delay, FreeRtos};
+use esp_idf_svc::hal::gpio::{AnyOutputPin, Output, OutputPin, PinDriver, Pull};
+use esp_idf_svc::hal::ledc::SpeedMode;
+use esp_idf_svc::hal::prelude::*;
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+type Led = PinDriver<'static, AnyOutputPin, Output>;
+
+fn main() -> eyre::Result<()> {
+    // It is necessary to call this function once. Otherwise some patches to the runtime
+    // implemented by esp-idf-sys might not link properly. See https://github.com/esp-rs/esp-idf-template/issues/71
+    esp_idf_svc::sys::link_patches();
+
+    // Bind the log crate to the ESP Logging facilities
+    esp_idf_svc::log::EspLogger::initialize_default();
+
+    let peripherals = Peripherals::take()?;
+    let sys_loop = EspSystemEventLoop::take()?;
+    let nvs = EspDefaultNvsPartition::take()?;
+
+    let servos = Arc::new(Mutex::new(vec![
+        Servo::new(
+            ServoConfig::sg90(SpeedMode::LowSpeed),
+            peripherals.ledc.timer0,
+            peripherals.ledc.channel0,
+            peripherals.pins.gpio6,
+        )?,
+        Servo::new(
+            ServoConfig::sg90(SpeedMode::LowSpeed),
+            peripherals.ledc.timer1,
+            peripherals.ledc.channel1,
+            peripherals.pins.gpio7,
+        )?,
+    ]));
+
+    // the delay of the driver isn't used, steps are timed by the steppers thread
+    let motor = uln2003::ULN2003::<_, _, _, _, u32, Delay>::new(
+        PinDriver::output(peripherals.pins.gpio0)?,
+        PinDriver::output(peripherals.pins.gpio1)?,
+        PinDriver::output(peripherals.pins.gpio2)?,
+        PinDriver::output(peripherals.pins.gpio3)?,
+        None,
+    );
+    let (steppers, _steppers_thread) = steppers::spawn(vec![motor])?;
+
+    let leds: Arc<Mutex<Vec<Led>>> = Arc::new(Mutex::new(vec![
+        PinDriver::output(peripherals.pins.gpio18.downgrade_output())?,
+        PinDriver::output(peripherals.pins.gpio19.downgrade_output())?,
+    ]));
+
+    let mut button = PinDriver::input(peripherals.pins.gpio8)?;
+    button.set_pull(Pull::Down)?;
+
+    let pin4 = PinDriver::input_output(peripherals.pins.gpio4)?;
+    let sensors = TempSensors::new(pin4, TempSensorsConfig::default())?;
+    let (readings, _sensors_thread) = task::spawn(sensors)?;
+
+    let (wifi, _wifi_thread) = wifi::connect_from_config(
+        peripherals.modem,
+        &sys_loop,
+        nvs,
+        CONFIG.wifi_ssid,
+        CONFIG.wifi_password,
+        NetifConfig::default(),
+    )?;
+    if !wifi.wait_up(Duration::from_secs(60)) {
+        eyre::bail!("Wifi is not connected");
+    }
+
+    let device = DeviceInfo::new(discovery::station_mac()?, CONFIG.ha_device_name);
+    let broker = BrokerConfig {
+        scheme: CONFIG.mqtt_scheme.parse()?,
+        port: Some(CONFIG.mqtt_port).filter(|port| *port != 0),
+        client_id: device.id.clone(),
+        username: Some(CONFIG.mqtt_user.to_string()).filter(|user| !user.is_empty()),
+        password: Some(CONFIG.mqtt_password.to_string()).filter(|pass| !pass.is_empty()),
+        ..BrokerConfig::new(CONFIG.mqtt_host)
+    };
+    broker.validate()?;
+    // entities turn unavailable when the broker publishes the Last Will
+    let status_topic = device.availability_topic();
+    let mut conf = broker.client_configuration();
+    availability::configure(&status_topic, &mut conf);
+    let config = MqttConfig {
+        birth: Some(availability::birth(&status_topic)),
+        ..MqttConfig::default()
+    };
+    let (mqtt, _mqtt_thread) = Mqtt::start(&broker.url(), &conf, config)?;
+    let period = Duration::from_secs(CONFIG.mqtt_heartbeat_secs);
+    let _heartbeat_thread = availability::spawn_heartbeat(&mqtt, &wifi, &device, period)?;
+
+    // COMMANDS
+
+    let servo_count = lock_recover(&servos).count();
+    for id in 0..servo_count {
+        let entity = Entity::Servo {
+            id,
+            max_angle: lock_recover(&servos).max_angle(id),
+        };
+        let (servos, replies, state_topic) = (
+            servos.clone(),
+            mqtt.clone(),
+            entity.state_topic(&device).unwrap_or_default(),
+        );
+        mqtt.subscribe(
+            &entity.command_topic(&device).unwrap_or_default(),
+            QoS::AtLeastOnce,
+            move |_, payload| {
+                let Some(angle) = parse::<f64>(payload) else {
+                    return;
+                };
+                let mut servos = lock_recover(&servos);
+                match rest_api::set_servo(&mut *servos, id, angle) {
+                    Ok(()) => publish(&replies, &state_topic, servos.angle(id).to_string()),
+                    Err(err) => log::warn!("Servo {id}: {err}"),
+                }
+            },
+        )?;
+    }
+    for id in 0..steppers.count() {
+        let moving = steppers.clone();
+        mqtt.subscribe(
+            &Entity::StepperMove { id }
+                .command_topic(&device)
+                .unwrap_or_default(),
+            QoS::AtLeastOnce,
+            move |_, payload| {
+                if let Some(steps) = parse::<i32>(payload) {
+                    if let Err(err) = rest_api::move_stepper(&mut moving.clone(), id, steps, None) {
+                        log::warn!("Stepper {id}: {err}");
+                    }
+                }
+            },
+        )?;
+        let stopping = steppers.clone();
+        mqtt.subscribe(
+            &Entity::StepperStop { id }
+                .command_topic(&device)
+                .unwrap_or_default(),
+            QoS::AtLeastOnce,
+            move |_, _| {
+                if let Err(err) = rest_api::stop_stepper(&mut stopping.clone(), id) {
+                    log::warn!("Stepper {id}: {err}");
+                }
+            },
+        )?;
+    }
+    let led_count = lock_recover(&leds).len();
+    for id in 0..led_count {
+        let entity = Entity::Led { id };
+        let (leds, replies, state_topic) = (
+            leds.clone(),
+            mqtt.clone(),
+            entity.state_topic(&device).unwrap_or_default(),
+        );
+        mqtt.subscribe(
+            &entity.command_topic(&device).unwrap_or_default(),
+            QoS::AtLeastOnce,
+            move |_, payload| {
+                let on = match payload {
+                    b"ON" => true,
+                    b"OFF" => false,
+                    _ => return,
+                };
+                let mut leds = lock_recover(&leds);
+                let result = if on {
+                    leds[id].set_high()
+                } else {
+                    leds[id].set_low()
+                };
+                match result {
+                    Ok(()) => publish(&replies, &state_topic, if on { ON } else { OFF }),
+                    Err(err) => log::warn!("LED {id}: {err}"),
+                }
+            },
+        )?;
+    }
+
+    // DISCOVERY AND STATES
+
+    let discovery = Discovery::start(&mqtt, device.clone())?;
+    let mut announced: Option<Vec<Entity>> = None;
+    let mut round = None;
+    let mut positions = vec![None; steppers.count()];
+    let mut pressed = None;
+    let mut connected = false;
+    loop {
+        // all states are published again after each connect, otherwise Home Assistant shows
+        // `unknown` until the first command or after the broker lost the retained ones
+        if mqtt.is_connected() != connected {
+            connected = !connected;
+            if connected {
+                publish_outputs(&mqtt, &device, &servos, &leds);
+                positions.fill(None);
+                pressed = None;
+            }
+        }
+
+        // sensors are found by the first round, unplugged or added ones change the set
+        let sensors = readings.sensors();
+        let mut entities: Vec<Entity> = sensors
+            .iter()
+            .map(|sensor| Entity::Temperature {
+                address: sensor.address.clone(),
+            })
+            .collect();
+        for id in 0..servo_count {
+            let max_angle = lock_recover(&servos).max_angle(id);
+            entities.push(Entity::Servo { id, max_angle });
+        }
+        for id in 0..steppers.count() {
+            entities.push(Entity::StepperMove { id });
+            entities.push(Entity::StepperStop { id });
+        }
+        entities.extend((0..led_count).map(|id| Entity::Led { id }));
+        entities.push(Entity::JoystickButton);
+        if announced.as_ref() != Some(&entities) {
+            discovery.set_entities(entities.clone())?;
+            announced = Some(entities);
+        }
+
+        if round != Some(readings.round()) {
+            round = Some(readings.round());
+            for sensor in &sensors {
+                let entity = Entity::Temperature {
+                    address: sensor.address.clone(),
+                };
+                if let (Some(topic), Some(temperature)) =
+                    (entity.state_topic(&device), sensor.temperature)
+                {
+                    publish(&mqtt, &topic, format!("{temperature:.2}"));
+                }
+            }
+        }
+        for (id, last) in positions.iter_mut().enumerate() {
+            let position = steppers.state(id).position;
+            if *last != Some(position) {
+                *last = Some(position);
+                let topic = Entity::StepperMove { id }.state_topic(&device);
+                publish(&mqtt, &topic.unwrap_or_default(), position.to_string());
+            }
+        }
+        // the button reads low while pressed, as in `adc_joystick`
+        let now_pressed = button.is_low();
+        if pressed != Some(now_pressed) {
+            pressed = Some(now_pressed);
+            let topic = Entity::JoystickButton.state_topic(&device);
+            let state = if now_pressed { ON } else { OFF };
+            publish(&mqtt, &topic.unwrap_or_default(), state);
+        }
+        FreeRtos::delay_ms(100);
+    }
+}
+
+/// Servo angles and LED states, they change only by commands.
+fn publish_outputs(
+    mqtt: &Mqtt,
+    device: &DeviceInfo,
+    servos: &Mutex<Vec<Servo>>,
+    leds: &Mutex<Vec<Led>>,
+) {
+    let servos = lock_recover(servos);
+    for id in 0..servos.count() {
+        let entity = Entity::Servo {
+            id,
+            max_angle: servos.max_angle(id),
+        };
+        let topic = entity.state_topic(device).unwrap_or_default();
+        publish(mqtt, &topic, servos.angle(id).to_string());
+    }
+    for (id, led) in lock_recover(leds).iter().enumerate() {
+        let topic = Entity::Led { id }.state_topic(device).unwrap_or_default();
+        publish(mqtt, &topic, if led.is_set_high() { ON } else { OFF });
+    }
+}
+
+fn parse<T: std::str::FromStr>(payload: &[u8]) -> Option<T> {
+    let parsed = std::str::from_utf8(payload).ok()?.trim().parse().ok();
+    if parsed.is_none() {
+        log::warn!("Invalid command {:?}", String::from_utf8_lossy(payload));
+    }
+    parsed
+}
+
+/// Retained, Home Assistant shows the state right after its restart.
+fn publish(mqtt: &Mqtt, topic: &str, payload: impl Into<Vec<u8>>) {
+    if let Err(err) = mqtt.publish(topic, QoS::AtMostOnce, true, payload) {
+        log::warn!("Publish to {topic} failed: {err}");
+    }
+}
+
+#[derive(Debug)]
+#[toml_cfg::toml_config]
+struct Config {
+    #[default("NO SSID")]
+    wifi_ssid: &'static str,
+    #[default("NO PASSWORD")]
+    wifi_password: &'static str,
+    #[default("mqtt")]
+    mqtt_scheme: &'static str,
+    #[default("NO MQTT HOST")]
+    mqtt_host: &'static str,
+    #[default(0)]
+    mqtt_port: u16,
+    #[default("")]
+    mqtt_user: &'static str,
+    #[default("")]
+    mqtt_password: &'static str,
+    #[default(60)]
+    mqtt_heartbeat_secs: u64,
+    #[default("ESP32-C3")]
+    ha_device_name: &'static str,
+}
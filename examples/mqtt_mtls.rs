@@ -0,0 +1,142 @@
+//! Publishes to an `mqtts://` broker which requires a client certificate.
+//!
+//! Copy `cfg.toml.example` into `cfg.toml` and fill wifi and `mqtt_*` settings,
+//! `mqtt_scheme = "mqtts"` and `mqtt_port = 8883`.
+//! `cargo run --example mqtt_mtls`
+//!
+//! A local Mosquitto with its own CA, the board gets `esp32-c3` as its identity:
+//!
+//!     openssl req -x509 -newkey rsa:2048 -nodes -days 365 -subj "/CN=lab-ca" \
+//!         -keyout ca.key -out ca.pem
+//!     openssl req -newkey rsa:2048 -nodes -subj "/CN=192.168.1.10" \
+//!         -addext "subjectAltName=IP:192.168.1.10" -keyout server.key -out server.csr
+//!     openssl x509 -req -in server.csr -CA ca.pem -CAkey ca.key -CAcreateserial \
+//!         -copy_extensions copy -days 365 -out server.pem
+//!     openssl req -newkey ec -pkeyopt ec_paramgen_curve:prime256v1 -nodes \
+//!         -subj "/CN=esp32-c3" -keyout client.key -out client.csr
+//!     openssl x509 -req -in client.csr -CA ca.pem -CAkey ca.key -days 365 -out client.pem
+//!
+//! `mosquitto.conf`, `use_identity_as_username` makes the certificate CN the user:
+//!
+//!     listener 8883
+//!     cafile ca.pem
+//!     certfile server.pem
+//!     keyfile server.key
+//!     require_certificate true
+//!     use_identity_as_username true
+//!
+//! Put `ca.pem`, `client.pem` and `client.key` into `mqtt_ca_pem`, `mqtt_cert_pem` and
+//! `mqtt_key_pem` as TOML `"""` strings. They are stored in NVS on boot, later builds
+//! may leave them empty and use the stored ones. Watch with
+//! `mosquitto_sub -h 192.168.1.10 -p 8883 --cafile ca.pem --cert client.pem --key client.key -t 'esp32c3/#' -v`.
+
+use embedded_svc::mqtt::client::QoS;
+use esp32_c3_examples::clock::{Clock, ClockConfig};
+use esp32_c3_examples::mqtt::broker::BrokerConfig;
+use esp32_c3_examples::mqtt::tls::{self, Pem, PemKind, PemStore};
+use esp32_c3_examples::mqtt::{Mqtt, MqttConfig};
+use esp32_c3_examples::wifi;
+use esp32_c3_examples::wifi::netif::NetifConfig;
+use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_svc::hal::delay::FreeRtos;
+use esp_idf_svc::hal::prelude::*;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs};
+use std::time::Duration;
+
+fn main() -> eyre::Result<()> {
+    // It is necessary to call this function once. Otherwise some patches to the runtime
+    // implemented by esp-idf-sys might not link properly. See https://github.com/esp-rs/esp-idf-template/issues/71
+    esp_idf_svc::sys::link_patches();
+
+    // Bind the log crate to the ESP Logging facilities
+    esp_idf_svc::log::EspLogger::initialize_default();
+
+    let peripherals = Peripherals::take()?;
+    let sys_loop = EspSystemEventLoop::take()?;
+    let nvs = EspDefaultNvsPartition::take()?;
+
+    // PEMs of cfg.toml replace the stored ones
+    let mut pems = PemStore::new(EspNvs::new(nvs.clone(), tls::NVS_NAMESPACE, true)?);
+    let provisioned = [
+        (tls::CA_KEY, CONFIG.mqtt_ca_pem),
+        (tls::CERTIFICATE_KEY, CONFIG.mqtt_cert_pem),
+        (tls::PRIVATE_KEY_KEY, CONFIG.mqtt_key_pem),
+    ];
+    for (key, pem) in provisioned {
+        let kind = match key {
+            tls::PRIVATE_KEY_KEY => PemKind::PrivateKey,
+            _ => PemKind::Certificate,
+        };
+        if !pem.is_empty() {
+            pems.store(key, &Pem::new(key, kind, pem)?)?;
+        }
+    }
+    let tls_config = pems.tls_config()?;
+    if tls_config.identity.is_none() {
+        log::warn!("No client certificate, the broker will likely refuse the connection");
+    }
+
+    let (wifi, _wifi_thread) = wifi::connect_from_config(
+        peripherals.modem,
+        &sys_loop,
+        nvs,
+        CONFIG.wifi_ssid,
+        CONFIG.wifi_password,
+        NetifConfig::default(),
+    )?;
+    if !wifi.wait_up(Duration::from_secs(60)) {
+        eyre::bail!("Wifi is not connected");
+    }
+
+    // certificates are valid from/until a date, 1970 fails the check
+    let clock = Clock::start(&ClockConfig::default())?;
+    if !clock.wait_valid(Duration::from_secs(30)) {
+        log::warn!("Time is not synced, certificate dates can't be checked");
+    }
+
+    let broker = BrokerConfig {
+        scheme: CONFIG.mqtt_scheme.parse()?,
+        port: Some(CONFIG.mqtt_port).filter(|port| *port != 0),
+        client_id: CONFIG.mqtt_client_id.to_string(),
+        ..BrokerConfig::new(CONFIG.mqtt_host)
+    };
+    broker.validate()?;
+    let mut conf = broker.client_configuration();
+    tls_config.configure(broker.scheme, &mut conf)?;
+    log::info!("Broker: {}, TLS: {tls_config:?}", broker.url());
+    let (mqtt, _mqtt_thread) = Mqtt::start(&broker.url(), &conf, MqttConfig::default())?;
+    if !mqtt.wait_connected(Duration::from_secs(30)) {
+        log::warn!("Not connected yet, check the broker log for the TLS error");
+    }
+
+    let topic = format!("esp32c3/{}/hello", CONFIG.mqtt_client_id);
+    loop {
+        if let Err(err) = mqtt.publish(&topic, QoS::AtLeastOnce, false, "Hello over mTLS!") {
+            log::warn!("Publish failed: {err}");
+        }
+        FreeRtos::delay_ms(5000);
+    }
+}
+
+#[derive(Debug)]
+#[toml_cfg::toml_config]
+struct Config {
+    #[default("NO SSID")]
+    wifi_ssid: &'static str,
+    #[default("NO PASSWORD")]
+    wifi_password: &'static str,
+    #[default("mqtts")]
+    mqtt_scheme: &'static str,
+    #[default("NO MQTT HOST")]
+    mqtt_host: &'static str,
+    #[default(0)]
+    mqtt_port: u16,
+    #[default("esp32-c3")]
+    mqtt_client_id: &'static str,
+    #[default("")]
+    mqtt_ca_pem: &'static str,
+    #[default("")]
+    mqtt_cert_pem: &'static str,
+    #[default("")]
+    mqtt_key_pem: &'static str,
+}
@@ -0,0 +1,129 @@
+//! Firmware updates over wifi, pulled from a manifest or uploaded.
+//!
+//! Copy `cfg.toml.example` into `cfg.toml` and fill wifi settings, flash once over USB,
+//! then build the next version and make its image:
+//! ```text
+//! espflash save-image --chip esp32c3 target/riscv32imc-esp-espidf/debug/examples/ota_update firmware.bin
+//! curl --data-binary @firmware.bin -H "X-Firmware-Sha256: $(sha256sum firmware.bin | cut -c-64)" \
+//!     http://esp32-c3.local/ota
+//! ```
+//! Or serve `firmware.bin` next to `manifest.json` of `ota_manifest_url`, it is checked hourly:
+//! `{"version": "0.2.0", "url": "firmware.bin", "size": 912345, "sha256": "..."}`.
+//! Only newer versions are installed unless `ota_allow_downgrade` is set.
+//! The new firmware is kept only if wifi comes up within a minute.
+//! `cargo run --example ota_update`
+
+use esp32_c3_examples::http_client::{HttpClient, HttpConfig};
+use esp32_c3_examples::lock_recover;
+use esp32_c3_examples::ota::{self, Outcome};
+use esp32_c3_examples::wifi;
+use esp32_c3_examples::wifi::mdns::{self, Service};
+use esp32_c3_examples::wifi::netif::NetifConfig;
+use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_svc::hal::delay::FreeRtos;
+use esp_idf_svc::hal::prelude::*;
+use esp_idf_svc::hal::reset;
+use esp_idf_svc::http::server::{Configuration as HttpConfiguration, EspHttpServer};
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use esp_idf_svc::ota::EspOta;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const CHECK_PERIOD_MS: u32 = 60 * 60 * 1000;
+
+// the app descriptor gets `CARGO_PKG_VERSION`, the manifest version is compared to it
+esp_idf_svc::sys::esp_app_desc!();
+
+fn main() -> eyre::Result<()> {
+    // It is necessary to call this function once. Otherwise some patches to the runtime
+    // implemented by esp-idf-sys might not link properly. See https://github.com/esp-rs/esp-idf-template/issues/71
+    esp_idf_svc::sys::link_patches();
+
+    // Bind the log crate to the ESP Logging facilities
+    esp_idf_svc::log::EspLogger::initialize_default();
+
+    let peripherals = Peripherals::take()?;
+    let sys_loop = EspSystemEventLoop::take()?;
+    let nvs = EspDefaultNvsPartition::take()?;
+
+    let mut ota = EspOta::new()?;
+    let running = ota.get_running_slot()?;
+    log::info!(
+        "Running {} from {}, {:?}",
+        env!("CARGO_PKG_VERSION"),
+        running.label,
+        running.state
+    );
+
+    let (wifi, _wifi_thread) = wifi::connect_from_config(
+        peripherals.modem,
+        &sys_loop,
+        nvs,
+        CONFIG.wifi_ssid,
+        CONFIG.wifi_password,
+        NetifConfig::new(Some(CONFIG.hostname), None)?,
+    )?;
+    // an update without wifi couldn't be replaced by the next one
+    ota::confirm(&mut ota, Duration::from_secs(60), || wifi.is_up())?;
+    if !wifi.wait_up(Duration::from_secs(60)) {
+        eyre::bail!("Wifi is not connected");
+    }
+    log::info!("Wifi status: {:?}", wifi.status());
+
+    let ota = Arc::new(Mutex::new(ota));
+    let mut server = EspHttpServer::new(&HttpConfiguration::default())?;
+    ota::serve_upload(&mut server, "/ota", ota.clone())?;
+    let _mdns = mdns::advertise(
+        CONFIG.hostname,
+        "ESP32-C3 examples",
+        &[Service::http(80).with_txt("path", "/ota")],
+    )?;
+    log::info!("Upload: http://{}.local/ota", CONFIG.hostname);
+
+    if CONFIG.ota_manifest_url.is_empty() {
+        loop {
+            FreeRtos::delay_ms(10_000);
+        }
+    }
+    // the timeout covers the whole image download
+    let mut client = HttpClient::new(HttpConfig {
+        timeout: Duration::from_secs(300),
+        ..Default::default()
+    })?;
+    loop {
+        let result = {
+            let mut ota = lock_recover(&ota);
+            ota::update_from_manifest(
+                &mut client,
+                &mut ota,
+                CONFIG.ota_manifest_url,
+                CONFIG.ota_allow_downgrade,
+                ota::log_progress(),
+            )
+        };
+        match result {
+            Ok(Outcome::Installed { version }) => {
+                log::info!("Rebooting into {version}");
+                reset::restart();
+            }
+            Ok(Outcome::UpToDate) => {}
+            Err(err) => log::warn!("Update failed: {err}"),
+        }
+        FreeRtos::delay_ms(CHECK_PERIOD_MS);
+    }
+}
+
+#[derive(Debug)]
+#[toml_cfg::toml_config]
+struct Config {
+    #[default("NO SSID")]
+    wifi_ssid: &'static str,
+    #[default("NO PASSWORD")]
+    wifi_password: &'static str,
+    #[default("esp32-c3")]
+    hostname: &'static str,
+    #[default("")]
+    ota_manifest_url: &'static str,
+    #[default(false)]
+    ota_allow_downgrade: bool,
+}
@@ -0,0 +1,113 @@
+//! REST API to the servos, steppers and temperature sensors of the board.
+//!
+//! * io6, io7 - servos sg90, ids 0 and 1
+//! * io0-io3 - IN1-IN4 of ULN2003 with `28byj-48` motor, id 0
+//! * io4 - ds18b20 sensors
+//!
+//! Copy `cfg.toml.example` into `cfg.toml` and fill wifi settings, then:
+//! ```text
+//! curl http://esp32-c3.local/api/status
+//! curl -X PUT -d '{"angle": 45}' http://esp32-c3.local/api/servo/0
+//! curl -X POST -d '{"steps": -2048, "speed": 400}' http://esp32-c3.local/api/stepper/0/move
+//! ```
+//! See `rest_api` for all endpoints.
+//! `cargo run --example rest_api`
+
+use esp32_c3_examples::ledc_servo_lib::{Servo, ServoConfig};
+use esp32_c3_examples::rest_api::{self, steppers, RestApi};
+use esp32_c3_examples::temp_sensor::{task, TempSensors, TempSensorsConfig};
+use esp32_c3_examples::wifi;
+use esp32_c3_examples::wifi::mdns::{self, Service};
+use esp32_c3_examples::wifi::netif::NetifConfig;
+use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_svc::hal::delay::{Delay, FreeRtos};
+use esp_idf_svc::hal::gpio::PinDriver;
+use esp_idf_svc::hal::ledc::SpeedMode;
+use esp_idf_svc::hal::prelude::*;
+use esp_idf_svc::http::server::{Configuration as HttpConfiguration, EspHttpServer};
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+fn main() -> eyre::Result<()> {
+    // It is necessary to call this function once. Otherwise some patches to the runtime
+    // implemented by esp-idf-sys might not link properly. See https://github.com/esp-rs/esp-idf-template/issues/71
+    esp_idf_svc::sys::link_patches();
+
+    // Bind the log crate to the ESP Logging facilities
+    esp_idf_svc::log::EspLogger::initialize_default();
+
+    let peripherals = Peripherals::take()?;
+    let sys_loop = EspSystemEventLoop::take()?;
+    let nvs = EspDefaultNvsPartition::take()?;
+
+    let servos = vec![
+        Servo::new(
+            ServoConfig::sg90(SpeedMode::LowSpeed),
+            peripherals.ledc.timer0,
+            peripherals.ledc.channel0,
+            peripherals.pins.gpio6,
+        )?,
+        Servo::new(
+            ServoConfig::sg90(SpeedMode::LowSpeed),
+            peripherals.ledc.timer1,
+            peripherals.ledc.channel1,
+            peripherals.pins.gpio7,
+        )?,
+    ];
+
+    // the delay of the driver isn't used, steps are timed by the steppers thread
+    let motor = uln2003::ULN2003::<_, _, _, _, u32, Delay>::new(
+        PinDriver::output(peripherals.pins.gpio0)?,
+        PinDriver::output(peripherals.pins.gpio1)?,
+        PinDriver::output(peripherals.pins.gpio2)?,
+        PinDriver::output(peripherals.pins.gpio3)?,
+        None,
+    );
+    let (steppers, _steppers_thread) = steppers::spawn(vec![motor])?;
+
+    let pin4 = PinDriver::input_output(peripherals.pins.gpio4)?;
+    let sensors = TempSensors::new(pin4, TempSensorsConfig::default())?;
+    let (readings, _sensors_thread) = task::spawn(sensors)?;
+
+    let (wifi, _wifi_thread) = wifi::connect_from_config(
+        peripherals.modem,
+        &sys_loop,
+        nvs,
+        CONFIG.wifi_ssid,
+        CONFIG.wifi_password,
+        NetifConfig::new(Some(CONFIG.hostname), None)?,
+    )?;
+    if !wifi.wait_up(Duration::from_secs(60)) {
+        eyre::bail!("Wifi is not connected");
+    }
+    log::info!("Wifi status: {:?}", wifi.status());
+
+    let api = Arc::new(Mutex::new(RestApi::new(servos, steppers, readings)));
+    let mut server = EspHttpServer::new(&HttpConfiguration {
+        uri_match_wildcard: true,
+        ..Default::default()
+    })?;
+    rest_api::serve(&mut server, &api)?;
+    let _mdns = mdns::advertise(
+        CONFIG.hostname,
+        "ESP32-C3 examples",
+        &[Service::http(80).with_txt("path", "/api/status")],
+    )?;
+    log::info!("API: http://{}.local/api/status", CONFIG.hostname);
+
+    loop {
+        FreeRtos::delay_ms(10_000);
+    }
+}
+
+#[derive(Debug)]
+#[toml_cfg::toml_config]
+struct Config {
+    #[default("NO SSID")]
+    wifi_ssid: &'static str,
+    #[default("NO PASSWORD")]
+    wifi_password: &'static str,
+    #[default("esp32-c3")]
+    hostname: &'static str,
+}
@@ -0,0 +1,70 @@
+//! Syncs the clock over SNTP and logs local time.
+//!
+//! Copy `cfg.toml.example` into `cfg.toml` and fill wifi, ntp_server and timezone.
+//! `cargo run --example sntp_clock`
+
+use esp32_c3_examples::clock::{self, Clock, ClockConfig};
+use esp32_c3_examples::wifi;
+use esp32_c3_examples::wifi::netif::NetifConfig;
+use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_svc::hal::delay::FreeRtos;
+use esp_idf_svc::hal::prelude::*;
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use std::time::{Duration, Instant};
+
+fn main() -> eyre::Result<()> {
+    // It is necessary to call this function once. Otherwise some patches to the runtime
+    // implemented by esp-idf-sys might not link properly. See https://github.com/esp-rs/esp-idf-template/issues/71
+    esp_idf_svc::sys::link_patches();
+
+    // Bind the log crate to the ESP Logging facilities
+    esp_idf_svc::log::EspLogger::initialize_default();
+
+    let peripherals = Peripherals::take()?;
+    let sys_loop = EspSystemEventLoop::take()?;
+    let nvs = EspDefaultNvsPartition::take()?;
+
+    let (wifi, _wifi_thread) = wifi::connect_from_config(
+        peripherals.modem,
+        &sys_loop,
+        nvs,
+        CONFIG.wifi_ssid,
+        CONFIG.wifi_password,
+        NetifConfig::default(),
+    )?;
+    // taken before the sync, stamped later
+    let booted = Instant::now();
+    log::info!("{} waiting for wifi", clock::stamp());
+    if !wifi.wait_up(Duration::from_secs(60)) {
+        eyre::bail!("Wifi is not connected");
+    }
+
+    let clock = Clock::start(&ClockConfig {
+        servers: vec![CONFIG.ntp_server.to_string()],
+        timezone: CONFIG.timezone.to_string(),
+    })?;
+    if !clock.wait_valid(Duration::from_secs(30)) {
+        log::warn!("Time is not synced yet, keep going with uptime");
+    }
+    if let Some(booted) = clock::instant_rfc3339(booted) {
+        log::info!("Wifi started at {booted}");
+    }
+
+    loop {
+        log::info!("{} tick", clock::stamp());
+        FreeRtos::delay_ms(10_000);
+    }
+}
+
+#[derive(Debug)]
+#[toml_cfg::toml_config]
+struct Config {
+    #[default("NO SSID")]
+    wifi_ssid: &'static str,
+    #[default("NO PASSWORD")]
+    wifi_password: &'static str,
+    #[default("pool.ntp.org")]
+    ntp_server: &'static str,
+    #[default("UTC0")]
+    timezone: &'static str,
+}
@@ -5,9 +5,12 @@
 //!
 //! `cargo run --example stepper_with_joystick
 
+use esp32_c3_examples::joystick::{
+    Axis, AxisCalibration, JoystickError, StepRate, StepRateConfig, Velocity,
+};
 use esp_idf_svc::hal::adc::config::Config;
 use esp_idf_svc::hal::adc::{attenuation, AdcChannelDriver, AdcDriver};
-use std::ops::RangeInclusive;
+use std::time::{Duration, Instant};
 
 use esp_idf_svc::hal::delay::Delay;
 use esp_idf_svc::hal::i2c::{I2cConfig, I2cDriver};
@@ -56,111 +59,95 @@ fn main() -> eyre::Result<()> {
 
     log::info!("init motor");
     let delay = Delay::new(10_000);
-    let mut motor1 =
-        uln2003::ULN2003::<_, _, _, _, u32, _>::new(p7, p6, p5, p4, Some(delay.clone()));
-    let mut motor2 = uln2003::ULN2003::<_, _, _, _, u32, _>::new(p3, p2, p1, p0, Some(delay));
+    let motor1 = uln2003::ULN2003::<_, _, _, _, u32, _>::new(p7, p6, p5, p4, Some(delay.clone()));
+    let motor2 = uln2003::ULN2003::<_, _, _, _, u32, _>::new(p3, p2, p1, p0, Some(delay.clone()));
+    let mut motor1 = AxisMotor::new(motor1);
+    let mut motor2 = AxisMotor::new(motor2);
 
-    let mut motor1_last_step_elapsed = 0;
-    let mut motor2_last_step_elapsed = 0;
+    let mut axis_x = Axis::new(AxisCalibration::default(), MAX_JITTER);
+    let mut axis_y = Axis::new(AxisCalibration::default(), MAX_JITTER);
+    let rate_config = StepRateConfig::default();
 
     log::info!("start loop");
+    let mut last_tick = Instant::now();
     loop {
-        match Cmd::from_joystick_value(adc.read(&mut adc_pin_x)?) {
-            Cmd::Stop => {
-                motor1.stop().unwrap();
-            }
-            Cmd::Forward { delay_ms } => {
-                if delay_ms < motor1_last_step_elapsed {
-                    motor1.set_direction(Direction::Normal);
-                    motor1.step().unwrap();
-                    motor1_last_step_elapsed = 0;
-                };
-            }
-            Cmd::Backward { delay_ms } => {
-                if delay_ms < motor1_last_step_elapsed {
-                    motor1.set_direction(Direction::Reverse);
-                    motor1.step().unwrap();
-                    motor1_last_step_elapsed = 0;
-                };
-            }
-        }
+        let now = Instant::now();
+        let elapsed = now - last_tick;
+        last_tick = now;
+
+        let rate = read_rate(&mut axis_x, adc.read(&mut adc_pin_x)?, &rate_config);
+        motor1.drive(rate, elapsed);
+
+        let rate = read_rate(&mut axis_y, adc.read(&mut adc_pin_y)?, &rate_config);
+        motor2.drive(rate, elapsed);
 
-        match Cmd::from_joystick_value(adc.read(&mut adc_pin_y)?) {
-            Cmd::Stop => {
-                motor2.stop().unwrap();
-            }
-            Cmd::Forward { delay_ms } => {
-                if delay_ms < motor2_last_step_elapsed {
-                    motor2.set_direction(Direction::Normal);
-                    motor2.step().unwrap();
-                    motor2_last_step_elapsed = 0;
-                };
-            }
-            Cmd::Backward { delay_ms } => {
-                if delay_ms < motor2_last_step_elapsed {
-                    motor2.set_direction(Direction::Reverse);
-                    motor2.step().unwrap();
-                    motor2_last_step_elapsed = 0;
-                };
-            }
-        }
         delay.delay_us(100);
-        motor1_last_step_elapsed += MIN_DELAY_MS;
-        motor2_last_step_elapsed += MIN_DELAY_MS;
     }
 }
 
-const MIN_DELAY_MS: u32 = 1;
-const MAX_DELAY_MS: u32 = 20;
-
-const JOYSTICK_MIN: u32 = 1;
-const JOYSTICK_CENTER: u32 = 1650;
-const JOYSTICK_MAX: u32 = 2801;
-const JOYSTICK_THRESHOLD: u32 = 30;
-const JOYSTICK_CENTER_RANGE: RangeInclusive<u32> =
-    JOYSTICK_CENTER - JOYSTICK_THRESHOLD..=JOYSTICK_CENTER + JOYSTICK_THRESHOLD;
-
-const RATIO: f64 = MAX_DELAY_MS as f64 / JOYSTICK_MAX as f64;
-
-#[derive(Debug)]
-enum Cmd {
-    Stop,
-    Forward {
-        /// Delay between steps in milliseconds.
-        delay_ms: u32,
-    },
-    Backward {
-        /// Delay between steps in milliseconds.
-        delay_ms: u32,
-    },
+/// Max difference between last ADC samples, more means the joystick is disconnected.
+const MAX_JITTER: u16 = 600;
+
+/// raw sample -> calibrated axis -> velocity command -> stepper rate
+fn read_rate(
+    axis: &mut Axis,
+    raw: u16,
+    config: &StepRateConfig,
+) -> Result<StepRate, JoystickError> {
+    let deflection = axis.read(raw)?;
+    let velocity = Velocity::from_deflection(deflection)?;
+    let rate = StepRate::from_velocity(velocity, config)?;
+    log::debug!("rate = {rate:?} for {raw}");
+    Ok(rate)
+}
+
+/// Stepper motor driven by one joystick axis.
+struct AxisMotor<M> {
+    motor: M,
+    since_last_step: Duration,
+    /// Error is logged once, until the axis is back to normal.
+    failed: bool,
 }
 
-impl Cmd {
-    fn from_joystick_value(joystick_value: u16) -> Self {
-        let res = match joystick_value as u32 {
-            val if JOYSTICK_CENTER_RANGE.contains(&val) => Cmd::Stop,
-            val if val <= JOYSTICK_CENTER => {
-                let delay_ms = Self::value_to_delay(val, false);
-                Cmd::Forward { delay_ms }
+impl<M: StepperMotor<u32>> AxisMotor<M> {
+    fn new(motor: M) -> Self {
+        AxisMotor {
+            motor,
+            since_last_step: Duration::ZERO,
+            failed: false,
+        }
+    }
+
+    /// Makes a step if it's time to, any error stops the motor.
+    fn drive(&mut self, rate: Result<StepRate, JoystickError>, elapsed: Duration) {
+        self.since_last_step += elapsed;
+
+        let (direction, interval) = match rate {
+            Ok(StepRate::Stop) => {
+                self.failed = false;
+                let _ = self.motor.stop();
+                return;
             }
-            val if val > JOYSTICK_CENTER => {
-                let delay_ms = Self::value_to_delay(val, true);
-                Cmd::Backward { delay_ms }
+            Ok(StepRate::Forward { interval }) => (Direction::Normal, interval),
+            Ok(StepRate::Backward { interval }) => (Direction::Reverse, interval),
+            Err(err) => {
+                if !self.failed {
+                    log::error!("Stop motor: {err}");
+                    self.failed = true;
+                }
+                let _ = self.motor.stop();
+                return;
             }
-            _ => panic!("Joystick value is out of range: {}", joystick_value),
         };
-        log::debug!("cmd =  {res:?} for {joystick_value}");
-        res
-    }
+        self.failed = false;
 
-    /// Converts joystick value to the delay in milliseconds.
-    /// The more the joystick is deflected, the faster the motor moves.
-    fn value_to_delay(joystick_value: u32, invert: bool) -> u32 {
-        let value = joystick_value.min(JOYSTICK_MAX).max(JOYSTICK_MIN) as f64;
-        if invert {
-            (MAX_DELAY_MS - (value * RATIO) as u32).max(MIN_DELAY_MS)
-        } else {
-            ((value * RATIO) as u32).max(MIN_DELAY_MS)
+        if self.since_last_step >= interval {
+            self.motor.set_direction(direction);
+            if self.motor.step().is_err() {
+                log::error!("Motor step failed");
+                let _ = self.motor.stop();
+            }
+            self.since_last_step = Duration::ZERO;
         }
     }
 }
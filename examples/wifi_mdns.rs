@@ -0,0 +1,91 @@
+//! Joins WiFi with a fixed address or DHCP hostname and announces itself with mDNS.
+//!
+//! Copy `cfg.toml.example` into `cfg.toml`, fill wifi and address settings, then
+//! open `http://<hostname>.local/` or browse `_http._tcp` services.
+//! `cargo run --example wifi_mdns`
+
+use embedded_svc::io::Write;
+use esp32_c3_examples::wifi;
+use esp32_c3_examples::wifi::mdns::{self, Service};
+use esp32_c3_examples::wifi::netif::{NetifConfig, StaticIp};
+use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_svc::hal::delay::FreeRtos;
+use esp_idf_svc::hal::prelude::*;
+use esp_idf_svc::http::server::{Configuration as HttpConfiguration, EspHttpServer};
+use esp_idf_svc::http::Method;
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use std::time::Duration;
+
+fn main() -> eyre::Result<()> {
+    // It is necessary to call this function once. Otherwise some patches to the runtime
+    // implemented by esp-idf-sys might not link properly. See https://github.com/esp-rs/esp-idf-template/issues/71
+    esp_idf_svc::sys::link_patches();
+
+    // Bind the log crate to the ESP Logging facilities
+    esp_idf_svc::log::EspLogger::initialize_default();
+
+    let peripherals = Peripherals::take()?;
+    let sys_loop = EspSystemEventLoop::take()?;
+    let nvs = EspDefaultNvsPartition::take()?;
+
+    let static_ip = if CONFIG.static_ip.is_empty() {
+        None
+    } else {
+        Some(StaticIp::new(
+            CONFIG.static_ip.parse()?,
+            CONFIG.gateway.parse()?,
+            CONFIG.netmask.parse()?,
+            Some(CONFIG.dns.parse()?),
+        )?)
+    };
+    let netif = NetifConfig::new(Some(CONFIG.hostname), static_ip)?;
+
+    let (wifi, _wifi_thread) = wifi::connect_from_config(
+        peripherals.modem,
+        &sys_loop,
+        nvs,
+        CONFIG.wifi_ssid,
+        CONFIG.wifi_password,
+        netif,
+    )?;
+    if !wifi.wait_up(Duration::from_secs(60)) {
+        eyre::bail!("Wifi is not connected");
+    }
+    log::info!("Wifi status: {:?}", wifi.status());
+
+    let mut server = EspHttpServer::new(&HttpConfiguration::default())?;
+    server.fn_handler("/", Method::Get, |req| {
+        req.into_ok_response()?
+            .write_all(format!("Hello from {}.local", CONFIG.hostname).as_bytes())?;
+        Ok(())
+    })?;
+
+    let services = [
+        Service::http(80).with_txt("path", "/"),
+        Service::new("_esp32c3", "_tcp", 80).with_txt("board", "esp32-c3"),
+    ];
+    let _mdns = mdns::advertise(CONFIG.hostname, "ESP32-C3 examples", &services)?;
+
+    loop {
+        FreeRtos::delay_ms(10_000);
+    }
+}
+
+#[derive(Debug)]
+#[toml_cfg::toml_config]
+struct Config {
+    #[default("NO SSID")]
+    wifi_ssid: &'static str,
+    #[default("NO PASSWORD")]
+    wifi_password: &'static str,
+    #[default("esp32-c3")]
+    hostname: &'static str,
+    #[default("")]
+    static_ip: &'static str,
+    #[default("192.168.1.1")]
+    gateway: &'static str,
+    #[default("255.255.255.0")]
+    netmask: &'static str,
+    #[default("192.168.1.1")]
+    dns: &'static str,
+}
@@ -0,0 +1,61 @@
+//! Joins a stored WiFi network or starts the setup access point.
+//!
+//! On the first boot (or when known networks keep failing) connect to `esp32-c3-setup`
+//! and submit the network in the form, the board reboots and joins it.
+//! `cargo run --example wifi_provisioning`
+
+use esp32_c3_examples::wifi::networks::{NetworkStore, NVS_NAMESPACE};
+use esp32_c3_examples::wifi::provisioning::{self, ProvisioningConfig};
+use esp32_c3_examples::wifi::{WifiConfig, WifiManager};
+use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_svc::hal::delay::FreeRtos;
+use esp_idf_svc::hal::prelude::*;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs};
+use esp_idf_svc::wifi::{BlockingWifi, EspWifi};
+
+fn main() -> eyre::Result<()> {
+    // It is necessary to call this function once. Otherwise some patches to the runtime
+    // implemented by esp-idf-sys might not link properly. See https://github.com/esp-rs/esp-idf-template/issues/71
+    esp_idf_svc::sys::link_patches();
+
+    // Bind the log crate to the ESP Logging facilities
+    esp_idf_svc::log::EspLogger::initialize_default();
+
+    let peripherals = Peripherals::take()?;
+    let sys_loop = EspSystemEventLoop::take()?;
+    let nvs = EspDefaultNvsPartition::take()?;
+
+    let wifi = BlockingWifi::wrap(
+        EspWifi::new(peripherals.modem, sys_loop.clone(), Some(nvs.clone()))?,
+        sys_loop.clone(),
+    )?;
+    // no defaults, networks come from the setup form only
+    let store = NetworkStore::load(EspNvs::new(nvs, NVS_NAMESPACE, true)?, &[])?;
+    let config = ProvisioningConfig::default();
+    let mut manager =
+        WifiManager::new(wifi, &sys_loop, WifiConfig::new(store.networks().to_vec()))?;
+
+    loop {
+        if provisioning::is_needed(
+            store.networks(),
+            &manager.handle().status(),
+            config.max_failures,
+        ) {
+            let mut wifi = manager.into_wifi();
+            provisioning::run(&mut wifi, store, &config)?;
+            log::info!("Rebooting into station mode");
+            esp_idf_svc::hal::reset::restart();
+        }
+        manager.poll();
+        if manager.handle().is_up() {
+            break;
+        }
+        FreeRtos::delay_ms(500);
+    }
+
+    let (wifi, _wifi_thread) = manager.spawn()?;
+    loop {
+        log::info!("Wifi status: {:?}", wifi.status());
+        FreeRtos::delay_ms(10_000);
+    }
+}
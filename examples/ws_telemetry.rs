@@ -0,0 +1,159 @@
+//! Live joystick, servo, stepper and temperature frames over WebSocket.
+//!
+//! * io0, io1 - joystick axes
+//! * io6, io7 - servos sg90, ids 0 and 1
+//! * io2, io3, io5, io10 - IN1-IN4 of ULN2003 with `28byj-48` motor, id 0
+//! * io4 - ds18b20 sensors
+//!
+//! Copy `cfg.toml.example` into `cfg.toml` and fill wifi settings, then open
+//! `http://esp32-c3.local/`, the page prints frames and sends the slider as servo commands.
+//! Any WebSocket client works too, e.g. `websocat ws://esp32-c3.local/ws` and
+//! `{"type": "rate", "hz": 2}` or `{"type": "stepper_move", "id": 0, "steps": 512}`.
+//! `cargo run --example ws_telemetry`
+
+use embedded_svc::io::Write;
+use esp32_c3_examples::joystick::AxisCalibration;
+use esp32_c3_examples::ledc_servo_lib::{Servo, ServoConfig};
+use esp32_c3_examples::rest_api::{steppers, SensorSource, StepperControl};
+use esp32_c3_examples::telemetry::{self, ClientCommand, Frame, Telemetry, TelemetryConfig};
+use esp32_c3_examples::temp_sensor::{task, TempSensors, TempSensorsConfig};
+use esp32_c3_examples::wifi;
+use esp32_c3_examples::wifi::mdns::{self, Service};
+use esp32_c3_examples::wifi::netif::NetifConfig;
+use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_svc::hal::adc::config::Config as AdcConfig;
+use esp_idf_svc::hal::adc::{attenuation, AdcChannelDriver, AdcDriver};
+use esp_idf_svc::hal::delay::{Delay, FreeRtos};
+use esp_idf_svc::hal::gpio::PinDriver;
+use esp_idf_svc::hal::ledc::SpeedMode;
+use esp_idf_svc::hal::prelude::*;
+use esp_idf_svc::http::server::{Configuration as HttpConfiguration, EspHttpServer};
+use esp_idf_svc::http::Method;
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use std::time::{Duration, Instant};
+
+const PAGE: &str = r#"<!DOCTYPE html><html><head><meta charset="utf-8"><title>Telemetry</title></head>
+<body><p><input id="servo" type="range" min="0" max="180" value="90"></p><pre id="frame"></pre>
+<script>
+const ws = new WebSocket(`ws://${location.host}/ws`);
+ws.onmessage = (e) => document.getElementById("frame").textContent =
+  JSON.stringify(JSON.parse(e.data), null, 2);
+document.getElementById("servo").oninput = (e) =>
+  ws.send(JSON.stringify({type: "servo", id: 0, angle: Number(e.target.value)}));
+</script></body></html>"#;
+
+fn main() -> eyre::Result<()> {
+    // It is necessary to call this function once. Otherwise some patches to the runtime
+    // implemented by esp-idf-sys might not link properly. See https://github.com/esp-rs/esp-idf-template/issues/71
+    esp_idf_svc::sys::link_patches();
+
+    // Bind the log crate to the ESP Logging facilities
+    esp_idf_svc::log::EspLogger::initialize_default();
+
+    let peripherals = Peripherals::take()?;
+    let sys_loop = EspSystemEventLoop::take()?;
+    let nvs = EspDefaultNvsPartition::take()?;
+
+    // joystick
+    let mut adc = AdcDriver::new(peripherals.adc1, &AdcConfig::new().calibration(true))?;
+    let mut adc_pin_x: AdcChannelDriver<{ attenuation::DB_11 }, _> =
+        AdcChannelDriver::new(peripherals.pins.gpio0)?;
+    let mut adc_pin_y: AdcChannelDriver<{ attenuation::DB_11 }, _> =
+        AdcChannelDriver::new(peripherals.pins.gpio1)?;
+    let axis = AxisCalibration::default();
+
+    let mut servos = vec![
+        Servo::new(
+            ServoConfig::sg90(SpeedMode::LowSpeed),
+            peripherals.ledc.timer0,
+            peripherals.ledc.channel0,
+            peripherals.pins.gpio6,
+        )?,
+        Servo::new(
+            ServoConfig::sg90(SpeedMode::LowSpeed),
+            peripherals.ledc.timer1,
+            peripherals.ledc.channel1,
+            peripherals.pins.gpio7,
+        )?,
+    ];
+
+    // the delay of the driver isn't used, steps are timed by the steppers thread
+    let motor = uln2003::ULN2003::<_, _, _, _, u32, Delay>::new(
+        PinDriver::output(peripherals.pins.gpio2)?,
+        PinDriver::output(peripherals.pins.gpio3)?,
+        PinDriver::output(peripherals.pins.gpio5)?,
+        PinDriver::output(peripherals.pins.gpio10)?,
+        None,
+    );
+    let (mut steppers, _steppers_thread) = steppers::spawn(vec![motor])?;
+
+    let pin4 = PinDriver::input_output(peripherals.pins.gpio4)?;
+    let sensors = TempSensors::new(pin4, TempSensorsConfig::default())?;
+    let (readings, _sensors_thread) = task::spawn(sensors)?;
+
+    let (wifi, _wifi_thread) = wifi::connect_from_config(
+        peripherals.modem,
+        &sys_loop,
+        nvs,
+        CONFIG.wifi_ssid,
+        CONFIG.wifi_password,
+        NetifConfig::new(Some(CONFIG.hostname), None)?,
+    )?;
+    if !wifi.wait_up(Duration::from_secs(60)) {
+        eyre::bail!("Wifi is not connected");
+    }
+    log::info!("Wifi status: {:?}", wifi.status());
+
+    let (telemetry, commands) = Telemetry::new(TelemetryConfig {
+        rate_hz: CONFIG.telemetry_rate_hz,
+        ..Default::default()
+    });
+    let mut server = EspHttpServer::new(&HttpConfiguration::default())?;
+    server.fn_handler("/", Method::Get, |req| {
+        req.into_ok_response()?.write_all(PAGE.as_bytes())?;
+        Ok(())
+    })?;
+    telemetry::serve(&mut server, "/ws", telemetry.clone())?;
+    let _mdns = mdns::advertise(
+        CONFIG.hostname,
+        "ESP32-C3 examples",
+        &[Service::http(80).with_txt("path", "/")],
+    )?;
+    log::info!("Telemetry: http://{}.local/", CONFIG.hostname);
+
+    let started = Instant::now();
+    loop {
+        FreeRtos::delay_ms(20);
+
+        for ClientCommand { session, command } in commands.try_iter() {
+            if let Err(err) = command.apply(&mut servos, &mut steppers) {
+                log::warn!("Telemetry: {command:?} of {session} failed: {err}");
+                telemetry.reply_error(session, &err);
+            }
+        }
+
+        // a broken stick is reported as released
+        let x = axis.normalize(adc.read(&mut adc_pin_x)?).unwrap_or(0.0);
+        let y = axis.normalize(adc.read(&mut adc_pin_y)?).unwrap_or(0.0);
+        telemetry.publish(|| Frame {
+            uptime_ms: started.elapsed().as_millis() as u64,
+            axes: vec![x, y],
+            servos: servos.iter().map(|servo| servo.get_angle()).collect(),
+            steppers: (0..steppers.count()).map(|id| steppers.state(id)).collect(),
+            temperatures: readings.sensors(),
+        });
+    }
+}
+
+#[derive(Debug)]
+#[toml_cfg::toml_config]
+struct Config {
+    #[default("NO SSID")]
+    wifi_ssid: &'static str,
+    #[default("NO PASSWORD")]
+    wifi_password: &'static str,
+    #[default("esp32-c3")]
+    hostname: &'static str,
+    #[default(10)]
+    telemetry_rate_hz: u32,
+}
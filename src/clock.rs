@@ -0,0 +1,227 @@
+//! Wall clock synchronised by SNTP.
+//!
+//! Start [`Clock`] once the network is up. Until the first sync the system time is
+//! 1970, so [`is_valid`] is `false` and [`stamp`] falls back to uptime. Local time follows
+//! the POSIX `TZ` string of [`ClockConfig::timezone`].
+//!
+//! ```ignore
+//! let clock = Clock::start(&ClockConfig::default())?;
+//! clock.wait_valid(Duration::from_secs(30));
+//! log::info!("{} temperature {t:.1}", clock::stamp());
+//! ```
+
+use esp_idf_svc::sntp::{EspSntp, SntpConf};
+use esp_idf_svc::sys::{self, EspError, ESP_ERR_INVALID_ARG};
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Set by the SNTP callback, the system clock is global, so is the flag.
+static TIME_VALID: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone)]
+pub struct ClockConfig {
+    /// Host names or addresses, up to `CONFIG_LWIP_SNTP_MAX_SERVERS` are used.
+    pub servers: Vec<String>,
+    /// POSIX `TZ`, e.g. `CET-1CEST,M3.5.0,M10.5.0/3`.
+    pub timezone: String,
+}
+
+impl Default for ClockConfig {
+    fn default() -> Self {
+        ClockConfig {
+            servers: vec!["pool.ntp.org".to_string()],
+            timezone: "UTC0".to_string(),
+        }
+    }
+}
+
+/// Keeps SNTP running, the sync stops when dropped.
+pub struct Clock {
+    _sntp: EspSntp<'static>,
+}
+
+impl Clock {
+    /// Sets the timezone and starts SNTP, only one clock can exist.
+    pub fn start(config: &ClockConfig) -> Result<Self, EspError> {
+        set_timezone(&config.timezone)?;
+        if config.servers.is_empty() {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        let mut conf = SntpConf::default();
+        if config.servers.len() > conf.servers.len() {
+            log::warn!(
+                "SNTP: only {} servers are supported, raise CONFIG_LWIP_SNTP_MAX_SERVERS",
+                conf.servers.len()
+            );
+        }
+        // spare slots repeat the servers, so the pool defaults aren't asked
+        for (i, slot) in conf.servers.iter_mut().enumerate() {
+            *slot = &config.servers[i % config.servers.len()];
+        }
+        let sntp = EspSntp::new_with_callback(&conf, |synced| {
+            if !TIME_VALID.swap(true, Ordering::Relaxed) {
+                log::info!("SNTP: time is synced, {}", format_utc(synced));
+            }
+        })?;
+        log::info!("SNTP: started with {:?}", config.servers);
+        Ok(Clock { _sntp: sntp })
+    }
+
+    /// `false` if the time isn't synced within `timeout`.
+    pub fn wait_valid(&self, timeout: Duration) -> bool {
+        let started = Instant::now();
+        while !is_valid() {
+            if started.elapsed() >= timeout {
+                return false;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        true
+    }
+}
+
+/// The time was synced at least once since boot.
+pub fn is_valid() -> bool {
+    TIME_VALID.load(Ordering::Relaxed)
+}
+
+/// Local time of `SystemTime::now()`, `None` until the first sync.
+pub fn now_rfc3339() -> Option<String> {
+    is_valid().then(|| rfc3339(SystemTime::now()))
+}
+
+/// Wall clock time of the earlier `instant`, e.g. when a reading was taken.
+/// `None` until the first sync.
+pub fn instant_rfc3339(instant: Instant) -> Option<String> {
+    let ago = Instant::now().saturating_duration_since(instant);
+    is_valid().then(|| rfc3339(SystemTime::now() - ago))
+}
+
+/// RFC 3339 when the time is valid, `+<uptime>s` before the first sync.
+pub fn stamp() -> String {
+    now_rfc3339().unwrap_or_else(|| {
+        // microseconds since boot
+        let uptime = Duration::from_micros(unsafe { sys::esp_timer_get_time() } as u64);
+        format!("+{:.3}s", uptime.as_secs_f32())
+    })
+}
+
+/// Local time with the UTC offset of the timezone, e.g. `2024-03-31T03:00:00.000+02:00`.
+pub fn rfc3339(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs() as i64;
+    format_rfc3339(secs, since_epoch.subsec_millis(), utc_offset(secs))
+}
+
+fn format_utc(since_epoch: Duration) -> String {
+    format_rfc3339(since_epoch.as_secs() as i64, since_epoch.subsec_millis(), 0)
+}
+
+/// Applies the POSIX `TZ` string to `localtime`.
+fn set_timezone(timezone: &str) -> Result<(), EspError> {
+    if timezone.is_empty() || timezone.contains('\0') {
+        return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+    }
+    std::env::set_var("TZ", timezone);
+    unsafe { sys::tzset() };
+    Ok(())
+}
+
+/// Offset of the local time from UTC at `secs`, DST included.
+fn utc_offset(secs: i64) -> i32 {
+    let time = secs as sys::time_t;
+    let mut tm: sys::tm = unsafe { std::mem::zeroed() };
+    if unsafe { sys::localtime_r(&time, &mut tm) }.is_null() {
+        return 0;
+    }
+    let days = days_from_civil(
+        tm.tm_year as i64 + 1900,
+        tm.tm_mon as u32 + 1,
+        tm.tm_mday as u32,
+    );
+    let local = days * 86400 + tm.tm_hour as i64 * 3600 + tm.tm_min as i64 * 60 + tm.tm_sec as i64;
+    (local - secs) as i32
+}
+
+/// `unix_secs` is UTC, `offset_secs` is added to it for the local fields.
+pub fn format_rfc3339(unix_secs: i64, millis: u32, offset_secs: i32) -> String {
+    let local = unix_secs + offset_secs as i64;
+    let (year, month, day) = civil_from_days(local.div_euclid(86400));
+    let secs_of_day = local.rem_euclid(86400);
+    let mut out = format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{millis:03}",
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+    );
+    if offset_secs == 0 {
+        out.push('Z');
+    } else {
+        let sign = if offset_secs < 0 { '-' } else { '+' };
+        let offset = offset_secs.unsigned_abs() / 60;
+        let _ = write!(out, "{sign}{:02}:{:02}", offset / 60, offset % 60);
+    }
+    out
+}
+
+/// Days since 1970-01-01 of the proleptic Gregorian date, see
+/// <https://howardhinnant.github.io/date_algorithms.html>.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Inverse of [`days_from_civil`].
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::clock::{civil_from_days, days_from_civil, format_rfc3339};
+
+    #[test]
+    fn civil_test() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 3, 1), 11017);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+        for days in [-1, 0, 59, 10956, 11016, 11017, 19782, 47482] {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+        assert_eq!(civil_from_days(19782), (2024, 2, 29));
+    }
+
+    #[test]
+    fn format_rfc3339_test() {
+        assert_eq!(format_rfc3339(0, 0, 0), "1970-01-01T00:00:00.000Z");
+        // 2024-03-31T01:00:00Z, just after the DST switch in Central Europe
+        assert_eq!(
+            format_rfc3339(1711846800, 42, 2 * 3600),
+            "2024-03-31T03:00:00.042+02:00"
+        );
+        // local day is before the UTC one
+        assert_eq!(
+            format_rfc3339(1704070800, 999, -(5 * 3600 + 30 * 60)),
+            "2023-12-31T19:30:00.999-05:30"
+        );
+    }
+}
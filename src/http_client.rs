@@ -0,0 +1,687 @@
+//! HTTP client over an `embedded_svc` connection, `EspHttpConnection` on the board.
+//!
+//! * bodies of any length are streamed in chunks, see [`Response::for_each_chunk`]
+//! * redirects are followed up to [`HttpConfig::max_redirects`]
+//! * the whole request, redirects and body included, is limited by [`HttpConfig::timeout`]
+//! * non-2xx statuses are [`HttpError::Status`] with the start of the body
+//! * JSON bodies are (de)serialised with `serde`
+//! * HTTPS servers are checked by the certificate bundle, a pinned CA or fingerprint,
+//!   failed checks are [`HttpError::Certificate`], see [`tls`]
+
+use embedded_svc::http::client::Connection;
+use embedded_svc::http::Method;
+use esp_idf_svc::http::client::{Configuration, EspHttpConnection, FollowRedirectsPolicy};
+use esp_idf_svc::sys::EspError;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt;
+use std::ops::ControlFlow;
+use std::time::{Duration, Instant};
+use tls::{CertificateError, ServerTrust, TlsErrors};
+
+pub mod tls;
+
+const CHUNK_LEN: usize = 512;
+/// Body of the error status kept for logs.
+const ERROR_BODY_LEN: usize = 256;
+
+#[derive(Debug, Clone)]
+pub struct HttpConfig {
+    /// Whole request, redirects and reading the body included.
+    pub timeout: Duration,
+    pub max_redirects: u8,
+    /// Limit of bodies read into memory, streamed bodies aren't limited.
+    pub max_body_len: usize,
+    pub user_agent: String,
+    pub trust: ServerTrust,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            timeout: Duration::from_secs(10),
+            max_redirects: 5,
+            max_body_len: 64 * 1024,
+            user_agent: "esp32-c3-examples".to_string(),
+            trust: ServerTrust::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum HttpError<E> {
+    Connection(E),
+    /// TLS handshake failed on the server certificate.
+    Certificate(CertificateError),
+    /// Non-2xx status after redirects.
+    Status {
+        status: u16,
+        body: String,
+    },
+    /// Too many redirects or a redirect without a valid `Location`.
+    Redirect(&'static str),
+    Timeout,
+    /// The body is longer than [`HttpConfig::max_body_len`].
+    BodyTooLarge,
+    /// Stopped by the chunk callback.
+    Aborted,
+    Json(serde_json::Error),
+}
+
+impl<E> HttpError<E> {
+    /// Status of [`HttpError::Status`].
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            HttpError::Status { status, .. } => Some(*status),
+            _ => None,
+        }
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for HttpError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HttpError::Connection(err) => write!(f, "connection error: {err}"),
+            HttpError::Certificate(err) => write!(f, "TLS error: {err}"),
+            HttpError::Status { status, body } => write!(f, "status {status}: {body}"),
+            HttpError::Redirect(msg) => write!(f, "redirect error: {msg}"),
+            HttpError::Timeout => f.write_str("timeout"),
+            HttpError::BodyTooLarge => f.write_str("body is too large"),
+            HttpError::Aborted => f.write_str("aborted"),
+            HttpError::Json(err) => write!(f, "JSON error: {err}"),
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> std::error::Error for HttpError<E> {}
+
+pub struct HttpClient<C> {
+    connection: C,
+    config: HttpConfig,
+}
+
+impl HttpClient<EspHttpConnection> {
+    /// Redirects are followed by the wrapper, not by ESP-IDF.
+    pub fn new(config: HttpConfig) -> Result<Self, EspError> {
+        let mut conf = Configuration {
+            timeout: Some(config.timeout),
+            follow_redirects_policy: FollowRedirectsPolicy::FollowNone,
+            ..Default::default()
+        };
+        config.trust.configure(&mut conf)?;
+        let connection = EspHttpConnection::new(&conf)?;
+        Ok(HttpClient::wrap(connection, config))
+    }
+}
+
+impl<C: Connection + TlsErrors> HttpClient<C> {
+    pub fn wrap(connection: C, config: HttpConfig) -> Self {
+        HttpClient { connection, config }
+    }
+
+    pub fn release(self) -> C {
+        self.connection
+    }
+
+    /// Sends the request and follows redirects, the body of the 2xx response is left for
+    /// the caller. `303`, and `301`/`302` of `POST`, continue with `GET` without the body.
+    pub fn request(
+        &mut self,
+        method: Method,
+        uri: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> Result<Response<'_, C>, HttpError<C::Error>> {
+        let deadline = Instant::now() + self.config.timeout;
+        let (mut method, mut uri, mut body) = (method, uri.to_string(), body);
+        let mut redirects = 0;
+        loop {
+            // checked before each hop too, a slow redirect chain can't outlive the timeout
+            if is_expired(deadline) {
+                return Err(HttpError::Timeout);
+            }
+            let status = self.send(method, &uri, headers, body)?;
+            if !is_redirect(status) {
+                let response = Response {
+                    connection: &mut self.connection,
+                    status,
+                    deadline,
+                };
+                if !(200..300).contains(&status) {
+                    return Err(response.into_status_error());
+                }
+                return Ok(response);
+            }
+
+            if redirects == self.config.max_redirects {
+                return Err(HttpError::Redirect("too many redirects"));
+            }
+            redirects += 1;
+            let location = self
+                .connection
+                .header("Location")
+                .ok_or(HttpError::Redirect("no Location header"))?;
+            let next = url::Url::parse(&uri)
+                .and_then(|base| base.join(location))
+                .map_err(|_| HttpError::Redirect("invalid Location header"))?;
+            log::debug!("HTTP {status}: {uri} -> {next}");
+            uri = next.into();
+            if status == 303 || (matches!(status, 301 | 302) && method == Method::Post) {
+                if method != Method::Head {
+                    method = Method::Get;
+                }
+                body = &[];
+            }
+            // the connection is reused, the rest of the redirect is skipped
+            let response = Response {
+                connection: &mut self.connection,
+                status,
+                deadline,
+            };
+            response.for_each_chunk(|_| ControlFlow::Continue(()))?;
+        }
+    }
+
+    /// Body of the 2xx response, up to [`HttpConfig::max_body_len`].
+    pub fn get(&mut self, uri: &str) -> Result<Vec<u8>, HttpError<C::Error>> {
+        let max_len = self.config.max_body_len;
+        self.request(Method::Get, uri, &[], &[])?
+            .read_to_end(max_len)
+    }
+
+    pub fn get_json<T: DeserializeOwned>(&mut self, uri: &str) -> Result<T, HttpError<C::Error>> {
+        let body = self.get(uri)?;
+        serde_json::from_slice(&body).map_err(HttpError::Json)
+    }
+
+    /// Posts `body` as JSON, the response is JSON too.
+    pub fn post_json<B: Serialize, T: DeserializeOwned>(
+        &mut self,
+        uri: &str,
+        body: &B,
+    ) -> Result<T, HttpError<C::Error>> {
+        let body = serde_json::to_vec(body).map_err(HttpError::Json)?;
+        let max_len = self.config.max_body_len;
+        let headers = [
+            ("Content-Type", "application/json"),
+            ("Accept", "application/json"),
+        ];
+        let response = self
+            .request(Method::Post, uri, &headers, &body)?
+            .read_to_end(max_len)?;
+        serde_json::from_slice(&response).map_err(HttpError::Json)
+    }
+
+    /// Returns the status, the response headers are available on the connection.
+    fn send(
+        &mut self,
+        method: Method,
+        uri: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> Result<u16, HttpError<C::Error>> {
+        let content_len = body.len().to_string();
+        let mut all_headers = vec![("User-Agent", self.config.user_agent.as_str())];
+        if !body.is_empty() || matches!(method, Method::Post | Method::Put | Method::Patch) {
+            all_headers.push(("Content-Length", content_len.as_str()));
+        }
+        all_headers.extend_from_slice(headers);
+
+        log::debug!("HTTP -> {method:?} {uri}");
+        self.connection
+            .initiate_request(method, uri, &all_headers)
+            .map_err(|err| self.connection_error(err))?;
+        self.connection
+            .write_all(body)
+            .map_err(|err| self.connection_error(err))?;
+        self.connection
+            .initiate_response()
+            .map_err(|err| self.connection_error(err))?;
+        let status = self.connection.status();
+        log::debug!("HTTP <- {status}");
+        Ok(status)
+    }
+
+    /// Certificate errors are told apart from the other connection errors.
+    fn connection_error(&mut self, err: C::Error) -> HttpError<C::Error> {
+        match self.connection.take_certificate_error() {
+            Some(cert) => HttpError::Certificate(cert),
+            None => HttpError::Connection(err),
+        }
+    }
+}
+
+/// Response with unread body.
+pub struct Response<'a, C> {
+    connection: &'a mut C,
+    status: u16,
+    deadline: Instant,
+}
+
+impl<'a, C: Connection> Response<'a, C> {
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.connection.header(name)
+    }
+
+    /// `0` at the end of the body.
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, HttpError<C::Error>> {
+        if is_expired(self.deadline) {
+            return Err(HttpError::Timeout);
+        }
+        self.connection.read(buf).map_err(HttpError::Connection)
+    }
+
+    /// Passes the body to `f` chunk by chunk, returns the body length.
+    /// [`ControlFlow::Break`] stops reading with [`HttpError::Aborted`].
+    pub fn for_each_chunk<F>(mut self, mut f: F) -> Result<usize, HttpError<C::Error>>
+    where
+        F: FnMut(&[u8]) -> ControlFlow<()>,
+    {
+        let mut buf = [0u8; CHUNK_LEN];
+        let mut total = 0;
+        loop {
+            let len = self.read(&mut buf)?;
+            if len == 0 {
+                return Ok(total);
+            }
+            total += len;
+            if f(&buf[..len]).is_break() {
+                return Err(HttpError::Aborted);
+            }
+        }
+    }
+
+    /// Fails with [`HttpError::BodyTooLarge`] after `max_len` bytes.
+    pub fn read_to_end(self, max_len: usize) -> Result<Vec<u8>, HttpError<C::Error>> {
+        let mut body = Vec::new();
+        let result = self.for_each_chunk(|chunk| {
+            if body.len() + chunk.len() > max_len {
+                return ControlFlow::Break(());
+            }
+            body.extend_from_slice(chunk);
+            ControlFlow::Continue(())
+        });
+        match result {
+            Ok(_) => Ok(body),
+            Err(HttpError::Aborted) => Err(HttpError::BodyTooLarge),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn into_status_error(mut self) -> HttpError<C::Error> {
+        let mut buf = [0u8; ERROR_BODY_LEN];
+        let mut len = 0;
+        while len < buf.len() {
+            match self.read(&mut buf[len..]) {
+                Ok(0) | Err(_) => break,
+                Ok(read) => len += read,
+            }
+        }
+        HttpError::Status {
+            status: self.status,
+            body: String::from_utf8_lossy(&buf[..len]).into_owned(),
+        }
+    }
+}
+
+fn is_expired(deadline: Instant) -> bool {
+    Instant::now() >= deadline
+}
+
+fn is_redirect(status: u16) -> bool {
+    matches!(status, 301 | 302 | 303 | 307 | 308)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::http_client::tls::{CertificateError, TlsErrors};
+    use crate::http_client::{HttpClient, HttpConfig, HttpError};
+    use embedded_svc::http::client::Connection;
+    use embedded_svc::http::{Headers, Method, Status};
+    use embedded_svc::io::{ErrorKind, ErrorType, Read, Write};
+    use serde::{Deserialize, Serialize};
+    use std::collections::VecDeque;
+    use std::ops::ControlFlow;
+    use std::time::Duration;
+
+    #[derive(Debug)]
+    pub struct FakeError;
+
+    impl embedded_svc::io::Error for FakeError {
+        fn kind(&self) -> ErrorKind {
+            ErrorKind::Other
+        }
+    }
+
+    struct FakeResponse {
+        status: u16,
+        headers: Vec<(&'static str, String)>,
+        body: Vec<u8>,
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct SentRequest {
+        method: Method,
+        uri: String,
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+    }
+
+    /// Replays scripted responses, records requests, reads at most 100 bytes at once.
+    #[derive(Default)]
+    struct FakeConnection {
+        responses: VecDeque<FakeResponse>,
+        current: Option<FakeResponse>,
+        read_pos: usize,
+        requests: Vec<SentRequest>,
+        /// Fails the next handshake.
+        certificate_error: Option<CertificateError>,
+        /// Of each response.
+        latency: Duration,
+    }
+
+    impl FakeConnection {
+        fn respond(mut self, status: u16, headers: &[(&'static str, &str)], body: &[u8]) -> Self {
+            self.responses.push_back(FakeResponse {
+                status,
+                headers: headers.iter().map(|(k, v)| (*k, v.to_string())).collect(),
+                body: body.to_vec(),
+            });
+            self
+        }
+    }
+
+    impl ErrorType for FakeConnection {
+        type Error = FakeError;
+    }
+
+    impl Status for FakeConnection {
+        fn status(&self) -> u16 {
+            self.current.as_ref().unwrap().status
+        }
+
+        fn status_message(&self) -> Option<&'_ str> {
+            None
+        }
+    }
+
+    impl Headers for FakeConnection {
+        fn header(&self, name: &str) -> Option<&'_ str> {
+            let current = self.current.as_ref()?;
+            let (_, value) = current.headers.iter().find(|(k, _)| *k == name)?;
+            Some(value)
+        }
+    }
+
+    impl Read for FakeConnection {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, FakeError> {
+            let body = &self.current.as_ref().ok_or(FakeError)?.body;
+            let len = buf.len().min(100).min(body.len() - self.read_pos);
+            buf[..len].copy_from_slice(&body[self.read_pos..self.read_pos + len]);
+            self.read_pos += len;
+            Ok(len)
+        }
+    }
+
+    impl Write for FakeConnection {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, FakeError> {
+            self.requests
+                .last_mut()
+                .unwrap()
+                .body
+                .extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<(), FakeError> {
+            Ok(())
+        }
+    }
+
+    impl Connection for FakeConnection {
+        type Headers = Self;
+        type Read = Self;
+        type RawConnectionError = FakeError;
+        type RawConnection = Self;
+
+        fn initiate_request<'a>(
+            &'a mut self,
+            method: Method,
+            uri: &'a str,
+            headers: &'a [(&'a str, &'a str)],
+        ) -> Result<(), FakeError> {
+            self.current = None;
+            self.requests.push(SentRequest {
+                method,
+                uri: uri.to_string(),
+                headers: headers
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+                body: Vec::new(),
+            });
+            Ok(())
+        }
+
+        fn is_request_initiated(&self) -> bool {
+            self.current.is_none()
+        }
+
+        fn initiate_response(&mut self) -> Result<(), FakeError> {
+            if self.certificate_error.is_some() {
+                return Err(FakeError);
+            }
+            std::thread::sleep(self.latency);
+            self.current = Some(self.responses.pop_front().ok_or(FakeError)?);
+            self.read_pos = 0;
+            Ok(())
+        }
+
+        fn is_response_initiated(&self) -> bool {
+            self.current.is_some()
+        }
+
+        fn split(&mut self) -> (&Self, &mut Self) {
+            unimplemented!()
+        }
+
+        fn raw_connection(&mut self) -> Result<&mut Self, FakeError> {
+            Err(FakeError)
+        }
+    }
+
+    impl TlsErrors for FakeConnection {
+        fn take_certificate_error(&mut self) -> Option<CertificateError> {
+            self.certificate_error.take()
+        }
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Reading {
+        sensor: String,
+        celsius: f32,
+    }
+
+    #[test]
+    fn redirect_json_test() {
+        let connection = FakeConnection::default()
+            .respond(301, &[("Location", "/v2/reading")], b"moved")
+            .respond(302, &[("Location", "https://b.io/reading")], b"")
+            .respond(200, &[], br#"{"sensor":"28ff","celsius":21.5}"#);
+        let mut client = HttpClient::wrap(connection, HttpConfig::default());
+        let reading: Reading = client.get_json("http://a.io/v1/reading?x=1").unwrap();
+        assert_eq!(reading.celsius, 21.5);
+
+        let uris = client
+            .release()
+            .requests
+            .iter()
+            .map(|r| r.uri.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            uris,
+            vec![
+                "http://a.io/v1/reading?x=1",
+                "http://a.io/v2/reading",
+                "https://b.io/reading"
+            ]
+        );
+
+        let mut looping = FakeConnection::default();
+        for _ in 0..3 {
+            looping = looping.respond(307, &[("Location", "/again")], b"");
+        }
+        let config = HttpConfig {
+            max_redirects: 2,
+            ..Default::default()
+        };
+        let mut client = HttpClient::wrap(looping, config);
+        assert!(matches!(
+            client.get("http://a.io/"),
+            Err(HttpError::Redirect(_))
+        ));
+    }
+
+    #[test]
+    fn redirect_timeout_test() {
+        let mut slow = FakeConnection {
+            latency: Duration::from_millis(30),
+            ..Default::default()
+        };
+        for _ in 0..3 {
+            slow = slow.respond(307, &[("Location", "/again")], b"");
+        }
+        let config = HttpConfig {
+            timeout: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let mut client = HttpClient::wrap(slow, config);
+        assert!(matches!(
+            client.get("http://a.io/"),
+            Err(HttpError::Timeout)
+        ));
+        // the third hop isn't sent
+        assert_eq!(client.release().requests.len(), 2);
+
+        let config = HttpConfig {
+            timeout: Duration::ZERO,
+            ..Default::default()
+        };
+        let mut client = HttpClient::wrap(FakeConnection::default(), config);
+        assert!(matches!(
+            client.get("http://a.io/"),
+            Err(HttpError::Timeout)
+        ));
+        assert!(client.release().requests.is_empty());
+    }
+
+    #[test]
+    fn post_json_test() {
+        let connection = FakeConnection::default()
+            .respond(303, &[("Location", "/readings/1")], b"")
+            .respond(200, &[], br#"{"sensor":"28ff","celsius":20.0}"#);
+        let mut client = HttpClient::wrap(connection, HttpConfig::default());
+        let sent = Reading {
+            sensor: "28ff".to_string(),
+            celsius: 20.0,
+        };
+        let received: Reading = client.post_json("http://a.io/readings", &sent).unwrap();
+        assert_eq!(received, sent);
+
+        let requests = client.release().requests;
+        assert_eq!(requests[0].method, Method::Post);
+        assert_eq!(requests[0].body, serde_json::to_vec(&sent).unwrap());
+        let header = |name: &str| {
+            requests[0]
+                .headers
+                .iter()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.clone())
+        };
+        assert_eq!(header("Content-Type").as_deref(), Some("application/json"));
+        assert_eq!(
+            header("Content-Length"),
+            Some(requests[0].body.len().to_string())
+        );
+        // 303 continues with GET without the body
+        assert_eq!(requests[1].method, Method::Get);
+        assert!(requests[1].body.is_empty());
+    }
+
+    #[test]
+    fn status_error_test() {
+        let connection = FakeConnection::default()
+            .respond(404, &[], b"no such sensor")
+            .respond(200, &[], b"not json");
+        let mut client = HttpClient::wrap(connection, HttpConfig::default());
+        let err = client.get("http://a.io/x").unwrap_err();
+        assert_eq!(err.status(), Some(404));
+        assert!(matches!(err, HttpError::Status { body, .. } if body == "no such sensor"));
+        assert!(matches!(
+            client.get_json::<Reading>("http://a.io/y"),
+            Err(HttpError::Json(_))
+        ));
+    }
+
+    #[test]
+    fn certificate_error_test() {
+        let expired = CertificateError::Rejected { flags: 0x01 };
+        let connection = FakeConnection {
+            certificate_error: Some(expired),
+            ..Default::default()
+        }
+        .respond(200, &[], b"ok");
+        let mut client = HttpClient::wrap(connection, HttpConfig::default());
+        assert!(matches!(
+            client.get("https://a.io/"),
+            Err(HttpError::Certificate(err)) if err == expired
+        ));
+        // other failures stay connection errors
+        assert_eq!(client.get("https://a.io/").unwrap(), b"ok");
+        assert!(matches!(
+            client.get("https://a.io/"),
+            Err(HttpError::Connection(_))
+        ));
+    }
+
+    #[test]
+    fn body_test() {
+        let body = (0..10_000).map(|i| i as u8).collect::<Vec<_>>();
+        let connection = FakeConnection::default()
+            .respond(200, &[], &body)
+            .respond(200, &[], &body)
+            .respond(200, &[], &body);
+        let config = HttpConfig {
+            max_body_len: 4096,
+            ..Default::default()
+        };
+        let mut client = HttpClient::wrap(connection, config);
+
+        // streamed bodies aren't limited
+        let mut streamed = Vec::new();
+        let len = client
+            .request(Method::Get, "http://a.io/firmware", &[], &[])
+            .unwrap()
+            .for_each_chunk(|chunk| {
+                streamed.extend_from_slice(chunk);
+                ControlFlow::Continue(())
+            })
+            .unwrap();
+        assert_eq!(len, body.len());
+        assert_eq!(streamed, body);
+
+        assert!(matches!(
+            client.get("http://a.io/firmware"),
+            Err(HttpError::BodyTooLarge)
+        ));
+        let response = client.request(Method::Get, "http://a.io/firmware", &[], &[]);
+        assert!(matches!(
+            response.unwrap().for_each_chunk(|_| ControlFlow::Break(())),
+            Err(HttpError::Aborted)
+        ));
+    }
+}
@@ -0,0 +1,212 @@
+//! Server certificate checks of HTTPS requests.
+//!
+//! * [`ServerTrust::Bundle`] - public CAs of the ESP-IDF certificate bundle
+//!   (`CONFIG_MBEDTLS_CERTIFICATE_BUNDLE`)
+//! * [`ServerTrust::Ca`] - only servers signed by the PEM CA, e.g. a lab CA
+//! * [`ServerTrust::Fingerprint`] - only the server certificate with this SHA-256
+//!
+//! Both custom modes are global in ESP-IDF, the last created client wins.
+
+use crate::lock_recover;
+use esp_idf_svc::handle::RawHandle;
+use esp_idf_svc::http::client::{Configuration, EspHttpConnection};
+use esp_idf_svc::sys::{self, EspError};
+use std::ffi::{c_int, c_void};
+use std::fmt;
+use std::mem::MaybeUninit;
+use std::sync::{Mutex, Once};
+
+/// Pinned by the last [`ServerTrust::Fingerprint`] client.
+static PINNED: Mutex<Option<[u8; 32]>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum ServerTrust {
+    #[default]
+    Bundle,
+    /// PEM of the CA.
+    Ca(String),
+    /// SHA-256 of the DER server certificate, see [`ServerTrust::fingerprint`].
+    Fingerprint([u8; 32]),
+}
+
+impl ServerTrust {
+    /// Hex with optional `:` separators, as printed by
+    /// `openssl x509 -noout -fingerprint -sha256`.
+    pub fn fingerprint(hex: &str) -> Result<Self, CertificateError> {
+        let digits = hex
+            .chars()
+            .filter(|c| *c != ':' && !c.is_whitespace())
+            .collect::<Vec<_>>();
+        if digits.len() != 64 {
+            return Err(CertificateError::InvalidFingerprint);
+        }
+        let mut fingerprint = [0u8; 32];
+        for (byte, pair) in fingerprint.iter_mut().zip(digits.chunks(2)) {
+            let pair = pair.iter().collect::<String>();
+            *byte =
+                u8::from_str_radix(&pair, 16).map_err(|_| CertificateError::InvalidFingerprint)?;
+        }
+        Ok(ServerTrust::Fingerprint(fingerprint))
+    }
+
+    /// Sets up the connection configuration, installs the global CA or fingerprint.
+    pub(crate) fn configure(&self, conf: &mut Configuration) -> Result<(), EspError> {
+        match self {
+            ServerTrust::Bundle => conf.crt_bundle_attach = Some(sys::esp_crt_bundle_attach),
+            ServerTrust::Ca(pem) => {
+                // mbedTLS wants PEM with the trailing zero
+                let mut pem = pem.as_bytes().to_vec();
+                pem.push(0);
+                EspError::convert(unsafe {
+                    sys::esp_tls_set_global_ca_store(pem.as_ptr(), pem.len() as _)
+                })?;
+                conf.use_global_ca_store = true;
+            }
+            ServerTrust::Fingerprint(fingerprint) => {
+                *lock_recover(&PINNED) = Some(*fingerprint);
+                conf.crt_bundle_attach = Some(attach_pinned);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Failed certificate check, see mbedTLS `MBEDTLS_X509_BADCERT_*` flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CertificateError {
+    Rejected { flags: u32 },
+    InvalidFingerprint,
+}
+
+impl CertificateError {
+    /// Human readable reasons of the flags.
+    pub fn reasons(&self) -> Vec<&'static str> {
+        const REASONS: [(u32, &str); 8] = [
+            (0x01, "expired"),
+            (0x02, "revoked"),
+            (0x04, "host name mismatch"),
+            (0x08, "not trusted"),
+            (0x40, "missing"),
+            (0x0100, "rejected, e.g. fingerprint mismatch"),
+            (0x0200, "not valid yet, is the clock synced?"),
+            (0x4000, "weak signature"),
+        ];
+        match self {
+            CertificateError::Rejected { flags } => REASONS
+                .iter()
+                .filter(|(flag, _)| flags & flag != 0)
+                .map(|(_, reason)| *reason)
+                .collect(),
+            CertificateError::InvalidFingerprint => vec!["invalid fingerprint"],
+        }
+    }
+}
+
+impl fmt::Display for CertificateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CertificateError::Rejected { flags } => {
+                write!(f, "certificate {} ({flags:#x})", self.reasons().join(", "))
+            }
+            CertificateError::InvalidFingerprint => {
+                f.write_str("fingerprint should be 32 hex bytes")
+            }
+        }
+    }
+}
+
+impl std::error::Error for CertificateError {}
+
+/// Connections which tell why the TLS handshake failed.
+pub trait TlsErrors {
+    /// Verification flags of the last failed handshake, cleared on read.
+    fn take_certificate_error(&mut self) -> Option<CertificateError>;
+}
+
+impl TlsErrors for EspHttpConnection {
+    fn take_certificate_error(&mut self) -> Option<CertificateError> {
+        let (mut code, mut flags) = (0, 0);
+        // returns the last error, e.g. ESP_ERR_MBEDTLS_SSL_HANDSHAKE_FAILED, not a status,
+        // only the flags tell a rejected certificate
+        unsafe {
+            sys::esp_http_client_get_and_clear_last_tls_error(self.handle(), &mut code, &mut flags)
+        };
+        (flags != 0).then_some(CertificateError::Rejected {
+            flags: flags as u32,
+        })
+    }
+}
+
+/// `crt_bundle_attach` replacement: the chain isn't checked, only the server certificate.
+unsafe extern "C" fn attach_pinned(conf: *mut c_void) -> sys::esp_err_t {
+    // mbedTLS requires a CA chain, an empty one is enough, like in `esp_crt_bundle`
+    static mut EMPTY_CA: MaybeUninit<sys::mbedtls_x509_crt> = MaybeUninit::uninit();
+    static INIT: Once = Once::new();
+    let ca = std::ptr::addr_of_mut!(EMPTY_CA).cast::<sys::mbedtls_x509_crt>();
+    INIT.call_once(|| sys::mbedtls_x509_crt_init(ca));
+
+    let conf = conf as *mut sys::mbedtls_ssl_config;
+    sys::mbedtls_ssl_conf_ca_chain(conf, ca, std::ptr::null_mut());
+    sys::mbedtls_ssl_conf_verify(conf, Some(verify_pinned), std::ptr::null_mut());
+    sys::ESP_OK
+}
+
+/// Called for each certificate of the chain, the server one is the last with `depth` 0.
+unsafe extern "C" fn verify_pinned(
+    _ctx: *mut c_void,
+    crt: *mut sys::mbedtls_x509_crt,
+    depth: c_int,
+    flags: *mut u32,
+) -> c_int {
+    if depth > 0 {
+        *flags = 0;
+        return 0;
+    }
+    let pinned = *lock_recover(&PINNED);
+    let raw = &(*crt).raw;
+    let mut fingerprint = [0u8; 32];
+    let md = sys::mbedtls_md_info_from_type(sys::mbedtls_md_type_t_MBEDTLS_MD_SHA256);
+    let hashed = sys::mbedtls_md(md, raw.p, raw.len, fingerprint.as_mut_ptr()) == 0;
+    if hashed && pinned == Some(fingerprint) {
+        *flags = 0;
+    } else {
+        log::warn!("TLS: server certificate doesn't match the pinned fingerprint");
+        *flags |= sys::MBEDTLS_X509_BADCERT_OTHER;
+    }
+    0
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::http_client::tls::{CertificateError, ServerTrust};
+
+    #[test]
+    fn fingerprint_test() {
+        let hex = "00:11:22:33:44:55:66:77:88:99:AA:BB:CC:DD:EE:FF:\
+                   00:11:22:33:44:55:66:77:88:99:aa:bb:cc:dd:ee:ff";
+        let ServerTrust::Fingerprint(fingerprint) = ServerTrust::fingerprint(hex).unwrap() else {
+            panic!("not a fingerprint");
+        };
+        assert_eq!(fingerprint[..4], [0x00, 0x11, 0x22, 0x33]);
+        assert_eq!(fingerprint[31], 0xFF);
+        assert_eq!(
+            ServerTrust::fingerprint(&hex.replace(':', "")),
+            ServerTrust::fingerprint(hex)
+        );
+        assert!(ServerTrust::fingerprint("00:11").is_err());
+        assert!(ServerTrust::fingerprint(&"zz".repeat(32)).is_err());
+    }
+
+    #[test]
+    fn reasons_test() {
+        let err = CertificateError::Rejected { flags: 0x0208 };
+        assert_eq!(
+            err.reasons(),
+            vec!["not trusted", "not valid yet, is the clock synced?"]
+        );
+        assert_eq!(
+            err.to_string(),
+            "certificate not trusted, not valid yet, is the clock synced? (0x208)"
+        );
+    }
+}
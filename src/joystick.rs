@@ -0,0 +1,351 @@
+//! Helpers for simple analog joystick ([like this one](https://components101.com/modules/joystick-module)).
+//!
+//! Hardware is not touched here: feed raw ADC values and button state, get back commands
+//! and button events. Axis values go through the pipeline where every stage can fail:
+//!
+//! raw sample -> [`Axis`] (calibrated deflection) -> [`Velocity`] -> [`StepRate`]
+//!
+//! Any error means the stick can't be trusted and the driven motor should be stopped.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JoystickError {
+    /// Raw value is too far outside of calibrated range.
+    OutOfRange { raw: u16 },
+    /// Consecutive samples jump too much, most likely the axis pin is floating.
+    Disconnected { spread: u16 },
+    /// Deflection isn't a number in `-1.0..=1.0`.
+    InvalidDeflection(f32),
+    /// Speed isn't a number in `0.0..=1.0`.
+    InvalidSpeed(f32),
+}
+
+impl fmt::Display for JoystickError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JoystickError::OutOfRange { raw } => write!(f, "joystick value {raw} is out of range"),
+            JoystickError::Disconnected { spread } => {
+                write!(f, "joystick looks disconnected, samples spread is {spread}")
+            }
+            JoystickError::InvalidDeflection(value) => write!(f, "invalid deflection {value}"),
+            JoystickError::InvalidSpeed(value) => write!(f, "invalid speed {value}"),
+        }
+    }
+}
+
+impl std::error::Error for JoystickError {}
+
+/// Raw ADC values of one joystick axis.
+#[derive(Debug, Clone)]
+pub struct AxisCalibration {
+    /// Raw value at the one end of the axis.
+    pub min: u16,
+    /// Raw value when the stick is released.
+    pub center: u16,
+    /// Raw value at the other end of the axis.
+    pub max: u16,
+    /// Values closer than this to `center` are treated as center.
+    pub dead_zone: u16,
+    /// Values outside of `min..=max` by less than this are clamped, others are errors.
+    pub tolerance: u16,
+}
+
+impl Default for AxisCalibration {
+    /// Values measured with joystick from `adc_joystick` example (11db attenuation).
+    fn default() -> Self {
+        AxisCalibration {
+            min: 1,
+            center: 1650,
+            max: 2801,
+            dead_zone: 30,
+            tolerance: 200,
+        }
+    }
+}
+
+impl AxisCalibration {
+    /// Transforms raw ADC value to the deflection in range `-1.0..=1.0`, `0.0` is center.
+    pub fn normalize(&self, raw: u16) -> Result<f32, JoystickError> {
+        if raw < self.min.saturating_sub(self.tolerance)
+            || raw > self.max.saturating_add(self.tolerance)
+        {
+            return Err(JoystickError::OutOfRange { raw });
+        }
+
+        let raw = raw.clamp(self.min, self.max) as f32;
+        let center = self.center as f32;
+        let dead_zone = self.dead_zone as f32;
+
+        let deflection = if (raw - center).abs() <= dead_zone {
+            0.0
+        } else if raw < center {
+            let span = (center - dead_zone - self.min as f32).max(1.0);
+            -((center - dead_zone - raw) / span).min(1.0)
+        } else {
+            let span = (self.max as f32 - center - dead_zone).max(1.0);
+            ((raw - center - dead_zone) / span).min(1.0)
+        };
+        Ok(deflection)
+    }
+}
+
+/// Number of last samples checked for the floating input.
+const JITTER_WINDOW: usize = 4;
+
+/// One joystick axis: checks that input is sane and calibrates it.
+#[derive(Debug)]
+pub struct Axis {
+    calibration: AxisCalibration,
+    /// Max allowed difference between the last samples, floating ADC input jumps much more
+    /// than a human can move the stick between two polls.
+    max_jitter: u16,
+    samples: VecDeque<u16>,
+}
+
+impl Axis {
+    pub fn new(calibration: AxisCalibration, max_jitter: u16) -> Self {
+        Axis {
+            calibration,
+            max_jitter,
+            samples: VecDeque::with_capacity(JITTER_WINDOW),
+        }
+    }
+
+    /// Takes raw ADC sample, returns deflection in range `-1.0..=1.0`.
+    pub fn read(&mut self, raw: u16) -> Result<f32, JoystickError> {
+        if self.samples.len() == JITTER_WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(raw);
+
+        let min = self.samples.iter().min().copied().unwrap_or(raw);
+        let max = self.samples.iter().max().copied().unwrap_or(raw);
+        let spread = max - min;
+        if spread > self.max_jitter {
+            return Err(JoystickError::Disconnected { spread });
+        }
+
+        self.calibration.normalize(raw)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Velocity {
+    Stop,
+    /// Speed in range `0.0..=1.0`.
+    Forward(f32),
+    /// Speed in range `0.0..=1.0`.
+    Backward(f32),
+}
+
+impl Velocity {
+    /// Negative deflection moves forward, positive - backward.
+    pub fn from_deflection(deflection: f32) -> Result<Self, JoystickError> {
+        if !(-1.0..=1.0).contains(&deflection) {
+            return Err(JoystickError::InvalidDeflection(deflection));
+        }
+        let velocity = if deflection == 0.0 {
+            Velocity::Stop
+        } else if deflection < 0.0 {
+            Velocity::Forward(-deflection)
+        } else {
+            Velocity::Backward(deflection)
+        };
+        Ok(velocity)
+    }
+}
+
+/// Step timing of stepper motor.
+#[derive(Debug, Clone)]
+pub struct StepRateConfig {
+    /// Delay between steps at full speed.
+    pub min_interval: Duration,
+    /// Delay between steps at the lowest speed.
+    pub max_interval: Duration,
+}
+
+impl Default for StepRateConfig {
+    /// Works well for `28byj-48` driven by `ULN2003`.
+    fn default() -> Self {
+        StepRateConfig {
+            min_interval: Duration::from_millis(1),
+            max_interval: Duration::from_millis(20),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StepRate {
+    Stop,
+    Forward { interval: Duration },
+    Backward { interval: Duration },
+}
+
+impl StepRate {
+    /// The more the speed, the less delay between steps.
+    pub fn from_velocity(
+        velocity: Velocity,
+        config: &StepRateConfig,
+    ) -> Result<Self, JoystickError> {
+        let interval = |speed: f32| {
+            if !(0.0..=1.0).contains(&speed) {
+                return Err(JoystickError::InvalidSpeed(speed));
+            }
+            let range = config.max_interval.saturating_sub(config.min_interval);
+            Ok(config.max_interval - range.mul_f64(speed as f64))
+        };
+
+        let rate = match velocity {
+            Velocity::Stop => StepRate::Stop,
+            Velocity::Forward(speed) => StepRate::Forward {
+                interval: interval(speed)?,
+            },
+            Velocity::Backward(speed) => StepRate::Backward {
+                interval: interval(speed)?,
+            },
+        };
+        Ok(rate)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ButtonEvent {
+    /// Button was pressed and released before `long_press` elapsed.
+    Click,
+    /// Button is held longer than `long_press`, fired once per press.
+    LongPress,
+}
+
+/// Turns polled button state into click and long press events.
+#[derive(Debug)]
+pub struct Button {
+    long_press: Duration,
+    pressed_since: Option<Instant>,
+    long_press_fired: bool,
+}
+
+/// Presses shorter than this are treated as contact bounce.
+const DEBOUNCE: Duration = Duration::from_millis(30);
+
+impl Button {
+    pub fn new(long_press: Duration) -> Self {
+        Button {
+            long_press,
+            pressed_since: None,
+            long_press_fired: false,
+        }
+    }
+
+    /// Should be called on each poll with the current button state.
+    pub fn update(&mut self, pressed: bool, now: Instant) -> Option<ButtonEvent> {
+        match (pressed, self.pressed_since) {
+            (true, None) => {
+                self.pressed_since = Some(now);
+                self.long_press_fired = false;
+                None
+            }
+            (true, Some(since)) => {
+                if !self.long_press_fired && now.duration_since(since) >= self.long_press {
+                    self.long_press_fired = true;
+                    Some(ButtonEvent::LongPress)
+                } else {
+                    None
+                }
+            }
+            (false, Some(since)) => {
+                self.pressed_since = None;
+                let held = now.duration_since(since);
+                if !self.long_press_fired && held >= DEBOUNCE {
+                    Some(ButtonEvent::Click)
+                } else {
+                    None
+                }
+            }
+            (false, None) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::joystick::{
+        Axis, AxisCalibration, Button, ButtonEvent, JoystickError, StepRate, StepRateConfig,
+        Velocity,
+    };
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn normalize_test() {
+        let axis = AxisCalibration::default();
+        assert_eq!(axis.normalize(1650), Ok(0.0));
+        assert_eq!(axis.normalize(1670), Ok(0.0));
+        assert_eq!(axis.normalize(0), Ok(-1.0));
+        assert_eq!(axis.normalize(2900), Ok(1.0));
+        assert_eq!(
+            axis.normalize(4095),
+            Err(JoystickError::OutOfRange { raw: 4095 })
+        );
+        let half = axis.normalize(2240).unwrap();
+        assert!(half > 0.4 && half < 0.6, "{half}");
+    }
+
+    #[test]
+    fn floating_axis_test() {
+        let mut axis = Axis::new(AxisCalibration::default(), 300);
+        assert_eq!(axis.read(1650), Ok(0.0));
+        assert!(axis.read(1800).is_ok());
+        assert_eq!(
+            axis.read(600),
+            Err(JoystickError::Disconnected { spread: 1200 })
+        );
+    }
+
+    #[test]
+    fn step_rate_test() {
+        let config = StepRateConfig::default();
+        let rate = |deflection| {
+            Velocity::from_deflection(deflection)
+                .and_then(|velocity| StepRate::from_velocity(velocity, &config))
+        };
+
+        assert_eq!(rate(0.0), Ok(StepRate::Stop));
+        assert_eq!(
+            rate(-1.0),
+            Ok(StepRate::Forward {
+                interval: Duration::from_millis(1)
+            })
+        );
+        assert_eq!(
+            rate(0.5),
+            Ok(StepRate::Backward {
+                interval: Duration::from_micros(10_500)
+            })
+        );
+        assert_eq!(
+            rate(f32::NAN).unwrap_err().to_string(),
+            "invalid deflection NaN"
+        );
+    }
+
+    #[test]
+    fn button_test() {
+        let mut button = Button::new(Duration::from_secs(1));
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+
+        assert_eq!(button.update(true, at(0)), None);
+        assert_eq!(button.update(false, at(100)), Some(ButtonEvent::Click));
+
+        assert_eq!(button.update(true, at(200)), None);
+        assert_eq!(button.update(true, at(1300)), Some(ButtonEvent::LongPress));
+        assert_eq!(button.update(true, at(1400)), None);
+        assert_eq!(button.update(false, at(1500)), None);
+
+        // bounce
+        assert_eq!(button.update(true, at(2000)), None);
+        assert_eq!(button.update(false, at(2010)), None);
+    }
+}
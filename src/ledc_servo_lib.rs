@@ -77,6 +77,11 @@ impl<'d> Servo<'d> {
         })
     }
 
+    /// Max angle that servo can be turned, see [`ServoConfig::max_angle`].
+    pub fn max_angle(&self) -> f64 {
+        self.config.max_angle
+    }
+
     pub fn get_angle(&self) -> f64 {
         let max_duty = self.ledc_driver.get_max_duty();
         let current_duty = self.ledc_driver.get_duty();
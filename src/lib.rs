@@ -0,0 +1,24 @@
+//! Small reusable components shared by the main binary and the examples.
+
+use std::sync::{Mutex, MutexGuard};
+
+pub mod clock;
+pub mod http_client;
+pub mod joystick;
+pub mod ledc_servo_lib;
+pub mod mqtt;
+pub mod ota;
+pub mod pan_tilt;
+pub mod rest_api;
+pub mod telemetry;
+pub mod temp_sensor;
+pub mod thermostat;
+pub mod wifi;
+
+/// Locks `mutex` even if a thread panicked while holding it. The shared state of the
+/// modules is always left consistent, a panicked writer doesn't break it.
+pub fn lock_recover<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
@@ -3,9 +3,7 @@ use esp_idf_svc::hal::ledc;
 use esp_idf_svc::hal::prelude::*;
 use eyre::Result;
 
-use ledc_servo_lib::{Servo, ServoConfig};
-
-mod ledc_servo_lib;
+use esp32_c3_examples::ledc_servo_lib::{Servo, ServoConfig};
 
 fn main() -> Result<()> {
     // It is necessary to call this function once. Otherwise some patches to the runtime
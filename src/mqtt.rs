@@ -0,0 +1,721 @@
+//! MQTT client that survives broker restarts.
+//!
+//! * connection state is tracked from the client events, see [`Mqtt::status`]
+//! * subscriptions are remembered and subscribed again after each reconnect
+//! * publishes go through a bounded queue, kept while offline, see [`QueuePolicy`]
+//! * received messages are passed to the handlers of matching topic filters
+//! * an optional birth message is published on each connect, see [`availability`]
+//!
+//! [`EspMqttClient`] is owned by a worker thread, the event callback only updates the
+//! shared state: the ESP-IDF MQTT task never waits for a lock held by a publisher.
+
+use crate::lock_recover;
+use embedded_svc::mqtt::client::{Details, Event, QoS};
+use esp_idf_svc::mqtt::client::{EspMqttClient, EspMqttMessage, MqttClientConfiguration};
+use esp_idf_svc::sys::EspError;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+pub mod availability;
+pub mod broker;
+pub mod discovery;
+pub mod tls;
+
+/// Logging with formatting doesn't fit into 4K.
+const STACK_SIZE: usize = 6144;
+/// Pause after a failed publish or subscribe, the connection is likely going down.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Longest topic of the MQTT spec.
+const MAX_TOPIC_LEN: usize = 65535;
+
+#[derive(Debug, Clone)]
+pub struct MqttConfig {
+    /// Messages waiting for the broker, the rest is handled by `policy`.
+    pub queue_len: usize,
+    pub policy: QueuePolicy,
+    /// Published first after each connect, e.g. [`availability::birth`], it takes a
+    /// place of `queue_len` like any other message.
+    pub birth: Option<Message>,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        MqttConfig {
+            queue_len: 32,
+            policy: QueuePolicy::DropOldest,
+            birth: None,
+        }
+    }
+}
+
+/// What happens to a publish when the queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueuePolicy {
+    /// The oldest queued message makes room, e.g. for periodic readings.
+    DropOldest,
+    /// The new message is rejected with [`MqttError::QueueFull`].
+    DropNewest,
+}
+
+#[derive(Debug)]
+pub enum MqttError {
+    InvalidTopic(&'static str),
+    QueueFull,
+    Serialize(serde_json::Error),
+    Thread(std::io::Error),
+    Client(EspError),
+}
+
+impl fmt::Display for MqttError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MqttError::InvalidTopic(err) => write!(f, "invalid topic: {err}"),
+            MqttError::QueueFull => f.write_str("publish queue is full"),
+            MqttError::Serialize(err) => write!(f, "payload isn't serialised: {err}"),
+            MqttError::Thread(err) => write!(f, "MQTT thread isn't started: {err}"),
+            MqttError::Client(err) => write!(f, "MQTT client failed: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for MqttError {}
+
+impl From<EspError> for MqttError {
+    fn from(err: EspError) -> Self {
+        MqttError::Client(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MqttStatus {
+    pub connected: bool,
+    /// Times the connection was lost.
+    pub disconnects: u32,
+    /// Publishes waiting for the broker.
+    pub queued: usize,
+    /// Publishes lost to the queue limit.
+    pub dropped: u32,
+}
+
+/// Outgoing message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: QoS,
+    pub retain: bool,
+}
+
+/// What the worker thread does with the client.
+pub trait Transport {
+    fn subscribe(&mut self, filter: &str, qos: QoS) -> Result<(), EspError>;
+    fn publish(&mut self, message: &Message) -> Result<(), EspError>;
+}
+
+impl Transport for EspMqttClient<'static> {
+    fn subscribe(&mut self, filter: &str, qos: QoS) -> Result<(), EspError> {
+        EspMqttClient::subscribe(self, filter, qos).map(|_| ())
+    }
+
+    fn publish(&mut self, message: &Message) -> Result<(), EspError> {
+        EspMqttClient::publish(
+            self,
+            &message.topic,
+            message.qos,
+            message.retain,
+            &message.payload,
+        )
+        .map(|_| ())
+    }
+}
+
+type Handler = Arc<dyn Fn(&str, &[u8]) + Send + Sync>;
+
+/// Cheap to clone handle to the client.
+#[derive(Clone)]
+pub struct Mqtt(Arc<Shared>);
+
+struct Shared {
+    config: MqttConfig,
+    state: Mutex<State>,
+    work: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    status: MqttStatus,
+    subscriptions: Vec<Subscription>,
+    queue: VecDeque<Message>,
+}
+
+struct Subscription {
+    filter: String,
+    qos: QoS,
+    handler: Handler,
+    /// Sent to the broker since the last connect.
+    subscribed: bool,
+}
+
+enum Work {
+    Subscribe(String, QoS),
+    Publish(Message),
+}
+
+impl Mqtt {
+    /// Connects to `url`, e.g. `mqtt://192.168.1.10:1883`, and runs the worker thread forever.
+    /// See [`broker::BrokerConfig`] for the URL and `conf`.
+    pub fn start(
+        url: &str,
+        conf: &MqttClientConfiguration,
+        config: MqttConfig,
+    ) -> Result<(Self, JoinHandle<()>), MqttError> {
+        let mqtt = Mqtt::new(config);
+        let events = mqtt.clone();
+        let mut client = EspMqttClient::new(url, conf, move |event| match event {
+            Ok(event) => events.handle_event(event),
+            Err(err) => log::warn!("MQTT: {err}"),
+        })?;
+        let worker = mqtt.clone();
+        let thread = std::thread::Builder::new()
+            .name("mqtt".to_string())
+            .stack_size(STACK_SIZE)
+            .spawn(move || loop {
+                let work = worker.wait_work();
+                if !worker.execute(work, &mut client) {
+                    std::thread::sleep(RETRY_DELAY);
+                }
+            })
+            .map_err(MqttError::Thread)?;
+        Ok((mqtt, thread))
+    }
+
+    /// Handle without a client, [`Mqtt::start`] connects one.
+    fn new(config: MqttConfig) -> Self {
+        Mqtt(Arc::new(Shared {
+            config,
+            state: Mutex::new(State::default()),
+            work: Condvar::new(),
+        }))
+    }
+
+    pub fn status(&self) -> MqttStatus {
+        let state = self.lock();
+        MqttStatus {
+            queued: state.queue.len(),
+            ..state.status
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.lock().status.connected
+    }
+
+    /// Blocks until connected, returns `false` on timeout.
+    pub fn wait_connected(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.lock();
+        while !state.status.connected {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            state = self
+                .0
+                .work
+                .wait_timeout(state, deadline - now)
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .0;
+        }
+        true
+    }
+
+    /// Queues the message, it is sent once connected.
+    pub fn publish(
+        &self,
+        topic: &str,
+        qos: QoS,
+        retain: bool,
+        payload: impl Into<Vec<u8>>,
+    ) -> Result<(), MqttError> {
+        check_topic(topic)?;
+        let message = Message {
+            topic: topic.to_string(),
+            payload: payload.into(),
+            qos,
+            retain,
+        };
+        let mut state = self.lock();
+        if state.queue.len() >= self.0.config.queue_len {
+            state.status.dropped += 1;
+            match self.0.config.policy {
+                QueuePolicy::DropOldest => {
+                    state.queue.pop_front();
+                }
+                QueuePolicy::DropNewest => return Err(MqttError::QueueFull),
+            }
+        }
+        state.queue.push_back(message);
+        self.0.work.notify_all();
+        Ok(())
+    }
+
+    pub fn publish_json<T: Serialize>(
+        &self,
+        topic: &str,
+        qos: QoS,
+        retain: bool,
+        payload: &T,
+    ) -> Result<(), MqttError> {
+        let payload = serde_json::to_vec(payload).map_err(MqttError::Serialize)?;
+        self.publish(topic, qos, retain, payload)
+    }
+
+    /// Subscribes now if connected and again after each reconnect. `handler` gets the
+    /// topic and payload of matching messages, it is called from the ESP-IDF MQTT task
+    /// and should be quick.
+    pub fn subscribe(
+        &self,
+        filter: &str,
+        qos: QoS,
+        handler: impl Fn(&str, &[u8]) + Send + Sync + 'static,
+    ) -> Result<(), MqttError> {
+        check_filter(filter)?;
+        self.lock().subscriptions.push(Subscription {
+            filter: filter.to_string(),
+            qos,
+            handler: Arc::new(handler),
+            subscribed: false,
+        });
+        self.0.work.notify_all();
+        Ok(())
+    }
+
+    fn handle_event(&self, event: &Event<EspMqttMessage<'_>>) {
+        match event {
+            Event::Connected(_) => self.on_connected(),
+            Event::Disconnected => self.on_disconnected(),
+            Event::Received(message) => match (message.details(), message.topic()) {
+                (Details::Complete, Some(topic)) => self.on_message(topic, message.data()),
+                _ => log::warn!("MQTT: message longer than the buffer is dropped"),
+            },
+            _ => {}
+        }
+    }
+
+    fn on_connected(&self) {
+        let mut state = self.lock();
+        state.status.connected = true;
+        // a clean session has no subscriptions, a persistent one ignores duplicates
+        for subscription in state.subscriptions.iter_mut() {
+            subscription.subscribed = false;
+        }
+        if let Some(birth) = &self.0.config.birth {
+            // one birth per queue, reconnects before it went out don't add more
+            state.queue.retain(|message| message != birth);
+            if state.queue.len() >= self.0.config.queue_len {
+                state.status.dropped += 1;
+                match self.0.config.policy {
+                    QueuePolicy::DropOldest => state.queue.pop_front(),
+                    QueuePolicy::DropNewest => state.queue.pop_back(),
+                };
+            }
+            state.queue.push_front(birth.clone());
+        }
+        log::info!("MQTT: connected, {} queued", state.queue.len());
+        self.0.work.notify_all();
+    }
+
+    fn on_disconnected(&self) {
+        let mut state = self.lock();
+        if state.status.connected {
+            state.status.connected = false;
+            state.status.disconnects += 1;
+            log::warn!("MQTT: disconnected");
+        }
+    }
+
+    fn on_message(&self, topic: &str, payload: &[u8]) {
+        // handlers may publish, they are called without the lock
+        let handlers: Vec<Handler> = self
+            .lock()
+            .subscriptions
+            .iter()
+            .filter(|subscription| topic_matches(&subscription.filter, topic))
+            .map(|subscription| subscription.handler.clone())
+            .collect();
+        for handler in handlers {
+            handler(topic, payload);
+        }
+    }
+
+    /// Waits until connected with something to do.
+    fn wait_work(&self) -> Work {
+        let mut state = self.lock();
+        loop {
+            if let Some(work) = next_work(&mut state) {
+                return work;
+            }
+            state = self
+                .0
+                .work
+                .wait(state)
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+    }
+
+    /// Returns `false` if the work failed and is left for the next connection.
+    fn execute(&self, work: Work, transport: &mut impl Transport) -> bool {
+        match work {
+            Work::Subscribe(filter, qos) => match transport.subscribe(&filter, qos) {
+                Ok(()) => true,
+                Err(err) => {
+                    log::warn!("MQTT: subscribe to {filter} failed: {err}");
+                    let mut state = self.lock();
+                    for subscription in state.subscriptions.iter_mut() {
+                        if subscription.filter == filter {
+                            subscription.subscribed = false;
+                        }
+                    }
+                    false
+                }
+            },
+            Work::Publish(message) => match transport.publish(&message) {
+                Ok(()) => true,
+                Err(err) => {
+                    log::warn!("MQTT: publish to {} failed: {err}", message.topic);
+                    // back to the front, the queue may have filled up meanwhile
+                    let mut state = self.lock();
+                    if state.queue.len() >= self.0.config.queue_len {
+                        state.status.dropped += 1;
+                        match self.0.config.policy {
+                            // the failed message is the oldest one
+                            QueuePolicy::DropOldest => return false,
+                            QueuePolicy::DropNewest => state.queue.pop_back(),
+                        };
+                    }
+                    state.queue.push_front(message);
+                    false
+                }
+            },
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        lock_recover(&self.0.state)
+    }
+}
+
+/// Subscriptions go first, replies to the queued messages may depend on them.
+fn next_work(state: &mut State) -> Option<Work> {
+    if !state.status.connected {
+        return None;
+    }
+    let pending = state.subscriptions.iter().position(|s| !s.subscribed);
+    if let Some(pos) = pending {
+        let filter = state.subscriptions[pos].filter.clone();
+        // one SUBSCRIBE per filter, with the highest QoS asked for
+        let mut qos = QoS::AtMostOnce;
+        for subscription in state.subscriptions.iter_mut() {
+            if subscription.filter == filter {
+                subscription.subscribed = true;
+                qos = max_qos(qos, subscription.qos);
+            }
+        }
+        return Some(Work::Subscribe(filter, qos));
+    }
+    state.queue.pop_front().map(Work::Publish)
+}
+
+fn max_qos(a: QoS, b: QoS) -> QoS {
+    if (b as u8) > (a as u8) {
+        b
+    } else {
+        a
+    }
+}
+
+/// Topic of a publish, without wildcards.
+pub fn check_topic(topic: &str) -> Result<(), MqttError> {
+    check_len(topic)?;
+    if topic.contains(['+', '#']) {
+        return Err(MqttError::InvalidTopic(
+            "wildcards are only allowed in filters",
+        ));
+    }
+    Ok(())
+}
+
+/// Filter of a subscription, `+` matches one level and a trailing `#` the rest.
+pub fn check_filter(filter: &str) -> Result<(), MqttError> {
+    check_len(filter)?;
+    let levels: Vec<&str> = filter.split('/').collect();
+    for (i, level) in levels.iter().enumerate() {
+        let is_last = i + 1 == levels.len();
+        match *level {
+            "+" => {}
+            "#" if is_last => {}
+            "#" => return Err(MqttError::InvalidTopic("# should be the last level")),
+            _ if level.contains(['+', '#']) => {
+                return Err(MqttError::InvalidTopic("wildcards should be whole levels"))
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn check_len(topic: &str) -> Result<(), MqttError> {
+    if topic.is_empty() {
+        return Err(MqttError::InvalidTopic("topic is empty"));
+    }
+    if topic.len() > MAX_TOPIC_LEN {
+        return Err(MqttError::InvalidTopic("topic is too long"));
+    }
+    if topic.contains('\0') {
+        return Err(MqttError::InvalidTopic("topic contains NUL"));
+    }
+    Ok(())
+}
+
+/// Whether `topic` matches the valid `filter`.
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    // `#` and `+` at the first level don't match `$SYS` like topics
+    if topic.starts_with('$') && filter.starts_with(['+', '#']) {
+        return false;
+    }
+    let mut topic_levels = topic.split('/');
+    for level in filter.split('/') {
+        match (level, topic_levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (level, Some(topic_level)) if level == topic_level => {}
+            _ => return false,
+        }
+    }
+    topic_levels.next().is_none()
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::mqtt::{
+        check_filter, check_topic, topic_matches, Message, Mqtt, MqttConfig, MqttError,
+        QueuePolicy, Transport,
+    };
+    use embedded_svc::mqtt::client::QoS;
+    use esp_idf_svc::sys::{EspError, ESP_FAIL};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Default)]
+    struct FakeBroker {
+        subscribed: Vec<(String, QoS)>,
+        published: Vec<Message>,
+        online: bool,
+    }
+
+    impl Transport for FakeBroker {
+        fn subscribe(&mut self, filter: &str, qos: QoS) -> Result<(), EspError> {
+            if !self.online {
+                return Err(EspError::from_infallible::<ESP_FAIL>());
+            }
+            self.subscribed.push((filter.to_string(), qos));
+            Ok(())
+        }
+
+        fn publish(&mut self, message: &Message) -> Result<(), EspError> {
+            if !self.online {
+                return Err(EspError::from_infallible::<ESP_FAIL>());
+            }
+            self.published.push(message.clone());
+            Ok(())
+        }
+    }
+
+    /// What the worker thread does until there is nothing left or a failure.
+    fn run(mqtt: &Mqtt, broker: &mut FakeBroker) {
+        loop {
+            let Some(work) = super::next_work(&mut mqtt.lock()) else {
+                return;
+            };
+            if !mqtt.execute(work, broker) {
+                return;
+            }
+        }
+    }
+
+    #[test]
+    fn reconnect_test() {
+        let mqtt = Mqtt::new(MqttConfig::default());
+        let mut broker = FakeBroker {
+            online: true,
+            ..Default::default()
+        };
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let sink = received.clone();
+        mqtt.subscribe("board/+/set", QoS::AtMostOnce, move |topic, payload| {
+            sink.lock()
+                .unwrap()
+                .push((topic.to_string(), payload.to_vec()));
+        })
+        .unwrap();
+        mqtt.subscribe("board/+/set", QoS::AtLeastOnce, |_, _| {})
+            .unwrap();
+        mqtt.publish("board/status", QoS::AtMostOnce, true, "offline")
+            .unwrap();
+
+        // nothing is sent before the connection
+        run(&mqtt, &mut broker);
+        assert!(broker.subscribed.is_empty());
+        assert_eq!(mqtt.status().queued, 1);
+
+        mqtt.on_connected();
+        run(&mqtt, &mut broker);
+        assert_eq!(
+            broker.subscribed,
+            vec![("board/+/set".to_string(), QoS::AtLeastOnce)]
+        );
+        assert_eq!(broker.published.len(), 1);
+        assert_eq!(mqtt.status().queued, 0);
+
+        mqtt.on_message("board/servo/set", b"45");
+        mqtt.on_message("board/servo/get", b"45");
+        assert_eq!(
+            *received.lock().unwrap(),
+            vec![("board/servo/set".to_string(), b"45".to_vec())]
+        );
+
+        // broker restart, the publish fails and waits for the next connection
+        broker.online = false;
+        mqtt.publish("board/temperature", QoS::AtLeastOnce, false, "21.5")
+            .unwrap();
+        run(&mqtt, &mut broker);
+        mqtt.on_disconnected();
+        assert_eq!(mqtt.status().queued, 1);
+        assert_eq!(mqtt.status().disconnects, 1);
+
+        broker.online = true;
+        mqtt.on_connected();
+        run(&mqtt, &mut broker);
+        assert_eq!(broker.subscribed.len(), 2);
+        assert_eq!(broker.published[1].topic, "board/temperature");
+        assert!(mqtt.status().connected);
+    }
+
+    #[test]
+    fn birth_test() {
+        let mqtt = Mqtt::new(MqttConfig {
+            birth: Some(Message {
+                topic: "board/status".to_string(),
+                payload: b"online".to_vec(),
+                qos: QoS::AtLeastOnce,
+                retain: true,
+            }),
+            ..MqttConfig::default()
+        });
+        let mut broker = FakeBroker {
+            online: true,
+            ..Default::default()
+        };
+        mqtt.publish("board/n", QoS::AtMostOnce, false, "1")
+            .unwrap();
+
+        // the birth goes before the queued messages, on every connect
+        mqtt.on_connected();
+        run(&mqtt, &mut broker);
+        mqtt.on_disconnected();
+        mqtt.on_connected();
+        run(&mqtt, &mut broker);
+        let topics: Vec<&str> = broker.published.iter().map(|m| m.topic.as_str()).collect();
+        assert_eq!(topics, ["board/status", "board/n", "board/status"]);
+
+        // reconnects while offline queue a single birth, within queue_len
+        let mqtt = Mqtt::new(MqttConfig {
+            queue_len: 2,
+            policy: QueuePolicy::DropNewest,
+            ..mqtt.0.config.clone()
+        });
+        mqtt.publish("board/n", QoS::AtMostOnce, false, "1")
+            .unwrap();
+        mqtt.publish("board/n", QoS::AtMostOnce, false, "2")
+            .unwrap();
+        for _ in 0..3 {
+            mqtt.on_connected();
+            mqtt.on_disconnected();
+        }
+        assert_eq!(mqtt.status().queued, 2);
+        assert_eq!(mqtt.status().dropped, 1);
+        mqtt.on_connected();
+        let mut broker = FakeBroker {
+            online: true,
+            ..Default::default()
+        };
+        run(&mqtt, &mut broker);
+        let payloads: Vec<&[u8]> = broker.published.iter().map(|m| &m.payload[..]).collect();
+        assert_eq!(payloads, [&b"online"[..], b"1"]);
+    }
+
+    #[test]
+    fn queue_policy_test() {
+        let publish = |mqtt: &Mqtt, i: u8| mqtt.publish("board/n", QoS::AtMostOnce, false, [i]);
+
+        let mqtt = Mqtt::new(MqttConfig {
+            queue_len: 2,
+            policy: QueuePolicy::DropOldest,
+            ..MqttConfig::default()
+        });
+        for i in 0..4 {
+            publish(&mqtt, i).unwrap();
+        }
+        let mut broker = FakeBroker {
+            online: true,
+            ..Default::default()
+        };
+        mqtt.on_connected();
+        run(&mqtt, &mut broker);
+        let payloads: Vec<_> = broker.published.iter().map(|m| m.payload[0]).collect();
+        assert_eq!(payloads, vec![2, 3]);
+        assert_eq!(mqtt.status().dropped, 2);
+
+        let mqtt = Mqtt::new(MqttConfig {
+            queue_len: 2,
+            policy: QueuePolicy::DropNewest,
+            ..MqttConfig::default()
+        });
+        publish(&mqtt, 0).unwrap();
+        publish(&mqtt, 1).unwrap();
+        assert!(matches!(publish(&mqtt, 2), Err(MqttError::QueueFull)));
+        let mut broker = FakeBroker {
+            online: true,
+            ..Default::default()
+        };
+        mqtt.on_connected();
+        run(&mqtt, &mut broker);
+        let payloads: Vec<_> = broker.published.iter().map(|m| m.payload[0]).collect();
+        assert_eq!(payloads, vec![0, 1]);
+    }
+
+    #[test]
+    fn topic_test() {
+        assert!(check_topic("board/temperature").is_ok());
+        assert!(check_topic("").is_err());
+        assert!(check_topic("board/+").is_err());
+        assert!(check_filter("board/#").is_ok());
+        assert!(check_filter("+/+/set").is_ok());
+        assert!(check_filter("#").is_ok());
+        assert!(check_filter("board/#/set").is_err());
+        assert!(check_filter("board/se+").is_err());
+
+        assert!(topic_matches("board/#", "board/servo/0"));
+        assert!(topic_matches("board/#", "board"));
+        assert!(topic_matches("board/+/set", "board/servo/set"));
+        assert!(!topic_matches("board/+/set", "board/servo/0/set"));
+        assert!(!topic_matches("board/+", "board/servo/0"));
+        assert!(topic_matches("board/servo", "board/servo"));
+        assert!(!topic_matches("board/servo", "board/servo/0"));
+        assert!(!topic_matches("#", "$SYS/uptime"));
+        assert!(topic_matches("$SYS/#", "$SYS/uptime"));
+    }
+}
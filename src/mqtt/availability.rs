@@ -0,0 +1,130 @@
+//! Online status and heartbeat of the board.
+//!
+//! * Last Will - the broker publishes `offline`, retained, to the status topic when the
+//!   board goes away without a DISCONNECT, see [`configure`]
+//! * birth - `online`, retained, is the first publish after each connect, see [`birth`]
+//! * heartbeat - uptime, RSSI, free heap and firmware version, see [`spawn_heartbeat`]
+//!
+//! `offline` is a dead board or network, `online` with an old heartbeat a stuck one.
+//! The status topic is [`DeviceInfo::availability_topic`], the one Home Assistant watches.
+
+use crate::mqtt::discovery::DeviceInfo;
+use crate::mqtt::{Message, Mqtt};
+use crate::wifi::WifiHandle;
+use embedded_svc::mqtt::client::QoS;
+use esp_idf_svc::mqtt::client::{LwtConfiguration, MqttClientConfiguration};
+use esp_idf_svc::sys;
+use serde::Serialize;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+pub const ONLINE: &str = "online";
+pub const OFFLINE: &str = "offline";
+/// Formatting the JSON and logging.
+const STACK_SIZE: usize = 4096;
+
+/// Registers the Last Will on `status_topic`, `conf` is made by
+/// [`super::broker::BrokerConfig::client_configuration`].
+pub fn configure<'a>(status_topic: &'a str, conf: &mut MqttClientConfiguration<'a>) {
+    conf.lwt = Some(LwtConfiguration {
+        topic: status_topic,
+        payload: OFFLINE.as_bytes(),
+        qos: QoS::AtLeastOnce,
+        retain: true,
+    });
+}
+
+/// Overwrites the retained Last Will, for [`super::MqttConfig::birth`].
+pub fn birth(status_topic: &str) -> Message {
+    Message {
+        topic: status_topic.to_string(),
+        payload: ONLINE.into(),
+        qos: QoS::AtLeastOnce,
+        retain: true,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Heartbeat {
+    pub uptime_secs: u64,
+    /// dBm, `None` while the wifi is down.
+    pub rssi: Option<i8>,
+    /// Bytes.
+    pub free_heap: u32,
+    /// Lowest `free_heap` since boot, a leak keeps lowering it.
+    pub min_free_heap: u32,
+    pub version: String,
+    pub wifi_disconnects: u32,
+    pub mqtt_disconnects: u32,
+}
+
+impl Heartbeat {
+    pub fn collect(device: &DeviceInfo, wifi: &WifiHandle, mqtt: &Mqtt) -> Self {
+        let wifi = wifi.status();
+        let uptime = Duration::from_micros(unsafe { sys::esp_timer_get_time() } as u64);
+        Heartbeat {
+            uptime_secs: uptime.as_secs(),
+            rssi: wifi.rssi,
+            free_heap: unsafe { sys::esp_get_free_heap_size() },
+            min_free_heap: unsafe { sys::esp_get_minimum_free_heap_size() },
+            version: device.sw_version.clone(),
+            wifi_disconnects: wifi.disconnects,
+            mqtt_disconnects: mqtt.status().disconnects,
+        }
+    }
+}
+
+/// Publishes a [`Heartbeat`] to [`DeviceInfo::heartbeat_topic`] every `period`. Not
+/// retained and skipped while offline, an old heartbeat must not look fresh.
+pub fn spawn_heartbeat(
+    mqtt: &Mqtt,
+    wifi: &WifiHandle,
+    device: &DeviceInfo,
+    period: Duration,
+) -> std::io::Result<JoinHandle<()>> {
+    let (mqtt, wifi, device) = (mqtt.clone(), wifi.clone(), device.clone());
+    let topic = device.heartbeat_topic();
+    std::thread::Builder::new()
+        .name("heartbeat".to_string())
+        .stack_size(STACK_SIZE)
+        .spawn(move || loop {
+            if mqtt.is_connected() {
+                let heartbeat = Heartbeat::collect(&device, &wifi, &mqtt);
+                if let Err(err) = mqtt.publish_json(&topic, QoS::AtMostOnce, false, &heartbeat) {
+                    log::warn!("Heartbeat: {err}");
+                }
+            }
+            std::thread::sleep(period);
+        })
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::mqtt::availability::{birth, configure, Heartbeat};
+    use crate::mqtt::broker::BrokerConfig;
+    use crate::mqtt::discovery::DeviceInfo;
+    use crate::mqtt::{Mqtt, MqttConfig};
+    use crate::wifi::WifiHandle;
+
+    #[test]
+    fn last_will_test() {
+        let device = DeviceInfo::new([0x58, 0xcf, 0x79, 0x01, 0x02, 0x03], "Workbench");
+        let topic = device.availability_topic();
+        let broker = BrokerConfig::new("broker.lan");
+        let mut conf = broker.client_configuration();
+        configure(&topic, &mut conf);
+        let lwt = conf.lwt.unwrap();
+        assert_eq!(lwt.topic, "esp32c3/esp32c3_58cf79010203/status");
+        assert_eq!(lwt.payload, b"offline");
+        assert!(lwt.retain);
+
+        let online = birth(&topic);
+        assert_eq!(online.payload, b"online");
+        assert!(online.retain);
+
+        let mqtt = Mqtt::new(MqttConfig::default());
+        let heartbeat = Heartbeat::collect(&device, &WifiHandle::default(), &mqtt);
+        assert_eq!(heartbeat.rssi, None);
+        assert_eq!(heartbeat.version, env!("CARGO_PKG_VERSION"));
+    }
+}
@@ -0,0 +1,297 @@
+//! Broker address and session settings, checked before the client is started.
+//!
+//! The fields match the `mqtt_*` keys of `cfg.toml.example`, [`BrokerConfig::url`]
+//! builds the URL ESP-IDF expects, e.g. `mqtts://broker.lan:8883`.
+
+use esp_idf_svc::mqtt::client::MqttClientConfiguration;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Brokers must accept 23 characters, most take more, longer ids are likely a mistake.
+pub const MAX_CLIENT_ID_LEN: usize = 64;
+/// Keepalive is 16 bit seconds in CONNECT.
+pub const MAX_KEEPALIVE: Duration = Duration::from_secs(u16::MAX as u64);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Scheme {
+    #[default]
+    Mqtt,
+    /// MQTT over TLS.
+    Mqtts,
+    /// MQTT over WebSocket.
+    Ws,
+    /// MQTT over WebSocket over TLS.
+    Wss,
+}
+
+impl Scheme {
+    pub fn default_port(self) -> u16 {
+        match self {
+            Scheme::Mqtt => 1883,
+            Scheme::Mqtts => 8883,
+            Scheme::Ws => 80,
+            Scheme::Wss => 443,
+        }
+    }
+
+    pub fn is_tls(self) -> bool {
+        matches!(self, Scheme::Mqtts | Scheme::Wss)
+    }
+
+    pub fn is_websocket(self) -> bool {
+        matches!(self, Scheme::Ws | Scheme::Wss)
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scheme::Mqtt => "mqtt",
+            Scheme::Mqtts => "mqtts",
+            Scheme::Ws => "ws",
+            Scheme::Wss => "wss",
+        }
+    }
+}
+
+impl FromStr for Scheme {
+    type Err = BrokerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "mqtt" | "tcp" => Ok(Scheme::Mqtt),
+            "mqtts" | "ssl" => Ok(Scheme::Mqtts),
+            "ws" => Ok(Scheme::Ws),
+            "wss" => Ok(Scheme::Wss),
+            _ => Err(BrokerError::new(
+                "mqtt_scheme",
+                "should be mqtt, mqtts, ws or wss",
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Scheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Names the `cfg.toml` key and what is wrong with it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BrokerError {
+    pub key: &'static str,
+    pub reason: &'static str,
+}
+
+impl BrokerError {
+    fn new(key: &'static str, reason: &'static str) -> Self {
+        BrokerError { key, reason }
+    }
+}
+
+impl fmt::Display for BrokerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.key, self.reason)
+    }
+}
+
+impl std::error::Error for BrokerError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BrokerConfig {
+    pub scheme: Scheme,
+    /// Name or IP, IPv6 in brackets, without scheme or port.
+    pub host: String,
+    /// `None` for the default port of the scheme.
+    pub port: Option<u16>,
+    /// Path of the WebSocket endpoint, e.g. `/mqtt`, not used by the other schemes.
+    pub path: String,
+    /// Empty to let the broker assign one, only with a clean session.
+    pub client_id: String,
+    /// Zero disables pings.
+    pub keepalive: Duration,
+    /// `false` keeps subscriptions and QoS 1/2 messages while offline.
+    pub clean_session: bool,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl BrokerConfig {
+    pub fn new(host: &str) -> Self {
+        BrokerConfig {
+            scheme: Scheme::Mqtt,
+            host: host.to_string(),
+            port: None,
+            path: "/mqtt".to_string(),
+            client_id: String::new(),
+            keepalive: Duration::from_secs(60),
+            clean_session: true,
+            username: None,
+            password: None,
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port.unwrap_or(self.scheme.default_port())
+    }
+
+    /// Everything ESP-IDF would only report as a failed connection.
+    pub fn validate(&self) -> Result<(), BrokerError> {
+        check_host(&self.host)?;
+        if self.port == Some(0) {
+            return Err(BrokerError::new("mqtt_port", "should be 1-65535"));
+        }
+        if self.scheme.is_websocket() && !self.path.starts_with('/') {
+            return Err(BrokerError::new("mqtt_path", "should start with /"));
+        }
+        if self.client_id.len() > MAX_CLIENT_ID_LEN {
+            return Err(BrokerError::new("mqtt_client_id", "is too long"));
+        }
+        if self
+            .client_id
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control())
+        {
+            return Err(BrokerError::new(
+                "mqtt_client_id",
+                "should not contain spaces or control characters",
+            ));
+        }
+        if self.client_id.is_empty() && !self.clean_session {
+            return Err(BrokerError::new(
+                "mqtt_client_id",
+                "is required without a clean session",
+            ));
+        }
+        if self.keepalive > MAX_KEEPALIVE {
+            return Err(BrokerError::new(
+                "mqtt_keepalive_secs",
+                "should be at most 65535",
+            ));
+        }
+        if self.password.is_some() && self.username.is_none() {
+            return Err(BrokerError::new(
+                "mqtt_password",
+                "needs mqtt_user, MQTT 3.1.1 has no password alone",
+            ));
+        }
+        Ok(())
+    }
+
+    /// E.g. `mqtt://192.168.1.10:1883` or `ws://broker.lan:80/mqtt`.
+    pub fn url(&self) -> String {
+        let path = if self.scheme.is_websocket() {
+            self.path.as_str()
+        } else {
+            ""
+        };
+        format!("{}://{}:{}{path}", self.scheme, self.host, self.port())
+    }
+
+    /// Session and credentials, the rest of the configuration is left default.
+    pub fn client_configuration(&self) -> MqttClientConfiguration<'_> {
+        MqttClientConfiguration {
+            client_id: Some(self.client_id.as_str()).filter(|id| !id.is_empty()),
+            keep_alive_interval: Some(self.keepalive).filter(|keepalive| !keepalive.is_zero()),
+            disable_clean_session: !self.clean_session,
+            username: self.username.as_deref(),
+            password: self.password.as_deref(),
+            ..Default::default()
+        }
+    }
+}
+
+fn check_host(host: &str) -> Result<(), BrokerError> {
+    if host.is_empty() {
+        return Err(BrokerError::new("mqtt_host", "should not be empty"));
+    }
+    if host.contains("://") {
+        return Err(BrokerError::new(
+            "mqtt_host",
+            "should be without scheme, see mqtt_scheme",
+        ));
+    }
+    if let Some(ipv6) = host.strip_prefix('[') {
+        return match ipv6.strip_suffix(']') {
+            Some(ip) if ip.parse::<std::net::Ipv6Addr>().is_ok() => Ok(()),
+            _ => Err(BrokerError::new("mqtt_host", "is not a valid [IPv6]")),
+        };
+    }
+    if host.contains(':') {
+        return Err(BrokerError::new(
+            "mqtt_host",
+            "should be without port, see mqtt_port",
+        ));
+    }
+    let valid = host.split('.').all(|label| {
+        !label.is_empty() && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    });
+    if !valid || host.len() > 253 {
+        return Err(BrokerError::new("mqtt_host", "is not a valid name or IP"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::mqtt::broker::{BrokerConfig, BrokerError, Scheme};
+    use std::time::Duration;
+
+    #[test]
+    fn url_test() {
+        let mut config = BrokerConfig::new("192.168.1.10");
+        assert_eq!(config.url(), "mqtt://192.168.1.10:1883");
+        config.scheme = "mqtts".parse().unwrap();
+        assert_eq!(config.url(), "mqtts://192.168.1.10:8883");
+        config.port = Some(8884);
+        assert_eq!(config.url(), "mqtts://192.168.1.10:8884");
+        config.scheme = Scheme::Ws;
+        config.port = None;
+        assert_eq!(config.url(), "ws://192.168.1.10:80/mqtt");
+        config.host = "[fe80::1]".to_string();
+        assert!(config.validate().is_ok());
+        assert!("http".parse::<Scheme>().is_err());
+
+        config.username = Some("board".to_string());
+        config.keepalive = Duration::ZERO;
+        let conf = config.client_configuration();
+        assert_eq!(conf.client_id, None);
+        assert_eq!(conf.keep_alive_interval, None);
+        assert_eq!(conf.username, Some("board"));
+    }
+
+    #[test]
+    fn validate_test() {
+        let check = |f: fn(&mut BrokerConfig)| {
+            let mut config = BrokerConfig::new("broker.lan");
+            f(&mut config);
+            config.validate().map_err(|BrokerError { key, .. }| key)
+        };
+        assert_eq!(check(|_| {}), Ok(()));
+        assert_eq!(check(|c| c.host.clear()), Err("mqtt_host"));
+        assert_eq!(
+            check(|c| c.host = "mqtt://broker.lan".to_string()),
+            Err("mqtt_host")
+        );
+        assert_eq!(
+            check(|c| c.host = "broker.lan:1883".to_string()),
+            Err("mqtt_host")
+        );
+        assert_eq!(check(|c| c.host = "HOST".to_string()), Ok(()));
+        assert_eq!(check(|c| c.host = "bro ker".to_string()), Err("mqtt_host"));
+        assert_eq!(check(|c| c.port = Some(0)), Err("mqtt_port"));
+        assert_eq!(check(|c| c.clean_session = false), Err("mqtt_client_id"));
+        assert_eq!(
+            check(|c| c.client_id = "esp32 c3".to_string()),
+            Err("mqtt_client_id")
+        );
+        assert_eq!(
+            check(|c| c.keepalive = Duration::from_secs(100_000)),
+            Err("mqtt_keepalive_secs")
+        );
+        assert_eq!(
+            check(|c| c.password = Some("secret".to_string())),
+            Err("mqtt_password")
+        );
+    }
+}
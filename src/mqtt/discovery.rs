@@ -0,0 +1,429 @@
+//! Home Assistant MQTT discovery of the board devices.
+//!
+//! Each [`Entity`] gets a retained config at
+//! `homeassistant/<component>/<device_id>/<object_id>/config`, Home Assistant creates the
+//! entity from it and groups all of them under one device. The device id and unique ids
+//! come from the station MAC, so they survive reflashing and renames.
+//!
+//! States and commands live under [`DeviceInfo::base_topic`], e.g. `esp32c3/<id>/servo/0`
+//! and `esp32c3/<id>/servo/0/set`. Entities are unavailable unless `online` is retained at
+//! [`DeviceInfo::availability_topic`].
+
+use crate::lock_recover;
+use crate::mqtt::availability::ONLINE;
+use crate::mqtt::{Mqtt, MqttError};
+use embedded_svc::mqtt::client::QoS;
+use esp_idf_svc::sys::{self, EspError};
+use serde::Serialize;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+pub const DISCOVERY_PREFIX: &str = "homeassistant";
+/// Home Assistant publishes `online` here after its restart, configs are sent again.
+pub const HA_STATUS_TOPIC: &str = "homeassistant/status";
+/// Payloads of [`Entity::Led`] and [`Entity::JoystickButton`].
+pub const ON: &str = "ON";
+pub const OFF: &str = "OFF";
+/// Relative steps of [`Entity::StepperMove`], same limit as the REST API.
+const MAX_MOVE_STEPS: f64 = crate::rest_api::MAX_MOVE_STEPS as f64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceInfo {
+    /// `esp32c3_` and the MAC hex, unique ids of the entities start with it.
+    pub id: String,
+    pub mac: [u8; 6],
+    /// Shown in Home Assistant, can be changed there.
+    pub name: String,
+    pub model: String,
+    pub sw_version: String,
+    /// Parent topic of states, commands and availability.
+    pub base_topic: String,
+}
+
+impl DeviceInfo {
+    pub fn new(mac: [u8; 6], name: &str) -> Self {
+        let hex: String = mac.iter().map(|b| format!("{b:02x}")).collect();
+        let id = format!("esp32c3_{hex}");
+        DeviceInfo {
+            base_topic: format!("esp32c3/{id}"),
+            id,
+            mac,
+            name: name.to_string(),
+            model: "ESP32-C3".to_string(),
+            sw_version: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+
+    /// `online` or `offline`, retained, see [`super::availability`].
+    pub fn availability_topic(&self) -> String {
+        format!("{}/status", self.base_topic)
+    }
+
+    pub fn heartbeat_topic(&self) -> String {
+        format!("{}/heartbeat", self.base_topic)
+    }
+
+    fn mac_string(&self) -> String {
+        let bytes: Vec<String> = self.mac.iter().map(|b| format!("{b:02x}")).collect();
+        bytes.join(":")
+    }
+}
+
+/// MAC of the WiFi station, burned into eFuse.
+pub fn station_mac() -> Result<[u8; 6], EspError> {
+    let mut mac = [0u8; 6];
+    EspError::convert(unsafe {
+        sys::esp_read_mac(mac.as_mut_ptr(), sys::esp_mac_type_t_ESP_MAC_WIFI_STA)
+    })?;
+    Ok(mac)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Entity {
+    /// DS18B20, `address` as printed by `temp_sensor`, e.g. `28FF641E0B16036F`.
+    Temperature {
+        address: String,
+    },
+    /// Angle in degrees.
+    Servo {
+        id: usize,
+        max_angle: f64,
+    },
+    /// Relative move, the state is the position in steps.
+    StepperMove {
+        id: usize,
+    },
+    StepperStop {
+        id: usize,
+    },
+    /// On/off LED.
+    Led {
+        id: usize,
+    },
+    JoystickButton,
+}
+
+impl Entity {
+    pub fn component(&self) -> &'static str {
+        match self {
+            Entity::Temperature { .. } => "sensor",
+            Entity::Servo { .. } | Entity::StepperMove { .. } => "number",
+            Entity::StepperStop { .. } => "button",
+            Entity::Led { .. } => "light",
+            Entity::JoystickButton => "binary_sensor",
+        }
+    }
+
+    /// Unique within the device.
+    pub fn object_id(&self) -> String {
+        match self {
+            Entity::Temperature { address } => {
+                format!("temperature_{}", address.to_ascii_lowercase())
+            }
+            Entity::Servo { id, .. } => format!("servo_{id}"),
+            Entity::StepperMove { id } => format!("stepper_{id}_move"),
+            Entity::StepperStop { id } => format!("stepper_{id}_stop"),
+            Entity::Led { id } => format!("led_{id}"),
+            Entity::JoystickButton => "joystick_button".to_string(),
+        }
+    }
+
+    /// Where the board publishes the state, `None` for buttons.
+    pub fn state_topic(&self, device: &DeviceInfo) -> Option<String> {
+        let base = &device.base_topic;
+        match self {
+            Entity::Temperature { address } => Some(format!("{base}/temperature/{address}")),
+            Entity::Servo { id, .. } => Some(format!("{base}/servo/{id}")),
+            Entity::StepperMove { id } => Some(format!("{base}/stepper/{id}")),
+            Entity::StepperStop { .. } => None,
+            Entity::Led { id } => Some(format!("{base}/led/{id}")),
+            Entity::JoystickButton => Some(format!("{base}/joystick/button")),
+        }
+    }
+
+    /// Where Home Assistant sends commands, `None` for sensors.
+    pub fn command_topic(&self, device: &DeviceInfo) -> Option<String> {
+        let base = &device.base_topic;
+        match self {
+            Entity::Temperature { .. } | Entity::JoystickButton => None,
+            Entity::Servo { id, .. } => Some(format!("{base}/servo/{id}/set")),
+            Entity::StepperMove { id } => Some(format!("{base}/stepper/{id}/move")),
+            Entity::StepperStop { id } => Some(format!("{base}/stepper/{id}/stop")),
+            Entity::Led { id } => Some(format!("{base}/led/{id}/set")),
+        }
+    }
+
+    pub fn config_topic(&self, device: &DeviceInfo) -> String {
+        format!(
+            "{DISCOVERY_PREFIX}/{}/{}/{}/config",
+            self.component(),
+            device.id,
+            self.object_id()
+        )
+    }
+
+    pub fn config(&self, device: &DeviceInfo) -> EntityConfig {
+        let mut config = EntityConfig {
+            name: String::new(),
+            unique_id: format!("{}_{}", device.id, self.object_id()),
+            availability_topic: device.availability_topic(),
+            state_topic: self.state_topic(device),
+            command_topic: self.command_topic(device),
+            device_class: None,
+            state_class: None,
+            unit_of_measurement: None,
+            min: None,
+            max: None,
+            step: None,
+            mode: None,
+            payload_on: None,
+            payload_off: None,
+            device: Device {
+                identifiers: vec![device.id.clone()],
+                connections: vec![("mac", device.mac_string())],
+                name: device.name.clone(),
+                model: device.model.clone(),
+                manufacturer: "Espressif",
+                sw_version: device.sw_version.clone(),
+            },
+        };
+        match self {
+            Entity::Temperature { address } => {
+                config.name = format!("Temperature {address}");
+                config.device_class = Some("temperature");
+                config.state_class = Some("measurement");
+                config.unit_of_measurement = Some("°C");
+            }
+            Entity::Servo { id, max_angle } => {
+                config.name = format!("Servo {id}");
+                config.unit_of_measurement = Some("°");
+                config.min = Some(0.0);
+                config.max = Some(*max_angle);
+                config.step = Some(1.0);
+                config.mode = Some("slider");
+            }
+            Entity::StepperMove { id } => {
+                config.name = format!("Stepper {id} move");
+                config.unit_of_measurement = Some("steps");
+                config.min = Some(-MAX_MOVE_STEPS);
+                config.max = Some(MAX_MOVE_STEPS);
+                config.step = Some(1.0);
+                config.mode = Some("box");
+            }
+            Entity::StepperStop { id } => config.name = format!("Stepper {id} stop"),
+            Entity::Led { id } => config.name = format!("LED {id}"),
+            Entity::JoystickButton => {
+                config.name = "Joystick button".to_string();
+                config.payload_on = Some(ON);
+                config.payload_off = Some(OFF);
+            }
+        }
+        config
+    }
+}
+
+/// Discovery payload, only the keys the entities use.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct EntityConfig {
+    pub name: String,
+    pub unique_id: String,
+    pub availability_topic: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_class: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_class: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unit_of_measurement: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub step: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mode: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload_on: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload_off: Option<&'static str>,
+    pub device: Device,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Device {
+    pub identifiers: Vec<String>,
+    /// `[["mac", "aa:bb:.."]]`, links the device to the WiFi integrations.
+    pub connections: Vec<(&'static str, String)>,
+    pub name: String,
+    pub model: String,
+    pub manufacturer: &'static str,
+    pub sw_version: String,
+}
+
+/// Cheap to clone handle to the announced entities.
+#[derive(Clone)]
+pub struct Discovery(Arc<DiscoveryShared>);
+
+struct DiscoveryShared {
+    mqtt: Mqtt,
+    device: DeviceInfo,
+    entities: Mutex<Vec<Entity>>,
+}
+
+impl Discovery {
+    /// Announces the entities again whenever Home Assistant comes online.
+    pub fn start(mqtt: &Mqtt, device: DeviceInfo) -> Result<Self, MqttError> {
+        let discovery = Discovery(Arc::new(DiscoveryShared {
+            mqtt: mqtt.clone(),
+            device,
+            entities: Mutex::new(Vec::new()),
+        }));
+        let restarted = discovery.clone();
+        mqtt.subscribe(HA_STATUS_TOPIC, QoS::AtLeastOnce, move |_, payload| {
+            if payload == ONLINE.as_bytes() {
+                log::info!("Discovery: Home Assistant is online");
+                if let Err(err) = restarted.announce() {
+                    log::warn!("Discovery: {err}");
+                }
+            }
+        })?;
+        Ok(discovery)
+    }
+
+    pub fn device(&self) -> &DeviceInfo {
+        &self.0.device
+    }
+
+    /// Announces `entities`, the previous ones which aren't there are removed from
+    /// Home Assistant, e.g. an unplugged sensor.
+    pub fn set_entities(&self, entities: Vec<Entity>) -> Result<(), MqttError> {
+        let removed: Vec<Entity> = {
+            let mut current = self.lock();
+            let removed = current
+                .iter()
+                .filter(|entity| !entities.contains(entity))
+                .cloned()
+                .collect();
+            *current = entities;
+            removed
+        };
+        for entity in removed {
+            // an empty retained config deletes the entity
+            let topic = entity.config_topic(&self.0.device);
+            self.0
+                .mqtt
+                .publish(&topic, QoS::AtLeastOnce, true, Vec::new())?;
+        }
+        self.announce()
+    }
+
+    fn announce(&self) -> Result<(), MqttError> {
+        let entities = self.lock().clone();
+        for entity in &entities {
+            self.0.mqtt.publish_json(
+                &entity.config_topic(&self.0.device),
+                QoS::AtLeastOnce,
+                true,
+                &entity.config(&self.0.device),
+            )?;
+        }
+        log::info!("Discovery: {} entities announced", entities.len());
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<Entity>> {
+        lock_recover(&self.0.entities)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::mqtt::discovery::{DeviceInfo, Discovery, Entity};
+    use crate::mqtt::{Mqtt, MqttConfig};
+    use serde_json::json;
+
+    fn device() -> DeviceInfo {
+        DeviceInfo::new([0x58, 0xcf, 0x79, 0x01, 0x02, 0x03], "Workbench")
+    }
+
+    #[test]
+    fn config_test() {
+        let device = device();
+        assert_eq!(device.id, "esp32c3_58cf79010203");
+        let sensor = Entity::Temperature {
+            address: "28FF641E0B16036F".to_string(),
+        };
+        assert_eq!(
+            sensor.config_topic(&device),
+            "homeassistant/sensor/esp32c3_58cf79010203/temperature_28ff641e0b16036f/config"
+        );
+        let config = serde_json::to_value(sensor.config(&device)).unwrap();
+        assert_eq!(
+            config["unique_id"],
+            "esp32c3_58cf79010203_temperature_28ff641e0b16036f"
+        );
+        assert_eq!(config["device_class"], "temperature");
+        assert_eq!(
+            config["state_topic"],
+            "esp32c3/esp32c3_58cf79010203/temperature/28FF641E0B16036F"
+        );
+        assert_eq!(
+            config["availability_topic"],
+            "esp32c3/esp32c3_58cf79010203/status"
+        );
+        assert_eq!(
+            config["device"]["connections"],
+            json!([["mac", "58:cf:79:01:02:03"]])
+        );
+        assert!(config.get("command_topic").is_none());
+
+        let servo = Entity::Servo {
+            id: 1,
+            max_angle: 180.0,
+        };
+        let config = serde_json::to_value(servo.config(&device)).unwrap();
+        assert_eq!(servo.component(), "number");
+        assert_eq!(
+            config["command_topic"],
+            "esp32c3/esp32c3_58cf79010203/servo/1/set"
+        );
+        assert_eq!(config["max"], 180.0);
+
+        let stop = Entity::StepperStop { id: 0 };
+        assert_eq!(stop.component(), "button");
+        assert!(stop.config(&device).state_topic.is_none());
+        assert_eq!(Entity::Led { id: 0 }.component(), "light");
+        assert_eq!(Entity::JoystickButton.component(), "binary_sensor");
+    }
+
+    #[test]
+    fn set_entities_test() {
+        let mqtt = Mqtt::new(MqttConfig::default());
+        let discovery = Discovery::start(&mqtt, device()).unwrap();
+        let first = Entity::Temperature {
+            address: "28FF641E0B16036F".to_string(),
+        };
+        let second = Entity::Temperature {
+            address: "28AA641E0B16036F".to_string(),
+        };
+        discovery
+            .set_entities(vec![first.clone(), second.clone()])
+            .unwrap();
+        discovery.set_entities(vec![first]).unwrap();
+
+        let queue = &mqtt.lock().queue;
+        let topics: Vec<(&str, usize)> = queue
+            .iter()
+            .map(|m| (m.topic.as_str(), m.payload.len()))
+            .collect();
+        assert_eq!(topics.len(), 4);
+        assert!(queue.iter().all(|m| m.retain));
+        // the unplugged sensor is deleted by an empty config
+        assert_eq!(topics[2].0, second.config_topic(discovery.device()));
+        assert_eq!(topics[2].1, 0);
+        assert_ne!(topics[3].1, 0);
+    }
+}
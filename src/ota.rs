@@ -0,0 +1,821 @@
+//! Firmware updates over the air, written into the inactive slot of `partitions.csv`.
+//!
+//! * [`update_from_manifest`] pulls `{"version", "url", "size", "sha256"}` JSON, then the image
+//!   of a newer version
+//! * [`serve_upload`] takes the image as the body of `POST`, see the `X-Firmware-*` headers
+//! * both check the image with [`ImageVerifier`]: magic, project, version, size and SHA-256
+//!
+//! The new firmware boots unverified, [`confirm`] marks it valid after a health check or
+//! rolls back to the previous one (`CONFIG_BOOTLOADER_APP_ROLLBACK_ENABLE`).
+//! Images are made by `espflash save-image --chip esp32c3 <elf> firmware.bin`.
+//!
+//! Versions and projects are read from the app descriptor. The firmware must invoke
+//! `esp_idf_svc::sys::esp_app_desc!();` to put `CARGO_PKG_VERSION` and `CARGO_PKG_NAME`
+//! there, the ESP-IDF default has its own `PROJECT_VER` and `libespidf` for every image.
+
+use crate::http_client::tls::TlsErrors;
+use crate::http_client::HttpClient;
+use crate::lock_recover;
+use embedded_svc::http::client::Connection;
+use embedded_svc::http::{Headers, Method};
+use embedded_svc::io::Write;
+use esp_idf_svc::http::server::EspHttpServer;
+use esp_idf_svc::ota::{EspOta, EspOtaUpdate, SlotState};
+use esp_idf_svc::sys::{self, EspError};
+use serde::Deserialize;
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::ControlFlow;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Image header, segment header and `esp_app_desc_t` up to the project name.
+const HEADER_LEN: usize = 112;
+const IMAGE_MAGIC: u8 = 0xE9;
+const APP_DESC_MAGIC: u32 = 0xABCD_5432;
+const CHUNK_LEN: usize = 1024;
+/// The upload response goes out before the reboot.
+const REBOOT_DELAY: Duration = Duration::from_secs(1);
+
+/// Update server's description of the latest firmware.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Manifest {
+    pub version: String,
+    /// Image URL, relative ones are resolved against the manifest URL.
+    pub url: String,
+    pub size: usize,
+    /// Hex of the image SHA-256, `sha256sum firmware.bin`.
+    pub sha256: String,
+}
+
+/// What the image must be.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expected {
+    pub size: usize,
+    pub sha256: [u8; 32],
+    /// Version of the image app descriptor, `CARGO_PKG_VERSION` with `esp_app_desc!`.
+    pub version: Option<String>,
+    /// The running firmware's project, images of other projects are rejected.
+    pub project: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum OtaError {
+    /// Malformed manifest, headers or image.
+    Invalid(&'static str),
+    TooLarge {
+        size: usize,
+        capacity: usize,
+    },
+    SizeMismatch {
+        expected: usize,
+        actual: usize,
+    },
+    VersionMismatch {
+        expected: String,
+        actual: String,
+    },
+    ProjectMismatch {
+        expected: String,
+        actual: String,
+    },
+    /// The manifest version is older than the running one.
+    Downgrade {
+        running: String,
+        offered: String,
+    },
+    ChecksumMismatch,
+    Download(String),
+    Flash(EspError),
+}
+
+impl OtaError {
+    /// HTTP status of the upload response.
+    pub fn status(&self) -> u16 {
+        match self {
+            OtaError::Invalid(_) => 400,
+            OtaError::TooLarge { .. } => 413,
+            OtaError::SizeMismatch { .. }
+            | OtaError::VersionMismatch { .. }
+            | OtaError::ProjectMismatch { .. }
+            | OtaError::Downgrade { .. }
+            | OtaError::ChecksumMismatch => 422,
+            OtaError::Download(_) => 502,
+            OtaError::Flash(_) => 500,
+        }
+    }
+}
+
+impl fmt::Display for OtaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OtaError::Invalid(err) => f.write_str(err),
+            OtaError::TooLarge { size, capacity } => {
+                write!(
+                    f,
+                    "image of {size} bytes doesn't fit the {capacity} bytes slot"
+                )
+            }
+            OtaError::SizeMismatch { expected, actual } => {
+                write!(f, "image has {actual} bytes, expected {expected}")
+            }
+            OtaError::VersionMismatch { expected, actual } => {
+                write!(f, "image version is {actual}, expected {expected}")
+            }
+            OtaError::ProjectMismatch { expected, actual } => {
+                write!(f, "image is of {actual}, expected {expected}")
+            }
+            OtaError::Downgrade { running, offered } => {
+                write!(f, "version {offered} is older than the running {running}")
+            }
+            OtaError::ChecksumMismatch => f.write_str("image SHA-256 mismatch"),
+            OtaError::Download(err) => write!(f, "download failed: {err}"),
+            OtaError::Flash(err) => write!(f, "flash failed: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for OtaError {}
+
+impl From<EspError> for OtaError {
+    fn from(err: EspError) -> Self {
+        OtaError::Flash(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    pub written: usize,
+    pub total: usize,
+}
+
+impl Progress {
+    pub fn percent(&self) -> u8 {
+        (self.written * 100 / self.total.max(1)).min(100) as u8
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// The running firmware has the manifest version.
+    UpToDate,
+    /// Written and set to boot, reboot to run it.
+    Installed { version: String },
+}
+
+/// Checks the image as it streams in, see [`Expected`].
+pub struct ImageVerifier {
+    expected: Expected,
+    header: Vec<u8>,
+    received: usize,
+    sha256: Sha256,
+}
+
+impl ImageVerifier {
+    /// `capacity` is the size of the update slot.
+    pub fn new(expected: Expected, capacity: usize) -> Result<Self, OtaError> {
+        if expected.size > capacity {
+            return Err(OtaError::TooLarge {
+                size: expected.size,
+                capacity,
+            });
+        }
+        if expected.size < HEADER_LEN {
+            return Err(OtaError::Invalid("image is too small"));
+        }
+        Ok(ImageVerifier {
+            expected,
+            header: Vec::with_capacity(HEADER_LEN),
+            received: 0,
+            sha256: Sha256::new()?,
+        })
+    }
+
+    /// Fails as soon as the image can't be the expected one.
+    pub fn update(&mut self, chunk: &[u8]) -> Result<(), OtaError> {
+        self.received += chunk.len();
+        if self.received > self.expected.size {
+            return Err(OtaError::SizeMismatch {
+                expected: self.expected.size,
+                actual: self.received,
+            });
+        }
+        if self.header.len() < HEADER_LEN {
+            let missing = (HEADER_LEN - self.header.len()).min(chunk.len());
+            self.header.extend_from_slice(&chunk[..missing]);
+            if self.header.len() == HEADER_LEN {
+                self.check_header()?;
+            }
+        }
+        self.sha256.update(chunk)?;
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<(), OtaError> {
+        if self.received != self.expected.size {
+            return Err(OtaError::SizeMismatch {
+                expected: self.expected.size,
+                actual: self.received,
+            });
+        }
+        if self.sha256.finish()? != self.expected.sha256 {
+            return Err(OtaError::ChecksumMismatch);
+        }
+        Ok(())
+    }
+
+    fn check_header(&self) -> Result<(), OtaError> {
+        let header = &self.header;
+        let desc_magic = u32::from_le_bytes([header[32], header[33], header[34], header[35]]);
+        if header[0] != IMAGE_MAGIC || desc_magic != APP_DESC_MAGIC {
+            return Err(OtaError::Invalid("not an ESP-IDF app image"));
+        }
+        let version = c_str(&header[48..80]);
+        if let Some(expected) = &self.expected.version {
+            if version != *expected {
+                return Err(OtaError::VersionMismatch {
+                    expected: expected.clone(),
+                    actual: version,
+                });
+            }
+        }
+        let project = c_str(&header[80..112]);
+        if let Some(expected) = &self.expected.project {
+            if project != *expected {
+                return Err(OtaError::ProjectMismatch {
+                    expected: expected.clone(),
+                    actual: project,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Where the verified image goes, the inactive slot on the board.
+pub trait UpdateSink {
+    fn write(&mut self, buf: &[u8]) -> Result<(), EspError>;
+    /// Makes the image boot next time.
+    fn complete(self) -> Result<(), EspError>;
+    fn abort(self) -> Result<(), EspError>;
+}
+
+impl UpdateSink for EspOtaUpdate<'_> {
+    fn write(&mut self, buf: &[u8]) -> Result<(), EspError> {
+        EspOtaUpdate::write(self, buf)
+    }
+
+    fn complete(self) -> Result<(), EspError> {
+        EspOtaUpdate::complete(self)
+    }
+
+    fn abort(self) -> Result<(), EspError> {
+        EspOtaUpdate::abort(self)
+    }
+}
+
+/// Verifies and writes the image chunk by chunk, any failure aborts the update.
+pub struct Installer<S, P> {
+    verifier: ImageVerifier,
+    sink: S,
+    progress: P,
+}
+
+impl<S: UpdateSink, P: FnMut(Progress)> Installer<S, P> {
+    pub fn new(verifier: ImageVerifier, sink: S, progress: P) -> Self {
+        Installer {
+            verifier,
+            sink,
+            progress,
+        }
+    }
+
+    pub fn write(&mut self, chunk: &[u8]) -> Result<(), OtaError> {
+        self.verifier.update(chunk)?;
+        self.sink.write(chunk)?;
+        (self.progress)(Progress {
+            written: self.verifier.received,
+            total: self.verifier.expected.size,
+        });
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<(), OtaError> {
+        match self.verifier.finish() {
+            Ok(()) => Ok(self.sink.complete()?),
+            Err(err) => {
+                let _ = self.sink.abort();
+                Err(err)
+            }
+        }
+    }
+
+    pub fn abort(self) {
+        let _ = self.sink.abort();
+    }
+}
+
+/// Logs each 10%.
+pub fn log_progress() -> impl FnMut(Progress) {
+    let mut logged = None;
+    move |progress| {
+        let step = progress.percent() / 10;
+        if logged != Some(step) {
+            logged = Some(step);
+            log::info!("OTA: {}% of {} bytes", progress.percent(), progress.total);
+        }
+    }
+}
+
+/// Installs the firmware of the manifest if its version is newer than the running one,
+/// reboot after [`Outcome::Installed`]. The client timeout has to cover the whole download.
+///
+/// Versions are compared as `MAJOR.MINOR.PATCH[-PRE]`, see [`compare_versions`]. An older
+/// manifest version is [`OtaError::Downgrade`] and one that doesn't parse is
+/// [`OtaError::Invalid`], unless `allow_downgrade` installs whatever differs, e.g. to roll
+/// a bad release back from the server.
+pub fn update_from_manifest<C: Connection + TlsErrors>(
+    client: &mut HttpClient<C>,
+    ota: &mut EspOta,
+    manifest_url: &str,
+    allow_downgrade: bool,
+    progress: impl FnMut(Progress),
+) -> Result<Outcome, OtaError>
+where
+    C::Error: fmt::Display,
+{
+    let manifest: Manifest = client
+        .get_json(manifest_url)
+        .map_err(|err| OtaError::Download(err.to_string()))?;
+    let running = running_firmware(ota)?;
+    let running_version = running.as_ref().map(|(version, _)| version.as_str());
+    if !is_update(running_version, &manifest.version, allow_downgrade)? {
+        log::info!("OTA: {} is up to date", manifest.version);
+        return Ok(Outcome::UpToDate);
+    }
+    let expected = Expected {
+        size: manifest.size,
+        sha256: parse_sha256(&manifest.sha256)
+            .ok_or(OtaError::Invalid("manifest sha256 should be 32 hex bytes"))?,
+        version: Some(manifest.version.clone()),
+        project: running.map(|(_, project)| project),
+    };
+    let url = url::Url::parse(manifest_url)
+        .and_then(|base| base.join(&manifest.url))
+        .map_err(|_| OtaError::Invalid("invalid image URL"))?;
+    log::info!("OTA: {} from {url}", manifest.version);
+
+    let verifier = ImageVerifier::new(expected, update_capacity()?)?;
+    let response = client
+        .request(Method::Get, url.as_str(), &[], &[])
+        .map_err(|err| OtaError::Download(err.to_string()))?;
+    let mut installer = Installer::new(verifier, ota.initiate_update()?, progress);
+    let mut failed = None;
+    let downloaded = response.for_each_chunk(|chunk| match installer.write(chunk) {
+        Ok(()) => ControlFlow::Continue(()),
+        Err(err) => {
+            failed = Some(err);
+            ControlFlow::Break(())
+        }
+    });
+    if let Some(err) = failed {
+        installer.abort();
+        return Err(err);
+    }
+    if let Err(err) = downloaded {
+        installer.abort();
+        return Err(OtaError::Download(err.to_string()));
+    }
+    installer.finish()?;
+    log::info!("OTA: {} installed", manifest.version);
+    Ok(Outcome::Installed {
+        version: manifest.version,
+    })
+}
+
+/// Registers `POST uri`, the body is the image. `X-Firmware-Sha256` is required,
+/// `X-Firmware-Version` is checked when present. The board reboots after the upload.
+/// ```text
+/// curl --data-binary @firmware.bin -H "X-Firmware-Sha256: $(sha256sum firmware.bin | cut -c-64)" \
+///     http://esp32-c3.local/ota
+/// ```
+pub fn serve_upload(
+    server: &mut EspHttpServer<'static>,
+    uri: &str,
+    ota: Arc<Mutex<EspOta>>,
+) -> Result<(), EspError> {
+    server.fn_handler(uri, Method::Post, move |mut req| {
+        let mut ota = lock_recover(&ota);
+        let expected = upload_expected(
+            req.content_len(),
+            req.header("X-Firmware-Sha256"),
+            req.header("X-Firmware-Version"),
+            running_firmware(&ota)?.map(|(_, project)| project),
+        );
+        let result = expected.and_then(|expected| {
+            let verifier = ImageVerifier::new(expected, update_capacity()?)?;
+            let mut installer = Installer::new(verifier, ota.initiate_update()?, log_progress());
+            let mut buf = [0u8; CHUNK_LEN];
+            loop {
+                let len = match req.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(len) => len,
+                    Err(err) => {
+                        installer.abort();
+                        return Err(OtaError::Download(err.to_string()));
+                    }
+                };
+                if let Err(err) = installer.write(&buf[..len]) {
+                    installer.abort();
+                    return Err(err);
+                }
+            }
+            installer.finish()
+        });
+
+        match result {
+            Ok(()) => {
+                log::info!("OTA: upload installed, rebooting");
+                req.into_ok_response()?
+                    .write_all(b"Installed, rebooting.")?;
+                std::thread::spawn(|| {
+                    std::thread::sleep(REBOOT_DELAY);
+                    esp_idf_svc::hal::reset::restart();
+                });
+            }
+            Err(err) => {
+                log::warn!("OTA: upload rejected: {err}");
+                req.into_status_response(err.status())?
+                    .write_all(err.to_string().as_bytes())?;
+            }
+        }
+        Ok(())
+    })?;
+    Ok(())
+}
+
+/// Marks the running firmware valid once `healthy` returns `true`. Otherwise rolls back
+/// after `timeout` and reboots. Does nothing if the firmware is already valid.
+pub fn confirm(
+    ota: &mut EspOta,
+    timeout: Duration,
+    mut healthy: impl FnMut() -> bool,
+) -> Result<(), EspError> {
+    if ota.get_running_slot()?.state != SlotState::Unverified {
+        return Ok(());
+    }
+    log::info!("OTA: new firmware, checking health for {timeout:?}");
+    let started = Instant::now();
+    while !healthy() {
+        if started.elapsed() >= timeout {
+            log::error!("OTA: health check failed, rolling back");
+            return Err(ota.mark_running_slot_invalid_and_reboot());
+        }
+        std::thread::sleep(Duration::from_secs(1));
+    }
+    ota.mark_running_slot_valid()?;
+    log::info!("OTA: new firmware is marked valid");
+    Ok(())
+}
+
+fn upload_expected(
+    content_len: Option<u64>,
+    sha256: Option<&str>,
+    version: Option<&str>,
+    project: Option<String>,
+) -> Result<Expected, OtaError> {
+    Ok(Expected {
+        size: content_len.ok_or(OtaError::Invalid("Content-Length is required"))? as usize,
+        sha256: sha256.and_then(parse_sha256).ok_or(OtaError::Invalid(
+            "X-Firmware-Sha256 should be 32 hex bytes",
+        ))?,
+        version: version.map(str::to_string),
+        project,
+    })
+}
+
+/// Version and project of the running firmware.
+/// Whether `offered` should replace the `running` version, see [`update_from_manifest`].
+fn is_update(
+    running: Option<&str>,
+    offered: &str,
+    allow_downgrade: bool,
+) -> Result<bool, OtaError> {
+    let Some(running) = running else {
+        return Ok(true);
+    };
+    if running == offered {
+        return Ok(false);
+    }
+    match compare_versions(offered, running) {
+        Some(Ordering::Greater) => Ok(true),
+        Some(Ordering::Equal) => Ok(false),
+        _ if allow_downgrade => Ok(true),
+        Some(Ordering::Less) => Err(OtaError::Downgrade {
+            running: running.to_string(),
+            offered: offered.to_string(),
+        }),
+        None => Err(OtaError::Invalid(
+            "versions should be MAJOR.MINOR.PATCH to be compared",
+        )),
+    }
+}
+
+/// Semver order of `MAJOR.MINOR.PATCH[-PRE][+BUILD]`, `None` if either doesn't parse.
+/// Pre-releases go before their release, build metadata is ignored.
+pub fn compare_versions(a: &str, b: &str) -> Option<Ordering> {
+    fn parse(version: &str) -> Option<([u64; 3], Option<&str>)> {
+        let version = version
+            .split_once('+')
+            .map_or(version, |(version, _)| version);
+        let (release, pre) = match version.split_once('-') {
+            Some((release, pre)) => (release, Some(pre)),
+            None => (version, None),
+        };
+        let mut numbers = [0u64; 3];
+        let mut parts = release.split('.');
+        for number in numbers.iter_mut() {
+            *number = parts.next()?.parse().ok()?;
+        }
+        if parts.next().is_some() || pre == Some("") {
+            return None;
+        }
+        Some((numbers, pre))
+    }
+
+    fn compare_pre(a: &str, b: &str) -> Ordering {
+        let mut a = a.split('.');
+        let mut b = b.split('.');
+        loop {
+            let ordering = match (a.next(), b.next()) {
+                (None, None) => return Ordering::Equal,
+                (None, Some(_)) => Ordering::Less,
+                (Some(_), None) => Ordering::Greater,
+                (Some(a), Some(b)) => match (a.parse::<u64>(), b.parse::<u64>()) {
+                    (Ok(a), Ok(b)) => a.cmp(&b),
+                    // numeric identifiers go first
+                    (Ok(_), Err(_)) => Ordering::Less,
+                    (Err(_), Ok(_)) => Ordering::Greater,
+                    (Err(_), Err(_)) => a.cmp(b),
+                },
+            };
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+    }
+
+    let (a_release, a_pre) = parse(a)?;
+    let (b_release, b_pre) = parse(b)?;
+    Some(
+        a_release
+            .cmp(&b_release)
+            .then_with(|| match (a_pre, b_pre) {
+                (None, None) => Ordering::Equal,
+                (None, Some(_)) => Ordering::Greater,
+                (Some(_), None) => Ordering::Less,
+                (Some(a), Some(b)) => compare_pre(a, b),
+            }),
+    )
+}
+
+fn running_firmware(ota: &EspOta) -> Result<Option<(String, String)>, EspError> {
+    Ok(ota.get_running_slot()?.firmware.map(|firmware| {
+        let project = firmware.description.unwrap_or_default();
+        (firmware.version.to_string(), project.to_string())
+    }))
+}
+
+/// Size of the slot the next update goes into.
+fn update_capacity() -> Result<usize, EspError> {
+    let partition = unsafe { sys::esp_ota_get_next_update_partition(std::ptr::null()).as_ref() };
+    partition
+        .map(|partition| partition.size as usize)
+        .ok_or(EspError::from_infallible::<{ sys::ESP_ERR_NOT_FOUND }>())
+}
+
+fn parse_sha256(hex: &str) -> Option<[u8; 32]> {
+    let hex = hex.trim();
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut sha256 = [0u8; 32];
+    for (byte, i) in sha256.iter_mut().zip((0..64).step_by(2)) {
+        *byte = u8::from_str_radix(&hex[i..i + 2], 16).ok()?;
+    }
+    Some(sha256)
+}
+
+fn c_str(bytes: &[u8]) -> String {
+    let len = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..len]).into_owned()
+}
+
+/// mbedTLS SHA-256, hardware accelerated on the board.
+struct Sha256(Box<sys::mbedtls_md_context_t>);
+
+impl Sha256 {
+    fn new() -> Result<Self, EspError> {
+        // a zeroed context is freed fine by `Drop` if the setup fails
+        let mut sha256 = Sha256(Box::new(unsafe { std::mem::zeroed() }));
+        unsafe {
+            sys::mbedtls_md_init(sha256.0.as_mut());
+            let info = sys::mbedtls_md_info_from_type(sys::mbedtls_md_type_t_MBEDTLS_MD_SHA256);
+            md_result(sys::mbedtls_md_setup(sha256.0.as_mut(), info, 0))?;
+            md_result(sys::mbedtls_md_starts(sha256.0.as_mut()))?;
+        }
+        Ok(sha256)
+    }
+
+    fn update(&mut self, data: &[u8]) -> Result<(), EspError> {
+        md_result(unsafe { sys::mbedtls_md_update(self.0.as_mut(), data.as_ptr(), data.len()) })
+    }
+
+    fn finish(mut self) -> Result<[u8; 32], EspError> {
+        let mut sha256 = [0u8; 32];
+        md_result(unsafe { sys::mbedtls_md_finish(self.0.as_mut(), sha256.as_mut_ptr()) })?;
+        Ok(sha256)
+    }
+}
+
+impl Drop for Sha256 {
+    fn drop(&mut self) {
+        unsafe { sys::mbedtls_md_free(self.0.as_mut()) };
+    }
+}
+
+fn md_result(result: std::ffi::c_int) -> Result<(), EspError> {
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(EspError::from_infallible::<{ sys::ESP_FAIL }>())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::ota::{
+        compare_versions, is_update, parse_sha256, upload_expected, Expected, ImageVerifier,
+        Installer, OtaError, Progress, UpdateSink, HEADER_LEN,
+    };
+    use esp_idf_svc::sys::EspError;
+    use std::cmp::Ordering;
+
+    /// Image header of `version` and `project`, padded with `len - HEADER_LEN` bytes.
+    fn fake_image(version: &str, project: &str, len: usize) -> Vec<u8> {
+        let mut image = vec![0u8; len.max(HEADER_LEN)];
+        image[0] = 0xE9;
+        image[32..36].copy_from_slice(&0xABCD_5432u32.to_le_bytes());
+        image[48..48 + version.len()].copy_from_slice(version.as_bytes());
+        image[80..80 + project.len()].copy_from_slice(project.as_bytes());
+        for (i, byte) in image[HEADER_LEN..].iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        image
+    }
+
+    fn expected(image: &[u8], sha256: &str) -> Expected {
+        Expected {
+            size: image.len(),
+            sha256: parse_sha256(sha256).unwrap(),
+            version: Some("0.2.0".to_string()),
+            project: Some("esp32-c3-examples".to_string()),
+        }
+    }
+
+    /// Flash slot of the tests.
+    #[derive(Default)]
+    struct FakeSlot {
+        written: Vec<u8>,
+        completed: bool,
+    }
+
+    impl UpdateSink for &mut FakeSlot {
+        fn write(&mut self, buf: &[u8]) -> Result<(), EspError> {
+            self.written.extend_from_slice(buf);
+            Ok(())
+        }
+
+        fn complete(self) -> Result<(), EspError> {
+            self.completed = true;
+            Ok(())
+        }
+
+        fn abort(self) -> Result<(), EspError> {
+            self.written.clear();
+            Ok(())
+        }
+    }
+
+    // sha256 of `image("0.2.0", "esp32-c3-examples", 1000)`
+    const SHA256: &str = "3f3e07c6454f6ac499d9e41235a6446c67a8cc231318afc004cf36382853d651";
+
+    #[test]
+    fn installer_test() {
+        let image = fake_image("0.2.0", "esp32-c3-examples", 1000);
+        let mut slot = FakeSlot::default();
+        let mut progress = Vec::new();
+        let verifier = ImageVerifier::new(expected(&image, SHA256), 4096).unwrap();
+        let mut installer = Installer::new(verifier, &mut slot, |p: Progress| {
+            progress.push(p.percent())
+        });
+        // the header is split between chunks
+        for chunk in image.chunks(100) {
+            installer.write(chunk).unwrap();
+        }
+        installer.finish().unwrap();
+        assert!(slot.completed);
+        assert_eq!(slot.written, image);
+        assert_eq!(progress.first(), Some(&10));
+        assert_eq!(progress.last(), Some(&100));
+    }
+
+    #[test]
+    fn verifier_test() {
+        let image = fake_image("0.2.0", "esp32-c3-examples", 1000);
+        let verify = |image: &[u8], expected: Expected| {
+            let mut verifier = ImageVerifier::new(expected, 4096)?;
+            for chunk in image.chunks(64) {
+                verifier.update(chunk)?;
+            }
+            verifier.finish()
+        };
+        assert!(verify(&image, expected(&image, SHA256)).is_ok());
+
+        let mut corrupted = image.clone();
+        corrupted[500] ^= 1;
+        assert!(matches!(
+            verify(&corrupted, expected(&image, SHA256)),
+            Err(OtaError::ChecksumMismatch)
+        ));
+        assert!(matches!(
+            verify(&image[..999], expected(&image, SHA256)),
+            Err(OtaError::SizeMismatch { actual: 999, .. })
+        ));
+        let other = fake_image("0.1.0", "esp32-c3-examples", 1000);
+        assert!(matches!(
+            verify(&other, expected(&image, SHA256)),
+            Err(OtaError::VersionMismatch { .. })
+        ));
+        let other = fake_image("0.2.0", "blink", 1000);
+        assert!(matches!(
+            verify(&other, expected(&image, SHA256)),
+            Err(OtaError::ProjectMismatch { .. })
+        ));
+        let mut other = image.clone();
+        other[0] = 0;
+        assert!(matches!(
+            verify(&other, expected(&image, SHA256)),
+            Err(OtaError::Invalid(_))
+        ));
+        assert!(matches!(
+            ImageVerifier::new(expected(&image, SHA256), 999),
+            Err(OtaError::TooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn upload_expected_test() {
+        let expected = upload_expected(Some(1000), Some(SHA256), None, None).unwrap();
+        assert_eq!(expected.size, 1000);
+        assert_eq!(expected.sha256[..2], [0x3f, 0x3e]);
+        let err = upload_expected(None, Some(SHA256), None, None).unwrap_err();
+        assert_eq!(err.status(), 400);
+        assert!(upload_expected(Some(1000), Some("3f3e"), None, None).is_err());
+        assert!(upload_expected(Some(1000), None, None, None).is_err());
+    }
+
+    #[test]
+    fn versions_test() {
+        assert_eq!(compare_versions("0.10.0", "0.9.1"), Some(Ordering::Greater));
+        assert_eq!(
+            compare_versions("1.0.0-rc.1", "1.0.0"),
+            Some(Ordering::Less)
+        );
+        assert_eq!(
+            compare_versions("1.0.0-rc.2", "1.0.0-rc.10"),
+            Some(Ordering::Less)
+        );
+        assert_eq!(
+            compare_versions("1.0.0+abc", "1.0.0"),
+            Some(Ordering::Equal)
+        );
+        assert_eq!(compare_versions("1.0", "1.0.0"), None);
+        assert_eq!(compare_versions("v1.0.0", "1.0.0"), None);
+
+        assert!(is_update(None, "0.1.0", false).unwrap());
+        assert!(is_update(Some("0.1.0"), "0.2.0", false).unwrap());
+        assert!(!is_update(Some("0.2.0"), "0.2.0", false).unwrap());
+        assert!(matches!(
+            is_update(Some("0.2.0"), "0.1.0", false),
+            Err(OtaError::Downgrade { .. })
+        ));
+        assert!(matches!(
+            is_update(Some("libespidf"), "0.1.0", false),
+            Err(OtaError::Invalid(_))
+        ));
+        // explicitly allowed
+        assert!(is_update(Some("0.2.0"), "0.1.0", true).unwrap());
+        assert!(is_update(Some("libespidf"), "0.1.0", true).unwrap());
+    }
+}
@@ -0,0 +1,240 @@
+//! Pan/tilt head made of two servos and controlled by joystick axes.
+//!
+//! * click - returns head to the center
+//! * long press - saves current position as preset into NVS
+
+use crate::ledc_servo_lib::Servo;
+use esp_idf_svc::nvs::{EspNvs, NvsDefault};
+use esp_idf_svc::sys::EspError;
+use std::time::Duration;
+
+/// NVS namespace for presets.
+pub const NVS_NAMESPACE: &str = "pan_tilt";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Stick deflection is the servo position, released stick holds `center`.
+    Absolute,
+    /// Stick deflection is the servo speed, released stick holds the last position.
+    Rate,
+}
+
+#[derive(Debug, Clone)]
+pub struct PanTiltConfig {
+    pub mode: Mode,
+    /// Position (pan, tilt) in degrees for the released stick and for the center recall.
+    pub center: (f64, f64),
+    /// Servo speed at full stick deflection in `Mode::Rate`, degrees per second.
+    pub rate_deg_per_sec: f64,
+    /// Max servo speed in any mode, degrees per second.
+    pub max_slew_deg_per_sec: f64,
+    pub invert_pan: bool,
+    pub invert_tilt: bool,
+}
+
+impl Default for PanTiltConfig {
+    fn default() -> Self {
+        PanTiltConfig {
+            mode: Mode::Absolute,
+            center: (90.0, 90.0),
+            rate_deg_per_sec: 60.0,
+            max_slew_deg_per_sec: 180.0,
+            invert_pan: false,
+            invert_tilt: false,
+        }
+    }
+}
+
+pub struct PanTilt<'d> {
+    pan: Servo<'d>,
+    tilt: Servo<'d>,
+    config: PanTiltConfig,
+    pan_axis: SlewAxis,
+    tilt_axis: SlewAxis,
+}
+
+impl<'d> PanTilt<'d> {
+    /// Moves both servos to the center immediately.
+    pub fn new(
+        mut pan: Servo<'d>,
+        mut tilt: Servo<'d>,
+        config: PanTiltConfig,
+    ) -> Result<PanTilt<'d>, EspError> {
+        let pan_axis = SlewAxis::new(config.center.0, pan.max_angle());
+        let tilt_axis = SlewAxis::new(config.center.1, tilt.max_angle());
+        pan.set_angle(pan_axis.current)?;
+        tilt.set_angle(tilt_axis.current)?;
+
+        Ok(PanTilt {
+            pan,
+            tilt,
+            config,
+            pan_axis,
+            tilt_axis,
+        })
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.config.mode
+    }
+
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.config.mode = mode;
+    }
+
+    /// Current position (pan, tilt) in degrees.
+    pub fn position(&self) -> (f64, f64) {
+        (self.pan_axis.current, self.tilt_axis.current)
+    }
+
+    /// Should be called periodically with normalized stick deflection (see
+    /// [`crate::joystick::AxisCalibration::normalize`]) and time since previous call.
+    pub fn update(&mut self, x: f32, y: f32, dt: Duration) -> Result<(), EspError> {
+        let x = (if self.config.invert_pan { -x } else { x }) as f64;
+        let y = (if self.config.invert_tilt { -y } else { y }) as f64;
+        let dt = dt.as_secs_f64();
+
+        match self.config.mode {
+            Mode::Absolute => {
+                self.pan_axis.target = absolute_target(x, self.config.center.0, self.pan_axis.max);
+                self.tilt_axis.target =
+                    absolute_target(y, self.config.center.1, self.tilt_axis.max);
+            }
+            Mode::Rate => {
+                // accumulates from the current position, so releasing the stick stops the head
+                let delta = self.config.rate_deg_per_sec * dt;
+                if x != 0.0 {
+                    self.pan_axis.set_target(self.pan_axis.current + x * delta);
+                }
+                if y != 0.0 {
+                    self.tilt_axis
+                        .set_target(self.tilt_axis.current + y * delta);
+                }
+            }
+        }
+
+        self.apply(self.config.max_slew_deg_per_sec * dt)
+    }
+
+    /// Returns the head to the center, movement is slew limited by next `update` calls.
+    /// In `Mode::Absolute` the stick position wins on the next `update`.
+    pub fn recall_center(&mut self) {
+        let (pan, tilt) = self.config.center;
+        self.goto(pan, tilt);
+    }
+
+    /// Sets target position (pan, tilt), movement is slew limited by next `update` calls.
+    pub fn goto(&mut self, pan: f64, tilt: f64) {
+        self.pan_axis.set_target(pan);
+        self.tilt_axis.set_target(tilt);
+    }
+
+    /// Stores current position into the preset `slot`.
+    pub fn save_preset(&self, nvs: &mut EspNvs<NvsDefault>, slot: u8) -> Result<(), EspError> {
+        let (pan, tilt) = self.position();
+        let mut buf = [0u8; 8];
+        buf[..4].copy_from_slice(&(pan as f32).to_le_bytes());
+        buf[4..].copy_from_slice(&(tilt as f32).to_le_bytes());
+        nvs.set_raw(&preset_key(slot), &buf)?;
+        log::info!("Preset {slot} saved: pan={pan:.1} tilt={tilt:.1}");
+        Ok(())
+    }
+
+    /// Moves to the position from the preset `slot`, returns `false` if the slot is empty.
+    pub fn recall_preset(&mut self, nvs: &EspNvs<NvsDefault>, slot: u8) -> Result<bool, EspError> {
+        let mut buf = [0u8; 8];
+        match nvs.get_raw(&preset_key(slot), &mut buf)? {
+            Some(data) if data.len() == 8 => {
+                let pan = f32::from_le_bytes([data[0], data[1], data[2], data[3]]) as f64;
+                let tilt = f32::from_le_bytes([data[4], data[5], data[6], data[7]]) as f64;
+                log::info!("Preset {slot} recalled: pan={pan:.1} tilt={tilt:.1}");
+                self.goto(pan, tilt);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn apply(&mut self, max_delta: f64) -> Result<(), EspError> {
+        if self.pan_axis.step(max_delta) {
+            self.pan.set_angle(self.pan_axis.current)?;
+        }
+        if self.tilt_axis.step(max_delta) {
+            self.tilt.set_angle(self.tilt_axis.current)?;
+        }
+        Ok(())
+    }
+}
+
+fn preset_key(slot: u8) -> String {
+    format!("preset{slot}")
+}
+
+/// Maps deflection `-1.0..=1.0` to angle, `0.0` is `center`, ends are `0` and `max`.
+fn absolute_target(deflection: f64, center: f64, max: f64) -> f64 {
+    if deflection < 0.0 {
+        center + deflection * center
+    } else {
+        center + deflection * (max - center)
+    }
+}
+
+/// Position of one servo that follows the target no faster than allowed.
+#[derive(Debug)]
+struct SlewAxis {
+    current: f64,
+    target: f64,
+    max: f64,
+}
+
+impl SlewAxis {
+    fn new(position: f64, max: f64) -> Self {
+        let position = position.clamp(0.0, max);
+        SlewAxis {
+            current: position,
+            target: position,
+            max,
+        }
+    }
+
+    fn set_target(&mut self, target: f64) {
+        self.target = target.clamp(0.0, self.max);
+    }
+
+    /// Moves toward target by `max_delta` at most, returns `true` if position changed.
+    fn step(&mut self, max_delta: f64) -> bool {
+        let diff = self.target - self.current;
+        if diff == 0.0 {
+            return false;
+        }
+        self.current += diff.clamp(-max_delta, max_delta);
+        true
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::pan_tilt::{absolute_target, SlewAxis};
+
+    #[test]
+    fn absolute_target_test() {
+        assert_eq!(absolute_target(0.0, 90.0, 180.0), 90.0);
+        assert_eq!(absolute_target(-1.0, 60.0, 180.0), 0.0);
+        assert_eq!(absolute_target(1.0, 60.0, 180.0), 180.0);
+        assert_eq!(absolute_target(0.5, 60.0, 180.0), 120.0);
+    }
+
+    #[test]
+    fn slew_axis_test() {
+        let mut axis = SlewAxis::new(90.0, 180.0);
+        assert!(!axis.step(10.0));
+
+        axis.set_target(200.0);
+        assert_eq!(axis.target, 180.0);
+        assert!(axis.step(50.0));
+        assert_eq!(axis.current, 140.0);
+        assert!(axis.step(50.0));
+        assert_eq!(axis.current, 180.0);
+        assert!(!axis.step(50.0));
+    }
+}
@@ -0,0 +1,618 @@
+//! JSON REST API for the devices of the board, served by `EspHttpServer`.
+//!
+//! | method | path                       | body                            |
+//! |--------|----------------------------|---------------------------------|
+//! | GET    | `/api/status`              |                                 |
+//! | GET    | `/api/sensors`             |                                 |
+//! | GET    | `/api/servo[/{id}]`        |                                 |
+//! | PUT    | `/api/servo/{id}`          | `{"angle": 90.0}`               |
+//! | GET    | `/api/stepper[/{id}]`      |                                 |
+//! | POST   | `/api/stepper/{id}/move`   | `{"steps": -512, "speed": 300}` |
+//! | POST   | `/api/stepper/{id}/stop`   |                                 |
+//!
+//! Devices are reached through [`ServoControl`], [`StepperControl`] and [`SensorSource`],
+//! so [`RestApi::handle`] is plain routing and validation and runs on the host too.
+//! Errors are `{"error": "..."}` with the status of [`ApiError::status`].
+
+use crate::clock;
+use crate::ledc_servo_lib::Servo;
+use crate::lock_recover;
+use crate::temp_sensor::task::TempReadings;
+use crate::temp_sensor::SensorStatus;
+use embedded_svc::io::Write;
+use esp_idf_svc::http::server::EspHttpServer;
+use esp_idf_svc::http::Method;
+use esp_idf_svc::sys::EspError;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+pub mod steppers;
+
+/// Longer request bodies are rejected with 413.
+pub const MAX_BODY_LEN: usize = 256;
+/// Longest move of one request, 16 turns of `28byj-48` in half steps.
+pub const MAX_MOVE_STEPS: u32 = 16 * 4096;
+/// Steps per second, `28byj-48` skips steps above it.
+pub const MAX_STEPPER_SPEED: u32 = 500;
+pub const DEFAULT_STEPPER_SPEED: u32 = 250;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ApiError {
+    NotFound(&'static str),
+    /// Allowed methods of the path, e.g. `GET, PUT`.
+    MethodNotAllowed(&'static str),
+    /// Body isn't the expected JSON.
+    BadRequest(String),
+    PayloadTooLarge,
+    /// Well-formed command with invalid values.
+    Invalid(&'static str),
+    /// Device can't take the command now, e.g. the stepper is still moving.
+    Busy(&'static str),
+    Device(String),
+}
+
+impl ApiError {
+    pub fn status(&self) -> u16 {
+        match self {
+            ApiError::NotFound(_) => 404,
+            ApiError::MethodNotAllowed(_) => 405,
+            ApiError::BadRequest(_) => 400,
+            ApiError::PayloadTooLarge => 413,
+            ApiError::Invalid(_) => 422,
+            ApiError::Busy(_) => 409,
+            ApiError::Device(_) => 500,
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::NotFound(what) => write!(f, "{what} not found"),
+            ApiError::MethodNotAllowed(allow) => write!(f, "method not allowed, use {allow}"),
+            ApiError::BadRequest(err) => write!(f, "invalid JSON: {err}"),
+            ApiError::PayloadTooLarge => write!(f, "body is longer than {MAX_BODY_LEN} bytes"),
+            ApiError::Invalid(err) | ApiError::Busy(err) => f.write_str(err),
+            ApiError::Device(err) => write!(f, "device failed: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl From<EspError> for ApiError {
+    fn from(err: EspError) -> Self {
+        ApiError::Device(err.to_string())
+    }
+}
+
+/// Servos with ids `0..count()`, ids passed to the other methods are in range.
+pub trait ServoControl {
+    fn count(&self) -> usize;
+    fn max_angle(&self, id: usize) -> f64;
+    /// Degrees.
+    fn angle(&self, id: usize) -> f64;
+    /// `angle` is already checked against [`ServoControl::max_angle`].
+    fn set_angle(&mut self, id: usize, angle: f64) -> Result<(), ApiError>;
+}
+
+/// Steppers with ids `0..count()`, ids passed to the other methods are in range.
+pub trait StepperControl {
+    fn count(&self) -> usize;
+    fn state(&self, id: usize) -> StepperState;
+    /// Starts moving by `steps`, negative ones go backward, doesn't wait for the end.
+    fn start_move(&mut self, id: usize, steps: i32, speed: u32) -> Result<(), ApiError>;
+    fn stop(&mut self, id: usize) -> Result<(), ApiError>;
+}
+
+pub trait SensorSource {
+    fn sensors(&self) -> Vec<SensorReading>;
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ServoState {
+    pub id: usize,
+    pub angle: f64,
+    pub max_angle: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct StepperState {
+    /// Steps from the power on position.
+    pub position: i64,
+    pub target: i64,
+    pub moving: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SensorReading {
+    /// 1-Wire ROM code, hex.
+    pub address: String,
+    /// Celsius, the last successful reading.
+    pub temperature: Option<f32>,
+    /// Seconds since `temperature` was read.
+    pub age_secs: Option<f32>,
+    /// `online`, `failing` or `offline`.
+    pub status: &'static str,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ApiStatus {
+    pub version: &'static str,
+    pub uptime_secs: u64,
+    /// RFC 3339, `None` until SNTP sync, see [`crate::clock`].
+    pub time: Option<String>,
+    pub servos: usize,
+    pub steppers: usize,
+    pub moving_steppers: usize,
+    pub sensors: usize,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ServoCommand {
+    angle: f64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MoveCommand {
+    steps: i32,
+    /// Steps per second, [`DEFAULT_STEPPER_SPEED`] if missing.
+    speed: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiResponse {
+    pub status: u16,
+    /// JSON.
+    pub body: String,
+    /// `Allow` header of 405.
+    pub allow: Option<&'static str>,
+}
+
+impl ApiResponse {
+    fn json<T: Serialize>(status: u16, value: &T) -> Self {
+        match serde_json::to_string(value) {
+            Ok(body) => ApiResponse {
+                status,
+                body,
+                allow: None,
+            },
+            Err(err) => ApiError::Device(err.to_string()).into(),
+        }
+    }
+}
+
+impl From<ApiError> for ApiResponse {
+    fn from(err: ApiError) -> Self {
+        let allow = match err {
+            ApiError::MethodNotAllowed(allow) => Some(allow),
+            _ => None,
+        };
+        ApiResponse {
+            allow,
+            ..ApiResponse::json(
+                err.status(),
+                &ErrorBody {
+                    error: err.to_string(),
+                },
+            )
+        }
+    }
+}
+
+pub struct RestApi<S, T, R> {
+    servos: S,
+    steppers: T,
+    sensors: R,
+    started: Instant,
+}
+
+impl<S: ServoControl, T: StepperControl, R: SensorSource> RestApi<S, T, R> {
+    pub fn new(servos: S, steppers: T, sensors: R) -> Self {
+        RestApi {
+            servos,
+            steppers,
+            sensors,
+            started: Instant::now(),
+        }
+    }
+
+    /// `uri` may have a query, it's ignored.
+    pub fn handle(&mut self, method: Method, uri: &str, body: &[u8]) -> ApiResponse {
+        self.route(method, uri, body)
+            .unwrap_or_else(ApiResponse::from)
+    }
+
+    fn route(&mut self, method: Method, uri: &str, body: &[u8]) -> Result<ApiResponse, ApiError> {
+        if body.len() > MAX_BODY_LEN {
+            return Err(ApiError::PayloadTooLarge);
+        }
+        let path = uri.split('?').next().unwrap_or_default();
+        let path = path
+            .strip_prefix("/api/")
+            .ok_or(ApiError::NotFound("endpoint"))?
+            .trim_end_matches('/');
+        let segments = path.split('/').collect::<Vec<_>>();
+
+        match segments.as_slice() {
+            ["status"] => {
+                allow(method, Method::Get, "GET")?;
+                Ok(ApiResponse::json(200, &self.status()))
+            }
+            ["sensors"] => {
+                allow(method, Method::Get, "GET")?;
+                Ok(ApiResponse::json(200, &self.sensors.sensors()))
+            }
+            ["servo"] => {
+                allow(method, Method::Get, "GET")?;
+                let servos = (0..self.servos.count())
+                    .map(|id| self.servo_state(id))
+                    .collect::<Vec<_>>();
+                Ok(ApiResponse::json(200, &servos))
+            }
+            ["servo", id] => {
+                let id = parse_id(id, self.servos.count(), "servo")?;
+                match method {
+                    Method::Get => Ok(ApiResponse::json(200, &self.servo_state(id))),
+                    Method::Put => {
+                        let command = parse_body::<ServoCommand>(body)?;
+                        set_servo(&mut self.servos, id, command.angle)?;
+                        Ok(ApiResponse::json(200, &self.servo_state(id)))
+                    }
+                    _ => Err(ApiError::MethodNotAllowed("GET, PUT")),
+                }
+            }
+            ["stepper"] => {
+                allow(method, Method::Get, "GET")?;
+                let steppers = (0..self.steppers.count())
+                    .map(|id| self.steppers.state(id))
+                    .collect::<Vec<_>>();
+                Ok(ApiResponse::json(200, &steppers))
+            }
+            ["stepper", id] => {
+                let id = parse_id(id, self.steppers.count(), "stepper")?;
+                allow(method, Method::Get, "GET")?;
+                Ok(ApiResponse::json(200, &self.steppers.state(id)))
+            }
+            ["stepper", id, "move"] => {
+                let id = parse_id(id, self.steppers.count(), "stepper")?;
+                allow(method, Method::Post, "POST")?;
+                let command = parse_body::<MoveCommand>(body)?;
+                move_stepper(&mut self.steppers, id, command.steps, command.speed)?;
+                Ok(ApiResponse::json(202, &self.steppers.state(id)))
+            }
+            ["stepper", id, "stop"] => {
+                let id = parse_id(id, self.steppers.count(), "stepper")?;
+                allow(method, Method::Post, "POST")?;
+                stop_stepper(&mut self.steppers, id)?;
+                Ok(ApiResponse::json(200, &self.steppers.state(id)))
+            }
+            _ => Err(ApiError::NotFound("endpoint")),
+        }
+    }
+
+    fn status(&self) -> ApiStatus {
+        ApiStatus {
+            version: env!("CARGO_PKG_VERSION"),
+            uptime_secs: self.started.elapsed().as_secs(),
+            time: clock::now_rfc3339(),
+            servos: self.servos.count(),
+            steppers: self.steppers.count(),
+            moving_steppers: (0..self.steppers.count())
+                .filter(|id| self.steppers.state(*id).moving)
+                .count(),
+            sensors: self.sensors.sensors().len(),
+        }
+    }
+
+    fn servo_state(&self, id: usize) -> ServoState {
+        ServoState {
+            id,
+            angle: self.servos.angle(id),
+            max_angle: self.servos.max_angle(id),
+        }
+    }
+}
+
+/// Registers `/api/*` handlers, the server needs `uri_match_wildcard`.
+/// Requests are handled one by one under the lock of `api`.
+pub fn serve<S, T, R>(
+    server: &mut EspHttpServer<'static>,
+    api: &Arc<Mutex<RestApi<S, T, R>>>,
+) -> Result<(), EspError>
+where
+    S: ServoControl + Send + 'static,
+    T: StepperControl + Send + 'static,
+    R: SensorSource + Send + 'static,
+{
+    // other methods get 405 from the router instead of 404 from the server
+    for method in [Method::Get, Method::Put, Method::Post, Method::Delete] {
+        let api = api.clone();
+        server.fn_handler("/api/*", method, move |mut req| {
+            // one byte more tells the router that the body is too long
+            let mut buf = [0u8; MAX_BODY_LEN + 1];
+            let len =
+                embedded_svc::utils::io::try_read_full(&mut req, &mut buf).map_err(|e| e.0)?;
+            let response = lock_recover(&api).handle(method, req.uri(), &buf[..len]);
+
+            let mut headers = vec![("Content-Type", "application/json")];
+            if let Some(allow) = response.allow {
+                headers.push(("Allow", allow));
+            }
+            req.into_response(response.status, None, &headers)?
+                .write_all(response.body.as_bytes())?;
+            Ok(())
+        })?;
+    }
+    Ok(())
+}
+
+/// Checks the id and the angle, commands of other transports go through it too.
+pub fn set_servo(servos: &mut impl ServoControl, id: usize, angle: f64) -> Result<(), ApiError> {
+    check_id(id, servos.count(), "servo")?;
+    if !angle.is_finite() || angle < 0.0 || angle > servos.max_angle(id) {
+        return Err(ApiError::Invalid("angle is out of the servo range"));
+    }
+    servos.set_angle(id, angle)?;
+    log::info!("API: servo {id} to {angle:.1}");
+    Ok(())
+}
+
+/// Checks the id, steps and speed, [`DEFAULT_STEPPER_SPEED`] if `speed` is `None`.
+pub fn move_stepper(
+    steppers: &mut impl StepperControl,
+    id: usize,
+    steps: i32,
+    speed: Option<u32>,
+) -> Result<(), ApiError> {
+    check_id(id, steppers.count(), "stepper")?;
+    if steps == 0 || steps.unsigned_abs() > MAX_MOVE_STEPS {
+        return Err(ApiError::Invalid("steps should be non-zero, up to 65536"));
+    }
+    let speed = speed.unwrap_or(DEFAULT_STEPPER_SPEED);
+    if speed == 0 || speed > MAX_STEPPER_SPEED {
+        return Err(ApiError::Invalid("speed should be 1-500 steps per second"));
+    }
+    steppers.start_move(id, steps, speed)?;
+    log::info!("API: stepper {id} by {steps} at {speed}/s");
+    Ok(())
+}
+
+pub fn stop_stepper(steppers: &mut impl StepperControl, id: usize) -> Result<(), ApiError> {
+    check_id(id, steppers.count(), "stepper")?;
+    steppers.stop(id)
+}
+
+fn allow(method: Method, allowed: Method, allow: &'static str) -> Result<(), ApiError> {
+    if method == allowed {
+        Ok(())
+    } else {
+        Err(ApiError::MethodNotAllowed(allow))
+    }
+}
+
+fn parse_id(id: &str, count: usize, what: &'static str) -> Result<usize, ApiError> {
+    let id = id.parse().map_err(|_| ApiError::NotFound(what))?;
+    check_id(id, count, what)?;
+    Ok(id)
+}
+
+fn check_id(id: usize, count: usize, what: &'static str) -> Result<(), ApiError> {
+    if id < count {
+        Ok(())
+    } else {
+        Err(ApiError::NotFound(what))
+    }
+}
+
+fn parse_body<T: serde::de::DeserializeOwned>(body: &[u8]) -> Result<T, ApiError> {
+    serde_json::from_slice(body).map_err(|err| ApiError::BadRequest(err.to_string()))
+}
+
+impl ServoControl for Vec<Servo<'_>> {
+    fn count(&self) -> usize {
+        self.len()
+    }
+
+    fn max_angle(&self, id: usize) -> f64 {
+        self[id].max_angle()
+    }
+
+    fn angle(&self, id: usize) -> f64 {
+        self[id].get_angle()
+    }
+
+    fn set_angle(&mut self, id: usize, angle: f64) -> Result<(), ApiError> {
+        Ok(self[id].set_angle(angle)?)
+    }
+}
+
+impl SensorSource for TempReadings {
+    fn sensors(&self) -> Vec<SensorReading> {
+        self.snapshot()
+            .sensors
+            .iter()
+            .map(|sensor| SensorReading {
+                address: format!("{:?}", sensor.address),
+                temperature: sensor.temperature,
+                age_secs: sensor.read_at.map(|at| at.elapsed().as_secs_f32()),
+                status: match sensor.status {
+                    SensorStatus::Online => "online",
+                    SensorStatus::Failing { .. } => "failing",
+                    SensorStatus::Offline => "offline",
+                },
+                error: sensor.error.map(|err| err.to_string()),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::rest_api::{
+        ApiError, RestApi, SensorReading, SensorSource, ServoControl, StepperControl, StepperState,
+    };
+    use esp_idf_svc::http::Method;
+
+    struct FakeServos(Vec<f64>);
+
+    impl ServoControl for FakeServos {
+        fn count(&self) -> usize {
+            self.0.len()
+        }
+
+        fn max_angle(&self, _id: usize) -> f64 {
+            180.0
+        }
+
+        fn angle(&self, id: usize) -> f64 {
+            self.0[id]
+        }
+
+        fn set_angle(&mut self, id: usize, angle: f64) -> Result<(), ApiError> {
+            self.0[id] = angle;
+            Ok(())
+        }
+    }
+
+    struct FakeSteppers(Vec<StepperState>);
+
+    impl StepperControl for FakeSteppers {
+        fn count(&self) -> usize {
+            self.0.len()
+        }
+
+        fn state(&self, id: usize) -> StepperState {
+            self.0[id]
+        }
+
+        fn start_move(&mut self, id: usize, steps: i32, _speed: u32) -> Result<(), ApiError> {
+            let state = &mut self.0[id];
+            if state.moving {
+                return Err(ApiError::Busy("stepper is moving"));
+            }
+            state.target = state.position + steps as i64;
+            state.moving = true;
+            Ok(())
+        }
+
+        fn stop(&mut self, id: usize) -> Result<(), ApiError> {
+            let state = &mut self.0[id];
+            state.target = state.position;
+            state.moving = false;
+            Ok(())
+        }
+    }
+
+    struct FakeSensors;
+
+    impl SensorSource for FakeSensors {
+        fn sensors(&self) -> Vec<SensorReading> {
+            vec![SensorReading {
+                address: "28FF000000000001".to_string(),
+                temperature: Some(21.5),
+                age_secs: Some(1.0),
+                status: "online",
+                error: None,
+            }]
+        }
+    }
+
+    fn api() -> RestApi<FakeServos, FakeSteppers, FakeSensors> {
+        let idle = StepperState {
+            position: 0,
+            target: 0,
+            moving: false,
+        };
+        RestApi::new(
+            FakeServos(vec![90.0, 0.0]),
+            FakeSteppers(vec![idle; 2]),
+            FakeSensors,
+        )
+    }
+
+    #[test]
+    fn servo_test() {
+        let mut api = api();
+        let response = api.handle(Method::Put, "/api/servo/1", br#"{"angle": 45.5}"#);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, r#"{"id":1,"angle":45.5,"max_angle":180.0}"#);
+
+        let response = api.handle(Method::Get, "/api/servo?pretty", b"");
+        assert_eq!(response.status, 200);
+        assert!(response.body.starts_with(r#"[{"id":0,"angle":90.0,"#));
+
+        let status = |body: &str| api_status(Method::Put, "/api/servo/0", body);
+        assert_eq!(status(r#"{"angle": 181}"#), 422);
+        assert_eq!(status(r#"{"angle": -1}"#), 422);
+        assert_eq!(status(r#"{"angle": "90"}"#), 400);
+        assert_eq!(status(r#"{"angle": 90, "speed": 1}"#), 400);
+        assert_eq!(status(""), 400);
+        assert_eq!(api_status(Method::Put, "/api/servo/2", "{}"), 404);
+        assert_eq!(api_status(Method::Put, "/api/servo/x", "{}"), 404);
+    }
+
+    #[test]
+    fn stepper_test() {
+        let mut api = api();
+        let response = api.handle(Method::Post, "/api/stepper/0/move", br#"{"steps": -512}"#);
+        assert_eq!(response.status, 202);
+        assert_eq!(
+            response.body,
+            r#"{"position":0,"target":-512,"moving":true}"#
+        );
+        let response = api.handle(Method::Post, "/api/stepper/0/move", br#"{"steps": 1}"#);
+        assert_eq!(response.status, 409);
+        assert_eq!(response.body, r#"{"error":"stepper is moving"}"#);
+        assert_eq!(
+            api.handle(Method::Post, "/api/stepper/0/stop", b"").status,
+            200
+        );
+
+        let status = |body: &str| api_status(Method::Post, "/api/stepper/1/move", body);
+        assert_eq!(status(r#"{"steps": 512, "speed": 500}"#), 202);
+        assert_eq!(status(r#"{"steps": 0}"#), 422);
+        assert_eq!(status(r#"{"steps": 70000}"#), 422);
+        assert_eq!(status(r#"{"steps": 512, "speed": 0}"#), 422);
+        assert_eq!(status(r#"{"steps": 512, "speed": 501}"#), 422);
+        assert_eq!(status(r#"{"steps": 1.5}"#), 400);
+        assert_eq!(
+            status(&format!(r#"{{"steps": 1{}}}"#, " ".repeat(300))),
+            413
+        );
+    }
+
+    #[test]
+    fn routing_test() {
+        let mut api = api();
+        let response = api.handle(Method::Get, "/api/sensors", b"");
+        assert_eq!(response.status, 200);
+        assert!(response.body.contains(r#""temperature":21.5"#));
+
+        let response = api.handle(Method::Get, "/api/status/", b"");
+        assert_eq!(response.status, 200);
+        assert!(response.body.contains(r#""servos":2,"steppers":2"#));
+
+        let response = api.handle(Method::Delete, "/api/servo/0", b"");
+        assert_eq!(response.status, 405);
+        assert_eq!(response.allow, Some("GET, PUT"));
+        assert_eq!(api_status(Method::Get, "/api/stepper/0/move", ""), 405);
+        assert_eq!(api_status(Method::Get, "/api/unknown", ""), 404);
+        assert_eq!(api_status(Method::Get, "/api/stepper/0/jump", ""), 404);
+        assert_eq!(api_status(Method::Get, "/status", ""), 404);
+    }
+
+    fn api_status(method: Method, uri: &str, body: &str) -> u16 {
+        api().handle(method, uri, body.as_bytes()).status
+    }
+}
@@ -0,0 +1,199 @@
+//! [`StepperControl`] of `ULN2003` motors, the steps are made by a background thread.
+//!
+//! Moves are relative and don't queue: a moving motor rejects the next move until it's
+//! done or stopped. Coils are released at the end of each move.
+
+use super::{ApiError, StepperControl, StepperState};
+use crate::lock_recover;
+use esp_idf_svc::hal::delay::{Ets, FreeRtos, TICK_PERIOD_MS};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use uln2003::{Direction, StepperMotor};
+
+const STACK_SIZE: usize = 4096;
+/// Idle motors are checked for new moves this often.
+const IDLE_POLL: Duration = Duration::from_millis(20);
+
+#[derive(Debug, Clone, Copy)]
+struct Motion {
+    position: i64,
+    target: i64,
+    interval: Duration,
+    next_step: Instant,
+}
+
+impl Motion {
+    fn new(now: Instant) -> Self {
+        Motion {
+            position: 0,
+            target: 0,
+            interval: Duration::ZERO,
+            next_step: now,
+        }
+    }
+
+    fn is_moving(&self) -> bool {
+        self.position != self.target
+    }
+
+    fn start(&mut self, steps: i32, speed: u32, now: Instant) -> Result<(), ApiError> {
+        if self.is_moving() {
+            return Err(ApiError::Busy("stepper is moving, stop it first"));
+        }
+        self.target = self.position + steps as i64;
+        self.interval = Duration::from_secs(1) / speed.max(1);
+        self.next_step = now;
+        Ok(())
+    }
+
+    fn stop(&mut self) {
+        self.target = self.position;
+    }
+
+    /// Direction of the step due at `now`, the step is counted as made.
+    fn step_due(&mut self, now: Instant) -> Option<Direction> {
+        if !self.is_moving() || now < self.next_step {
+            return None;
+        }
+        // late steps aren't caught up, the motor would skip them
+        self.next_step = now + self.interval;
+        if self.target > self.position {
+            self.position += 1;
+            Some(Direction::Normal)
+        } else {
+            self.position -= 1;
+            Some(Direction::Reverse)
+        }
+    }
+
+    /// Time until the next step, `None` when idle.
+    fn wait(&self, now: Instant) -> Option<Duration> {
+        self.is_moving()
+            .then(|| self.next_step.saturating_duration_since(now))
+    }
+
+    fn state(&self) -> StepperState {
+        StepperState {
+            position: self.position,
+            target: self.target,
+            moving: self.is_moving(),
+        }
+    }
+}
+
+/// Cheap to clone handle to the motors of [`spawn`].
+#[derive(Debug, Clone)]
+pub struct Steppers(Arc<Mutex<Vec<Motion>>>);
+
+impl Steppers {
+    fn lock(&self) -> MutexGuard<'_, Vec<Motion>> {
+        lock_recover(&self.0)
+    }
+}
+
+impl StepperControl for Steppers {
+    fn count(&self) -> usize {
+        self.lock().len()
+    }
+
+    fn state(&self, id: usize) -> StepperState {
+        self.lock()[id].state()
+    }
+
+    fn start_move(&mut self, id: usize, steps: i32, speed: u32) -> Result<(), ApiError> {
+        self.lock()[id].start(steps, speed, Instant::now())
+    }
+
+    fn stop(&mut self, id: usize) -> Result<(), ApiError> {
+        self.lock()[id].stop();
+        Ok(())
+    }
+}
+
+/// Moves `motors` in the background, ids are indexes of `motors`.
+pub fn spawn<M>(mut motors: Vec<M>) -> std::io::Result<(Steppers, JoinHandle<()>)>
+where
+    M: StepperMotor<u32> + Send + 'static,
+{
+    let now = Instant::now();
+    let steppers = Steppers(Arc::new(Mutex::new(vec![Motion::new(now); motors.len()])));
+    let motions = steppers.clone();
+
+    let handle = std::thread::Builder::new()
+        .name("steppers".to_string())
+        .stack_size(STACK_SIZE)
+        .spawn(move || {
+            let mut energized = vec![false; motors.len()];
+            loop {
+                let now = Instant::now();
+                let wait = {
+                    let mut motions = motions.lock();
+                    let steps = motors.iter_mut().zip(motions.iter_mut());
+                    for (id, (motor, motion)) in steps.enumerate() {
+                        if let Some(direction) = motion.step_due(now) {
+                            motor.set_direction(direction);
+                            energized[id] = true;
+                            if motor.step().is_err() {
+                                log::error!("Stepper {id}: step failed, stopped");
+                                motion.stop();
+                            }
+                        }
+                        if !motion.is_moving() && energized[id] {
+                            // released coils don't heat, 28byj-48 holds by its gearbox
+                            energized[id] = motor.stop().is_err();
+                        }
+                    }
+                    motions.iter().filter_map(|m| m.wait(now)).min()
+                };
+                let wait = wait.unwrap_or(IDLE_POLL).min(IDLE_POLL);
+                sleep(wait);
+            }
+        })?;
+
+    Ok((steppers, handle))
+}
+
+/// Whole ticks are slept and only the remainder is busy, the IDLE task must run or the
+/// task watchdog fires. Waits shorter than a tick sleep one tick, set
+/// `CONFIG_FREERTOS_HZ=1000` for speeds over 100 steps per second.
+fn sleep(wait: Duration) {
+    let tick = Duration::from_millis(TICK_PERIOD_MS as u64);
+    if wait < tick {
+        FreeRtos::delay_ms(TICK_PERIOD_MS);
+        return;
+    }
+    let ticks = (wait.as_micros() / tick.as_micros()) as u32;
+    FreeRtos::delay_ms(ticks * TICK_PERIOD_MS);
+    Ets::delay_us((wait - tick * ticks).as_micros() as u32);
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::rest_api::steppers::Motion;
+    use std::time::{Duration, Instant};
+    use uln2003::Direction;
+
+    #[test]
+    fn motion_test() {
+        let now = Instant::now();
+        let mut motion = Motion::new(now);
+        assert!(motion.step_due(now).is_none());
+        assert_eq!(motion.wait(now), None);
+
+        motion.start(-2, 100, now).unwrap();
+        assert!(motion.start(1, 100, now).is_err());
+        assert!(matches!(motion.step_due(now), Some(Direction::Reverse)));
+        assert_eq!(motion.wait(now), Some(Duration::from_millis(10)));
+        assert!(motion.step_due(now + Duration::from_millis(5)).is_none());
+        assert!(motion.step_due(now + Duration::from_millis(10)).is_some());
+        assert_eq!(motion.state().position, -2);
+        assert!(!motion.state().moving);
+
+        motion.start(5, 100, now).unwrap();
+        assert!(matches!(motion.step_due(now), Some(Direction::Normal)));
+        motion.stop();
+        assert_eq!((motion.position, motion.target), (-1, -1));
+        assert!(motion.step_due(now + Duration::from_secs(1)).is_none());
+    }
+}
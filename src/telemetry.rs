@@ -0,0 +1,535 @@
+//! Live telemetry over WebSocket, JSON frames out and control commands in.
+//!
+//! The control loop calls [`Telemetry::publish`] as often as it likes: the frame is built
+//! and serialised only when some client is due for one. Each client has its own sender
+//! thread and a one frame outbox, a newer frame replaces the unsent one, so a slow client
+//! only gets fewer frames and never blocks the loop or the other clients.
+//!
+//! Clients send [`Command`]s as JSON text, e.g. `{"type": "servo", "id": 0, "angle": 90}`.
+//! Device commands are queued for the control loop, see [`Telemetry::new`], invalid ones
+//! are answered with `{"error": "..."}`. Needs `CONFIG_HTTPD_WS_SUPPORT`.
+
+use crate::lock_recover;
+use crate::rest_api::{self, ApiError, SensorReading, ServoControl, StepperControl, StepperState};
+use embedded_svc::ws::{FrameType, Sender};
+use esp_idf_svc::http::server::ws::EspHttpWsConnection;
+use esp_idf_svc::http::server::EspHttpServer;
+use esp_idf_svc::sys::EspError;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Only sending, 4K is plenty.
+const STACK_SIZE: usize = 4096;
+/// Longer messages from clients close the connection.
+pub const MAX_MESSAGE_LEN: usize = 256;
+pub const MAX_RATE_HZ: u32 = 50;
+/// Unsent replies of a client, the oldest are dropped.
+const MAX_REPLIES: usize = 4;
+/// Closed outboxes are noticed at least this often.
+const IDLE_WAIT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+pub struct TelemetryConfig {
+    /// Frames per second of a new client, it can change its own with [`Command::Rate`].
+    pub rate_hz: u32,
+    /// Each client costs a thread, more are rejected.
+    pub max_clients: usize,
+    /// Device commands waiting for the control loop, more are rejected.
+    pub command_queue: usize,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        TelemetryConfig {
+            rate_hz: 10,
+            max_clients: 3,
+            command_queue: 8,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TelemetryError {
+    TooManyClients,
+    MessageTooLong,
+    Thread(std::io::Error),
+    Connection(EspError),
+}
+
+impl fmt::Display for TelemetryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TelemetryError::TooManyClients => f.write_str("too many telemetry clients"),
+            TelemetryError::MessageTooLong => {
+                write!(f, "message is longer than {MAX_MESSAGE_LEN} bytes")
+            }
+            TelemetryError::Thread(err) => write!(f, "client thread isn't started: {err}"),
+            TelemetryError::Connection(err) => write!(f, "WebSocket failed: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for TelemetryError {}
+
+impl From<EspError> for TelemetryError {
+    fn from(err: EspError) -> Self {
+        TelemetryError::Connection(err)
+    }
+}
+
+/// Frame of the board devices, the control loop fills what it has.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Frame {
+    pub uptime_ms: u64,
+    /// Normalized joystick deflection, -1.0..=1.0.
+    pub axes: Vec<f32>,
+    /// Servo angles, degrees.
+    pub servos: Vec<f64>,
+    pub steppers: Vec<StepperState>,
+    pub temperatures: Vec<SensorReading>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Command {
+    Servo {
+        id: usize,
+        angle: f64,
+    },
+    StepperMove {
+        id: usize,
+        steps: i32,
+        speed: Option<u32>,
+    },
+    StepperStop {
+        id: usize,
+    },
+    /// Frames per second of the sending client, handled by [`Telemetry`] itself.
+    Rate {
+        hz: u32,
+    },
+}
+
+impl Command {
+    /// Same checks as the REST API, see [`rest_api::set_servo`].
+    pub fn apply(
+        &self,
+        servos: &mut impl ServoControl,
+        steppers: &mut impl StepperControl,
+    ) -> Result<(), ApiError> {
+        match *self {
+            Command::Servo { id, angle } => rest_api::set_servo(servos, id, angle),
+            Command::StepperMove { id, steps, speed } => {
+                rest_api::move_stepper(steppers, id, steps, speed)
+            }
+            Command::StepperStop { id } => rest_api::stop_stepper(steppers, id),
+            Command::Rate { .. } => Ok(()),
+        }
+    }
+}
+
+/// Device command with the WebSocket session it came from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientCommand {
+    pub session: i32,
+    pub command: Command,
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+/// Cheap to clone handle to the connected clients.
+#[derive(Clone)]
+pub struct Telemetry(Arc<Shared>);
+
+struct Shared {
+    config: TelemetryConfig,
+    clients: Mutex<Vec<Client>>,
+    commands: SyncSender<ClientCommand>,
+    last_frame: Mutex<Option<Instant>>,
+}
+
+struct Client {
+    session: i32,
+    outbox: Arc<Outbox>,
+}
+
+impl Telemetry {
+    /// Device commands of clients come out of the receiver, Rate commands don't.
+    pub fn new(config: TelemetryConfig) -> (Self, Receiver<ClientCommand>) {
+        let (commands, receiver) = mpsc::sync_channel(config.command_queue);
+        let shared = Shared {
+            config,
+            clients: Mutex::new(Vec::new()),
+            commands,
+            last_frame: Mutex::new(None),
+        };
+        (Telemetry(Arc::new(shared)), receiver)
+    }
+
+    pub fn clients(&self) -> usize {
+        self.lock_clients().len()
+    }
+
+    /// Serialises the frame of `build` for the clients, unless no client is due for one.
+    /// Returns `true` if the frame was built.
+    pub fn publish<T: Serialize>(&self, build: impl FnOnce() -> T) -> bool {
+        let clients = self.lock_clients();
+        let Some(interval) = clients.iter().map(|c| c.outbox.interval()).min() else {
+            return false;
+        };
+        {
+            let now = Instant::now();
+            let mut last_frame = lock_recover(&self.0.last_frame);
+            if last_frame.is_some_and(|last| now < last + interval) {
+                return false;
+            }
+            *last_frame = Some(now);
+        }
+        let frame: Arc<str> = match serde_json::to_string(&build()) {
+            Ok(frame) => frame.into(),
+            Err(err) => {
+                log::warn!("Telemetry: frame isn't serialised: {err}");
+                return false;
+            }
+        };
+        for client in clients.iter() {
+            client.outbox.push_frame(frame.clone());
+        }
+        true
+    }
+
+    /// Tells the client why its command failed, e.g. after [`Command::apply`].
+    pub fn reply_error(&self, session: i32, err: &dyn fmt::Display) {
+        let reply = serde_json::to_string(&ErrorBody {
+            error: err.to_string(),
+        })
+        .unwrap_or_default();
+        if let Some(client) = self.lock_clients().iter().find(|c| c.session == session) {
+            client.outbox.push_reply(reply);
+        }
+    }
+
+    /// Starts the sender thread of the new client.
+    pub fn connect<S>(&self, session: i32, mut sender: S) -> Result<(), TelemetryError>
+    where
+        S: Sender + Send + 'static,
+    {
+        let mut clients = self.lock_clients();
+        if clients.len() >= self.0.config.max_clients {
+            log::warn!("Telemetry: client {session} rejected, too many clients");
+            return Err(TelemetryError::TooManyClients);
+        }
+        let outbox = Arc::new(Outbox::new(rate_interval(self.0.config.rate_hz)));
+        let client_outbox = outbox.clone();
+        let telemetry = self.clone();
+        std::thread::Builder::new()
+            .name(format!("telemetry_{session}"))
+            .stack_size(STACK_SIZE)
+            .spawn(move || {
+                while let Some(message) = client_outbox.next() {
+                    if let Err(err) = sender.send(FrameType::Text(false), message.as_bytes()) {
+                        log::info!("Telemetry: client {session} send failed: {err:?}");
+                        break;
+                    }
+                }
+                telemetry.disconnect(session);
+            })
+            .map_err(TelemetryError::Thread)?;
+        clients.push(Client { session, outbox });
+        log::info!("Telemetry: client {session} connected");
+        Ok(())
+    }
+
+    /// Stops the sender thread, unknown sessions are ignored.
+    pub fn disconnect(&self, session: i32) {
+        let mut clients = self.lock_clients();
+        if let Some(pos) = clients.iter().position(|c| c.session == session) {
+            let client = clients.swap_remove(pos);
+            let dropped = client.outbox.close();
+            log::info!("Telemetry: client {session} disconnected, {dropped} frames skipped");
+        }
+    }
+
+    /// Handles a text message of the client, bad ones are answered with an error.
+    pub fn receive(&self, session: i32, message: &[u8]) {
+        let result = match serde_json::from_slice::<Command>(message) {
+            Ok(Command::Rate { hz }) if (1..=MAX_RATE_HZ).contains(&hz) => {
+                if let Some(client) = self.lock_clients().iter().find(|c| c.session == session) {
+                    client.outbox.set_interval(rate_interval(hz));
+                }
+                Ok(())
+            }
+            Ok(Command::Rate { .. }) => Err(ApiError::Invalid("rate should be 1-50 Hz")),
+            Ok(command) => match self.0.commands.try_send(ClientCommand { session, command }) {
+                Ok(()) => Ok(()),
+                Err(TrySendError::Full(_)) => Err(ApiError::Busy("too many commands, retry")),
+                Err(TrySendError::Disconnected(_)) => Err(ApiError::Busy("commands aren't read")),
+            },
+            Err(err) => Err(ApiError::BadRequest(err.to_string())),
+        };
+        if let Err(err) = result {
+            self.reply_error(session, &err);
+        }
+    }
+
+    fn lock_clients(&self) -> MutexGuard<'_, Vec<Client>> {
+        lock_recover(&self.0.clients)
+    }
+}
+
+/// Registers the WebSocket handler at `uri`, e.g. `/ws`.
+pub fn serve(
+    server: &mut EspHttpServer<'static>,
+    uri: &str,
+    telemetry: Telemetry,
+) -> Result<(), EspError> {
+    server.ws_handler(uri, move |ws: &mut EspHttpWsConnection| {
+        if ws.is_new() {
+            return telemetry.connect(ws.session(), ws.create_detached_sender()?);
+        }
+        if ws.is_closed() {
+            telemetry.disconnect(ws.session());
+            return Ok(());
+        }
+        let mut buf = [0u8; MAX_MESSAGE_LEN];
+        let (frame_type, len) = ws.recv(&mut buf)?;
+        if len > buf.len() {
+            // not read, the connection is closed by the error
+            return Err(TelemetryError::MessageTooLong);
+        }
+        if let FrameType::Text(_) = frame_type {
+            telemetry.receive(ws.session(), &buf[..len]);
+        }
+        Ok(())
+    })?;
+    Ok(())
+}
+
+fn rate_interval(hz: u32) -> Duration {
+    Duration::from_secs(1) / hz.clamp(1, MAX_RATE_HZ)
+}
+
+/// Latest frame and replies of one client.
+struct Outbox {
+    state: Mutex<OutboxState>,
+    ready: Condvar,
+}
+
+struct OutboxState {
+    frame: Option<Arc<str>>,
+    replies: VecDeque<String>,
+    interval: Duration,
+    next_frame: Instant,
+    /// Frames replaced before they were sent.
+    dropped: u32,
+    closed: bool,
+}
+
+impl Outbox {
+    fn new(interval: Duration) -> Self {
+        Outbox {
+            state: Mutex::new(OutboxState {
+                frame: None,
+                replies: VecDeque::new(),
+                interval,
+                next_frame: Instant::now(),
+                dropped: 0,
+                closed: false,
+            }),
+            ready: Condvar::new(),
+        }
+    }
+
+    fn interval(&self) -> Duration {
+        lock_recover(&self.state).interval
+    }
+
+    fn set_interval(&self, interval: Duration) {
+        lock_recover(&self.state).interval = interval;
+    }
+
+    fn push_frame(&self, frame: Arc<str>) {
+        let mut state = lock_recover(&self.state);
+        if state.frame.replace(frame).is_some() {
+            state.dropped += 1;
+        }
+        self.ready.notify_one();
+    }
+
+    fn push_reply(&self, reply: String) {
+        let mut state = lock_recover(&self.state);
+        if state.replies.len() >= MAX_REPLIES {
+            state.replies.pop_front();
+        }
+        state.replies.push_back(reply);
+        self.ready.notify_one();
+    }
+
+    /// Returns the number of dropped frames.
+    fn close(&self) -> u32 {
+        let mut state = lock_recover(&self.state);
+        state.closed = true;
+        self.ready.notify_one();
+        state.dropped
+    }
+
+    /// Waits for the next reply or frame, frames no more often than the interval.
+    /// `None` once closed.
+    fn next(&self) -> Option<Arc<str>> {
+        let mut state = lock_recover(&self.state);
+        loop {
+            if state.closed {
+                return None;
+            }
+            if let Some(reply) = state.replies.pop_front() {
+                return Some(reply.into());
+            }
+            let now = Instant::now();
+            let wait = match state.frame {
+                Some(_) if now >= state.next_frame => {
+                    state.next_frame = now + state.interval;
+                    return state.frame.take();
+                }
+                Some(_) => state.next_frame - now,
+                None => IDLE_WAIT,
+            };
+            state = self
+                .ready
+                .wait_timeout(state, wait)
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .0;
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::telemetry::{ClientCommand, Command, Frame, Telemetry, TelemetryConfig};
+    use embedded_svc::ws::{ErrorType, FrameType, Sender};
+    use std::sync::mpsc::{self, Receiver};
+    use std::time::Duration;
+
+    /// Forwards sent messages, `delay` makes it a slow client.
+    struct FakeSender {
+        sent: mpsc::Sender<String>,
+        delay: Duration,
+    }
+
+    impl ErrorType for FakeSender {
+        type Error = mpsc::SendError<String>;
+    }
+
+    impl Sender for FakeSender {
+        fn send(&mut self, _frame_type: FrameType, frame_data: &[u8]) -> Result<(), Self::Error> {
+            std::thread::sleep(self.delay);
+            self.sent
+                .send(String::from_utf8(frame_data.to_vec()).unwrap())
+        }
+    }
+
+    fn client(telemetry: &Telemetry, session: i32, delay: Duration) -> Receiver<String> {
+        let (sent, received) = mpsc::channel();
+        telemetry
+            .connect(session, FakeSender { sent, delay })
+            .unwrap();
+        received
+    }
+
+    fn frame(uptime_ms: u64) -> Frame {
+        Frame {
+            uptime_ms,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn slow_client_test() {
+        let config = TelemetryConfig {
+            rate_hz: 50,
+            max_clients: 2,
+            ..Default::default()
+        };
+        let (telemetry, _commands) = Telemetry::new(config);
+        assert!(!telemetry.publish(|| frame(0)));
+
+        let fast = client(&telemetry, 1, Duration::ZERO);
+        let slow = client(&telemetry, 2, Duration::from_millis(200));
+        let (sent, _) = mpsc::channel();
+        let rejected = telemetry.connect(
+            3,
+            FakeSender {
+                sent,
+                delay: Duration::ZERO,
+            },
+        );
+        assert!(rejected.is_err());
+
+        assert!(telemetry.publish(|| frame(1)));
+        // too soon after the previous frame
+        assert!(!telemetry.publish(|| frame(2)));
+        for uptime_ms in 3..8 {
+            std::thread::sleep(Duration::from_millis(25));
+            assert!(telemetry.publish(|| frame(uptime_ms)));
+        }
+
+        let receive = |client: &Receiver<String>, count| {
+            (0..count)
+                .map(|_| client.recv_timeout(Duration::from_secs(1)).unwrap())
+                .collect::<Vec<_>>()
+        };
+        let fast = receive(&fast, 6);
+        assert!(fast[0].starts_with(r#"{"uptime_ms":1,"axes":[]"#));
+        assert!(fast[5].starts_with(r#"{"uptime_ms":7,"#));
+        // the first frame is being sent, the others replace each other in the outbox
+        let slow = receive(&slow, 2);
+        assert!(slow[0].starts_with(r#"{"uptime_ms":1,"#));
+        assert!(slow[1].starts_with(r#"{"uptime_ms":7,"#));
+
+        telemetry.disconnect(2);
+        assert_eq!(telemetry.clients(), 1);
+    }
+
+    #[test]
+    fn command_test() {
+        let config = TelemetryConfig {
+            command_queue: 1,
+            ..Default::default()
+        };
+        let (telemetry, commands) = Telemetry::new(config);
+        let replies = client(&telemetry, 7, Duration::ZERO);
+
+        telemetry.receive(7, br#"{"type": "servo", "id": 1, "angle": 45.5}"#);
+        assert_eq!(
+            commands.try_recv(),
+            Ok(ClientCommand {
+                session: 7,
+                command: Command::Servo { id: 1, angle: 45.5 }
+            })
+        );
+
+        telemetry.receive(7, br#"{"type": "stepper_stop", "id": 0}"#);
+        telemetry.receive(7, br#"{"type": "stepper_move", "id": 0, "steps": 10}"#);
+        let reply = replies.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(reply, r#"{"error":"too many commands, retry"}"#);
+        assert_eq!(
+            commands.try_iter().map(|c| c.command).collect::<Vec<_>>(),
+            vec![Command::StepperStop { id: 0 }]
+        );
+
+        telemetry.receive(7, br#"{"type": "servo", "id": 0, "speed": 1}"#);
+        let reply = replies.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert!(reply.starts_with(r#"{"error":"invalid JSON: unknown field `speed`"#));
+        telemetry.receive(7, br#"{"type": "rate", "hz": 0}"#);
+        let reply = replies.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(reply, r#"{"error":"rate should be 1-50 Hz"}"#);
+        telemetry.receive(7, br#"{"type": "rate", "hz": 1}"#);
+        assert!(commands.try_recv().is_err());
+    }
+}
@@ -0,0 +1,579 @@
+//! DS18B20 temperature sensors on the 1-Wire bus that survive loose wires.
+//! DS18S20 and DS1822 are supported as well, other devices are reported and skipped.
+//!
+//! * every read is retried and checked with CRC
+//! * errors are reported per sensor, the sensor goes offline after several failed rounds
+//! * the bus is searched again periodically, so sensors can be added or reconnected on the fly
+//! * resolution is configurable per sensor, conversion wait depends on the highest one
+//! * high/low alarm thresholds and alarm search, see [`alarm`]
+//! * acquisition in the background thread, see [`task`]
+//! * history with min/max/mean/trend statistics and CSV/JSON export, see [`history`]
+
+use alarm::AlarmThresholds;
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+use esp_idf_svc::hal::delay::{Delay, FreeRtos};
+use family::DeviceFamily;
+use one_wire_bus::{Address, OneWire, OneWireError};
+use std::fmt;
+use std::fmt::Debug;
+use std::time::{Duration, Instant};
+
+pub use ds18b20::Resolution;
+
+pub mod alarm;
+pub mod family;
+pub mod history;
+pub mod registry;
+pub mod task;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SensorError {
+    /// Nobody answered the reset pulse, the bus is empty or broken.
+    NoPresence,
+    /// The bus is not pulled up, check the resistor.
+    BusNotHigh,
+    /// Sensor doesn't answer, scratchpad is read as all ones.
+    NotResponding,
+    /// Data is corrupted on the wire.
+    Crc,
+    /// Device answered something unexpected, usually devices were added/removed during search.
+    UnexpectedResponse,
+    /// Device is not a supported temperature sensor, e.g. a DS2413 switch, see [`family`].
+    FamilyCodeMismatch(u8),
+    /// The device family doesn't support the operation.
+    Unsupported,
+    Timeout,
+    /// GPIO error.
+    Pin,
+    /// Config read back from the sensor differs from the written one.
+    VerifyFailed,
+}
+
+impl<E> From<OneWireError<E>> for SensorError {
+    fn from(err: OneWireError<E>) -> Self {
+        match err {
+            OneWireError::BusNotHigh => SensorError::BusNotHigh,
+            OneWireError::PinError(_) => SensorError::Pin,
+            OneWireError::UnexpectedResponse => SensorError::UnexpectedResponse,
+            // only `Ds18b20::new` checks it and the code is not kept, families are checked here
+            OneWireError::FamilyCodeMismatch => SensorError::UnexpectedResponse,
+            OneWireError::CrcMismatch => SensorError::Crc,
+            OneWireError::Timeout => SensorError::Timeout,
+        }
+    }
+}
+
+impl fmt::Display for SensorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            SensorError::NoPresence => "no presence pulse on the bus",
+            SensorError::BusNotHigh => "bus is not pulled up",
+            SensorError::NotResponding => "sensor is not responding",
+            SensorError::Crc => "CRC mismatch",
+            SensorError::UnexpectedResponse => "unexpected response",
+            SensorError::FamilyCodeMismatch(code) => {
+                return write!(
+                    f,
+                    "not a supported temperature sensor, family code 0x{code:02X}"
+                );
+            }
+            SensorError::Unsupported => "not supported by the device",
+            SensorError::Timeout => "timeout",
+            SensorError::Pin => "GPIO error",
+            SensorError::VerifyFailed => "written config is not confirmed",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl std::error::Error for SensorError {}
+
+#[derive(Debug, Clone)]
+pub struct TempSensorsConfig {
+    /// Extra read attempts after a failed read.
+    pub retries: u8,
+    /// Failed rounds in a row after which the sensor is marked offline.
+    pub offline_after: u8,
+    /// How often the bus is searched for new or reconnected sensors.
+    pub rediscover_every: Duration,
+    /// How often sensors are measured, see [`TempSensors::next_measurement_in`].
+    pub sample_period: Duration,
+    /// Resolution written to each discovered sensor, `None` keeps the sensor's own.
+    pub resolution: Option<Resolution>,
+    /// Copy written resolution to sensor EEPROM, so it survives power loss.
+    /// EEPROM wears out, don't enable it if the config is written often.
+    pub persist_resolution: bool,
+}
+
+impl Default for TempSensorsConfig {
+    fn default() -> Self {
+        TempSensorsConfig {
+            retries: 2,
+            offline_after: 3,
+            rediscover_every: Duration::from_secs(60),
+            sample_period: Duration::from_secs(2),
+            resolution: None,
+            persist_resolution: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SensorStatus {
+    Online,
+    /// Last rounds failed, but not enough to give up.
+    Failing {
+        rounds: u8,
+    },
+    /// Not read until found by the next bus search.
+    Offline,
+}
+
+#[derive(Debug)]
+pub struct Sensor {
+    address: Address,
+    family: DeviceFamily,
+    status: SensorStatus,
+    last_error: Option<SensorError>,
+    last_temp: Option<f32>,
+    resolution: Option<Resolution>,
+}
+
+impl Sensor {
+    fn new(address: Address) -> Self {
+        Sensor {
+            address,
+            family: DeviceFamily::from_address(&address),
+            status: SensorStatus::Online,
+            last_error: None,
+            last_temp: None,
+            resolution: None,
+        }
+    }
+
+    pub fn address(&self) -> Address {
+        self.address
+    }
+
+    pub fn family(&self) -> DeviceFamily {
+        self.family
+    }
+
+    pub fn status(&self) -> SensorStatus {
+        self.status
+    }
+
+    pub fn last_error(&self) -> Option<SensorError> {
+        self.last_error
+    }
+
+    /// Last successfully read temperature in Celsius.
+    pub fn last_temp(&self) -> Option<f32> {
+        self.last_temp
+    }
+
+    /// Resolution from the last read scratchpad.
+    pub fn resolution(&self) -> Option<Resolution> {
+        self.resolution
+    }
+
+    fn update(&mut self, temperature: Result<f32, SensorError>, offline_after: u8) {
+        match temperature {
+            Ok(temp) => {
+                self.status = SensorStatus::Online;
+                self.last_error = None;
+                self.last_temp = Some(temp);
+            }
+            Err(err) => {
+                self.last_error = Some(err);
+                let rounds = match self.status {
+                    SensorStatus::Failing { rounds } => rounds + 1,
+                    _ => 1,
+                };
+                self.status = if rounds >= offline_after {
+                    log::warn!("[{:?}] is offline: {err}", self.address());
+                    SensorStatus::Offline
+                } else {
+                    SensorStatus::Failing { rounds }
+                };
+            }
+        }
+    }
+}
+
+/// Result of one sensor in one measurement round.
+#[derive(Debug, Clone, Copy)]
+pub struct Reading {
+    pub address: Address,
+    pub temperature: Result<f32, SensorError>,
+    pub status: SensorStatus,
+}
+
+pub struct TempSensors<P> {
+    bus: OneWire<P>,
+    delay: Delay,
+    config: TempSensorsConfig,
+    sensors: Vec<Sensor>,
+    unsupported: Vec<Address>,
+    last_discovery: Option<Instant>,
+    last_measurement: Option<Instant>,
+}
+
+/// EEPROM write time, see datasheet.
+const EEPROM_WRITE_MS: u32 = 10;
+
+impl<P, E> TempSensors<P>
+where
+    P: InputPin<Error = E> + OutputPin<Error = E>,
+    E: Debug,
+{
+    /// Takes the pin of the bus, sensors are searched on the first [`Self::measure`].
+    pub fn new(pin: P, config: TempSensorsConfig) -> Result<Self, SensorError> {
+        Ok(TempSensors {
+            bus: OneWire::new(pin)?,
+            delay: Delay::new(10_000),
+            config,
+            sensors: Vec::new(),
+            unsupported: Vec::new(),
+            last_discovery: None,
+            last_measurement: None,
+        })
+    }
+
+    pub fn sensors(&self) -> &[Sensor] {
+        &self.sensors
+    }
+
+    /// Devices found by the last search which are not temperature sensors.
+    pub fn unsupported(&self) -> &[Address] {
+        &self.unsupported
+    }
+
+    /// Searches the bus: new sensors are added, found offline sensors are back online,
+    /// not found sensors are marked offline. Returns the number of found sensors.
+    pub fn discover(&mut self) -> Result<usize, SensorError> {
+        self.last_discovery = Some(Instant::now());
+
+        let mut found = Vec::new();
+        for address in self.bus.devices(false, &mut self.delay) {
+            found.push(address?);
+        }
+
+        // new sensors and the ones back, maybe after a power cycle to the default 12-bit
+        let mut configure = Vec::new();
+        for sensor in self.sensors.iter_mut() {
+            if !found.contains(&sensor.address()) {
+                if sensor.status != SensorStatus::Offline {
+                    log::warn!("[{:?}] is gone", sensor.address());
+                }
+                sensor.status = SensorStatus::Offline;
+                sensor.last_error = Some(SensorError::NotResponding);
+            } else if sensor.status == SensorStatus::Offline {
+                log::info!("[{:?}] is back", sensor.address());
+                sensor.status = SensorStatus::Online;
+                sensor.last_error = None;
+                // unknown until set or read, the conversion wait is the longest meanwhile
+                sensor.resolution = None;
+                configure.push(sensor.address());
+            }
+        }
+
+        let mut unsupported = Vec::new();
+        for address in &found {
+            if self.sensors.iter().any(|s| s.address() == *address) {
+                continue;
+            }
+            let family = DeviceFamily::from_address(address);
+            if !family.is_temperature_sensor() {
+                if !self.unsupported.contains(address) {
+                    log::warn!("[{:?}] {family} is not supported, skipped", address);
+                }
+                unsupported.push(*address);
+                continue;
+            }
+            log::info!("[{:?}] {family} found", address);
+            self.sensors.push(Sensor::new(*address));
+            configure.push(*address);
+        }
+
+        self.unsupported = unsupported;
+
+        if let Some(resolution) = self.config.resolution {
+            let persist = self.config.persist_resolution;
+            for address in &configure {
+                if !DeviceFamily::from_address(address).has_config_register() {
+                    continue;
+                }
+                if let Err(err) = self.set_resolution(address, resolution, persist) {
+                    log::warn!("[{:?}] can't set resolution: {err}", address);
+                }
+            }
+        }
+
+        Ok(found.len())
+    }
+
+    /// Writes resolution to the sensor scratchpad, alarm thresholds are kept as is.
+    /// With `persist` the config is also copied to the sensor EEPROM.
+    pub fn set_resolution(
+        &mut self,
+        address: &Address,
+        resolution: Resolution,
+        persist: bool,
+    ) -> Result<(), SensorError> {
+        if !check_family(address)?.has_config_register() {
+            return Err(SensorError::Unsupported);
+        }
+        let scratchpad = self.read_scratchpad(address)?;
+        self.write_config(
+            address,
+            &[scratchpad[2], scratchpad[3], resolution as u8],
+            persist,
+        )?;
+
+        if let Some(sensor) = self.sensors.iter_mut().find(|s| s.address == *address) {
+            sensor.resolution = Some(resolution);
+        }
+        log::info!("[{:?}] resolution set to {:?}", address, resolution);
+        Ok(())
+    }
+
+    /// Writes alarm thresholds to the sensor scratchpad, resolution is kept as is.
+    /// With `persist` the config is also copied to the sensor EEPROM.
+    pub fn set_alarm(
+        &mut self,
+        address: &Address,
+        thresholds: AlarmThresholds,
+        persist: bool,
+    ) -> Result<(), SensorError> {
+        let scratchpad = self.read_scratchpad(address)?;
+        let (th, tl) = thresholds.to_registers();
+        if DeviceFamily::from_address(address).has_config_register() {
+            self.write_config(address, &[th, tl, scratchpad[4]], persist)?;
+        } else {
+            self.write_config(address, &[th, tl], persist)?;
+        }
+        log::info!(
+            "[{:?}] alarm set to < {} or >= {}",
+            address,
+            thresholds.low(),
+            thresholds.high()
+        );
+        Ok(())
+    }
+
+    /// Reads alarm thresholds from the sensor scratchpad.
+    pub fn alarm_thresholds(&mut self, address: &Address) -> Result<AlarmThresholds, SensorError> {
+        let scratchpad = self.read_scratchpad(address)?;
+        Ok(AlarmThresholds::from_registers(
+            scratchpad[2],
+            scratchpad[3],
+        ))
+    }
+
+    /// Finds sensors which last converted temperature is outside of their thresholds.
+    /// Should be called after [`Self::measure`], the flag is updated by conversion.
+    pub fn alarm_search(&mut self) -> Result<Vec<Address>, SensorError> {
+        let mut alarming = Vec::new();
+        for address in self.bus.devices(true, &mut self.delay) {
+            alarming.push(address?);
+        }
+        Ok(alarming)
+    }
+
+    /// Time the sensors need to convert temperature, the slowest sensor defines it.
+    pub fn conversion_time(&self) -> Duration {
+        let millis = self
+            .sensors
+            .iter()
+            .filter(|s| s.status != SensorStatus::Offline)
+            .map(|s| s.family.conversion_time_ms(s.resolution))
+            .max()
+            .unwrap_or(0);
+        Duration::from_millis(millis as u64)
+    }
+
+    /// Time left until the next measurement according to `sample_period`.
+    pub fn next_measurement_in(&self) -> Duration {
+        self.last_measurement.map_or(Duration::ZERO, |at| {
+            self.config.sample_period.saturating_sub(at.elapsed())
+        })
+    }
+
+    /// Measures all online sensors at once, searches the bus before if it's time to.
+    /// Blocks for the [`Self::conversion_time`].
+    pub fn measure(&mut self) -> Vec<Reading> {
+        self.last_measurement = Some(Instant::now());
+        let discovery_due = self
+            .last_discovery
+            .map_or(true, |at| at.elapsed() >= self.config.rediscover_every);
+        if discovery_due {
+            if let Err(err) = self.discover() {
+                log::warn!("Bus search failed: {err}");
+            }
+        }
+
+        if let Err(err) = self.start_conversion() {
+            log::warn!("Can't start conversion: {err}");
+            return self.fail_all(err);
+        }
+        FreeRtos::delay_ms(self.conversion_time().as_millis() as u32);
+
+        let mut readings = Vec::with_capacity(self.sensors.len());
+        for idx in 0..self.sensors.len() {
+            if self.sensors[idx].status == SensorStatus::Offline {
+                continue;
+            }
+            let temperature = self.read_with_retries(idx);
+            let sensor = &mut self.sensors[idx];
+            sensor.update(temperature, self.config.offline_after);
+            readings.push(Reading {
+                address: sensor.address(),
+                temperature,
+                status: sensor.status,
+            });
+        }
+        readings
+    }
+
+    fn start_conversion(&mut self) -> Result<(), SensorError> {
+        if !self.bus.reset(&mut self.delay)? {
+            return Err(SensorError::NoPresence);
+        }
+        self.bus.skip_address(&mut self.delay)?;
+        self.bus
+            .write_byte(ds18b20::commands::CONVERT_TEMP, &mut self.delay)?;
+        Ok(())
+    }
+
+    fn read_with_retries(&mut self, idx: usize) -> Result<f32, SensorError> {
+        let address = self.sensors[idx].address();
+        let mut result = self.read_scratchpad(&address);
+        for attempt in 1..=self.config.retries {
+            match result {
+                Ok(_) => break,
+                Err(err) => {
+                    log::debug!("[{address:?}] read failed ({err}), retry {attempt}");
+                    result = self.read_scratchpad(&address);
+                }
+            }
+        }
+
+        let scratchpad = result?;
+        let sensor = &mut self.sensors[idx];
+        sensor.resolution = Some(sensor.family.resolution(&scratchpad));
+        Ok(sensor.family.parse_temperature(&scratchpad))
+    }
+
+    /// Writes TH, TL and config (if the device has it) registers and checks them by reading back.
+    fn write_config(
+        &mut self,
+        address: &Address,
+        config: &[u8],
+        persist: bool,
+    ) -> Result<(), SensorError> {
+        self.bus.send_command(
+            ds18b20::commands::WRITE_SCRATCHPAD,
+            Some(address),
+            &mut self.delay,
+        )?;
+        self.bus.write_bytes(config, &mut self.delay)?;
+
+        let written = self.read_scratchpad(address)?;
+        if written[2..2 + config.len()] != *config {
+            return Err(SensorError::VerifyFailed);
+        }
+
+        if persist {
+            self.bus.send_command(
+                ds18b20::commands::COPY_SCRATCHPAD,
+                Some(address),
+                &mut self.delay,
+            )?;
+            FreeRtos::delay_ms(EEPROM_WRITE_MS);
+        }
+        Ok(())
+    }
+
+    /// Reads and checks the scratchpad of one sensor.
+    fn read_scratchpad(&mut self, address: &Address) -> Result<[u8; 9], SensorError> {
+        check_family(address)?;
+        if !self.bus.reset(&mut self.delay)? {
+            return Err(SensorError::NoPresence);
+        }
+        self.bus.match_address(address, &mut self.delay)?;
+        self.bus
+            .write_byte(ds18b20::commands::READ_SCRATCHPAD, &mut self.delay)?;
+        let mut scratchpad = [0u8; 9];
+        self.bus.read_bytes(&mut scratchpad, &mut self.delay)?;
+        check_scratchpad(&scratchpad)?;
+        Ok(scratchpad)
+    }
+
+    fn fail_all(&mut self, err: SensorError) -> Vec<Reading> {
+        let offline_after = self.config.offline_after;
+        self.sensors
+            .iter_mut()
+            .filter(|s| s.status != SensorStatus::Offline)
+            .map(|sensor| {
+                sensor.update(Err(err), offline_after);
+                Reading {
+                    address: sensor.address(),
+                    temperature: Err(err),
+                    status: sensor.status,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Other devices on the bus don't have the temperature scratchpad.
+fn check_family(address: &Address) -> Result<DeviceFamily, SensorError> {
+    let family = DeviceFamily::from_address(address);
+    if family.is_temperature_sensor() {
+        Ok(family)
+    } else {
+        Err(SensorError::FamilyCodeMismatch(address.family_code()))
+    }
+}
+
+fn check_scratchpad(scratchpad: &[u8; 9]) -> Result<(), SensorError> {
+    if scratchpad.iter().all(|b| *b == 0xFF) {
+        return Err(SensorError::NotResponding);
+    }
+    one_wire_bus::crc::check_crc8::<()>(scratchpad)?;
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::temp_sensor::family::DeviceFamily;
+    use crate::temp_sensor::{check_family, check_scratchpad, SensorError};
+    use one_wire_bus::Address;
+
+    #[test]
+    fn check_scratchpad_test() {
+        let mut scratchpad = [0x91, 0x01, 0x4B, 0x46, 0x7F, 0xFF, 0x0F, 0x10, 0x00];
+        scratchpad[8] = one_wire_bus::crc::crc8(&scratchpad[..8]);
+        assert_eq!(check_scratchpad(&scratchpad), Ok(()));
+
+        scratchpad[0] ^= 1;
+        assert_eq!(check_scratchpad(&scratchpad), Err(SensorError::Crc));
+        assert_eq!(
+            check_scratchpad(&[0xFF; 9]),
+            Err(SensorError::NotResponding)
+        );
+    }
+
+    #[test]
+    fn check_family_test() {
+        let address = |code: u64| Address(0x4B0000000F2D5A00 | code);
+        assert_eq!(check_family(&address(0x22)), Ok(DeviceFamily::Ds1822));
+        let err = check_family(&address(0x3A)).unwrap_err();
+        assert_eq!(err, SensorError::FamilyCodeMismatch(0x3A));
+        assert_eq!(
+            err.to_string(),
+            "not a supported temperature sensor, family code 0x3A"
+        );
+    }
+}
@@ -0,0 +1,198 @@
+//! High/low alarm thresholds (TH/TL registers) of DS18B20.
+//!
+//! Sensor compares whole degrees of every converted temperature with TH/TL and sets alarm flag,
+//! then alarm search ([`super::TempSensors::alarm_search`]) finds only alarming sensors.
+//! [`AlarmMonitor`] turns search results into raise/clear events for the application.
+
+use one_wire_bus::Address;
+use std::fmt;
+
+/// Temperature range of DS18B20, Celsius.
+pub const MIN_TEMP: f32 = -55.0;
+pub const MAX_TEMP: f32 = 125.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ThresholdError {
+    NotANumber,
+    /// Outside of the sensor range `MIN_TEMP..=MAX_TEMP`.
+    OutOfRange(f32),
+    /// Low threshold should be below the high one.
+    LowAboveHigh,
+}
+
+impl fmt::Display for ThresholdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ThresholdError::NotANumber => f.write_str("threshold is not a number"),
+            ThresholdError::OutOfRange(value) => {
+                write!(f, "threshold {value} is outside of {MIN_TEMP}..={MAX_TEMP}")
+            }
+            ThresholdError::LowAboveHigh => f.write_str("low threshold is above the high one"),
+        }
+    }
+}
+
+impl std::error::Error for ThresholdError {}
+
+/// Encoded TH/TL registers, only valid ones can be created.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AlarmThresholds {
+    th: i8,
+    tl: i8,
+}
+
+impl AlarmThresholds {
+    /// Alarm is raised when temperature is `>= high` or `< low`.
+    ///
+    /// Registers keep whole degrees, so thresholds are rounded outward: the sensor never
+    /// alarms inside of `low..high`, but may be late by up to 1°C outside of it.
+    pub fn new(low: f32, high: f32) -> Result<Self, ThresholdError> {
+        for value in [low, high] {
+            if value.is_nan() {
+                return Err(ThresholdError::NotANumber);
+            }
+            if !(MIN_TEMP..=MAX_TEMP).contains(&value) {
+                return Err(ThresholdError::OutOfRange(value));
+            }
+        }
+        if low >= high {
+            return Err(ThresholdError::LowAboveHigh);
+        }
+
+        // sensor alarms if `floor(t) >= TH` or `floor(t) <= TL`
+        Ok(AlarmThresholds {
+            th: high.ceil() as i8,
+            tl: (low.floor() - 1.0) as i8,
+        })
+    }
+
+    /// Decodes registers as read from the scratchpad (bytes 2 and 3).
+    pub fn from_registers(th: u8, tl: u8) -> Self {
+        AlarmThresholds {
+            th: th as i8,
+            tl: tl as i8,
+        }
+    }
+
+    /// Registers (TH, TL) ready to be written into the scratchpad.
+    pub fn to_registers(self) -> (u8, u8) {
+        (self.th as u8, self.tl as u8)
+    }
+
+    /// Alarm is raised at this temperature and above.
+    pub fn high(&self) -> f32 {
+        self.th as f32
+    }
+
+    /// Alarm is raised below this temperature.
+    pub fn low(&self) -> f32 {
+        self.tl as f32 + 1.0
+    }
+
+    /// The same check as the sensor does, for the already read temperature.
+    pub fn is_alarm(&self, temp: f32) -> bool {
+        let whole = temp.floor();
+        whole >= self.th as f32 || whole <= self.tl as f32
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlarmEvent {
+    Raised(Address),
+    Cleared(Address),
+}
+
+/// Remembers alarming sensors between alarm searches.
+#[derive(Debug, Default)]
+pub struct AlarmMonitor {
+    alarming: Vec<Address>,
+}
+
+impl AlarmMonitor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn alarming(&self) -> &[Address] {
+        &self.alarming
+    }
+
+    /// Takes the result of the alarm search, returns what changed since the previous one.
+    pub fn update(&mut self, alarming: &[Address]) -> Vec<AlarmEvent> {
+        let raised = alarming
+            .iter()
+            .filter(|a| !self.alarming.contains(a))
+            .map(|a| AlarmEvent::Raised(*a));
+        let cleared = self
+            .alarming
+            .iter()
+            .filter(|a| !alarming.contains(a))
+            .map(|a| AlarmEvent::Cleared(*a));
+        let events = raised.chain(cleared).collect();
+
+        self.alarming = alarming.to_vec();
+        events
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::temp_sensor::alarm::{AlarmEvent, AlarmMonitor, AlarmThresholds, ThresholdError};
+    use one_wire_bus::Address;
+
+    #[test]
+    fn encode_thresholds_test() {
+        let thresholds = AlarmThresholds::new(18.0, 25.0).unwrap();
+        assert_eq!(thresholds.to_registers(), (25, 17));
+        assert_eq!((thresholds.low(), thresholds.high()), (18.0, 25.0));
+
+        // rounded outward
+        let thresholds = AlarmThresholds::new(18.5, 24.2).unwrap();
+        assert_eq!((thresholds.low(), thresholds.high()), (18.0, 25.0));
+
+        // negative values are two's complement
+        let thresholds = AlarmThresholds::new(-10.0, -2.5).unwrap();
+        assert_eq!(thresholds.to_registers(), (0xFE, 0xF5));
+        assert_eq!(AlarmThresholds::from_registers(0xFE, 0xF5), thresholds);
+
+        assert_eq!(
+            AlarmThresholds::new(f32::NAN, 1.0),
+            Err(ThresholdError::NotANumber)
+        );
+        assert_eq!(
+            AlarmThresholds::new(0.0, 130.0),
+            Err(ThresholdError::OutOfRange(130.0))
+        );
+        assert_eq!(
+            AlarmThresholds::new(10.0, 10.0),
+            Err(ThresholdError::LowAboveHigh)
+        );
+    }
+
+    #[test]
+    fn is_alarm_test() {
+        let thresholds = AlarmThresholds::new(18.0, 25.0).unwrap();
+        assert!(!thresholds.is_alarm(18.0));
+        assert!(!thresholds.is_alarm(24.9375));
+        assert!(thresholds.is_alarm(25.0));
+        assert!(thresholds.is_alarm(17.9375));
+
+        let thresholds = AlarmThresholds::new(-10.0, -2.5).unwrap();
+        assert!(!thresholds.is_alarm(-3.0625));
+        assert!(thresholds.is_alarm(-2.0));
+        assert!(thresholds.is_alarm(-10.0625));
+    }
+
+    #[test]
+    fn alarm_monitor_test() {
+        let (a, b) = (Address(1), Address(2));
+        let mut monitor = AlarmMonitor::new();
+        assert_eq!(monitor.update(&[a]), vec![AlarmEvent::Raised(a)]);
+        assert_eq!(monitor.update(&[a]), vec![]);
+        assert_eq!(
+            monitor.update(&[b]),
+            vec![AlarmEvent::Raised(b), AlarmEvent::Cleared(a)]
+        );
+        assert_eq!(monitor.update(&[]), vec![AlarmEvent::Cleared(b)]);
+    }
+}
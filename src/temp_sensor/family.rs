@@ -0,0 +1,188 @@
+//! 1-Wire device families, dispatched by the family code (the lowest byte of ROM address).
+
+use super::Resolution;
+use one_wire_bus::Address;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceFamily {
+    /// Programmable 9-12 bits resolution.
+    Ds18b20,
+    /// Fixed 9 bits (0.5°C), extended by `COUNT_REMAIN` register. No config register.
+    Ds18s20,
+    /// The same protocol as DS18B20, ±2°C accuracy.
+    Ds1822,
+    /// Dual channel switch, not a temperature sensor.
+    Ds2413,
+    Unknown(u8),
+}
+
+impl DeviceFamily {
+    pub fn from_address(address: &Address) -> Self {
+        match address.family_code() {
+            0x28 => DeviceFamily::Ds18b20,
+            0x10 => DeviceFamily::Ds18s20,
+            0x22 => DeviceFamily::Ds1822,
+            0x3A => DeviceFamily::Ds2413,
+            code => DeviceFamily::Unknown(code),
+        }
+    }
+
+    pub fn is_temperature_sensor(&self) -> bool {
+        matches!(
+            self,
+            DeviceFamily::Ds18b20 | DeviceFamily::Ds18s20 | DeviceFamily::Ds1822
+        )
+    }
+
+    /// Resolution can be changed, the scratchpad has TH, TL and config registers.
+    pub fn has_config_register(&self) -> bool {
+        matches!(self, DeviceFamily::Ds18b20 | DeviceFamily::Ds1822)
+    }
+
+    /// Resolution of the checked scratchpad.
+    pub fn resolution(&self, scratchpad: &[u8; 9]) -> Resolution {
+        if self.has_config_register() {
+            resolution_from_config(scratchpad[4])
+        } else {
+            Resolution::Bits9
+        }
+    }
+
+    /// Max time of the temperature conversion.
+    pub fn conversion_time_ms(&self, resolution: Option<Resolution>) -> u16 {
+        if self.has_config_register() {
+            resolution
+                .unwrap_or(Resolution::Bits12)
+                .max_measurement_time_millis()
+        } else {
+            750
+        }
+    }
+
+    /// Temperature in Celsius from the checked scratchpad.
+    pub fn parse_temperature(&self, scratchpad: &[u8; 9]) -> f32 {
+        if self.has_config_register() {
+            parse_temperature(scratchpad)
+        } else {
+            parse_ds18s20_temperature(scratchpad)
+        }
+    }
+}
+
+impl fmt::Display for DeviceFamily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceFamily::Ds18b20 => f.write_str("DS18B20"),
+            DeviceFamily::Ds18s20 => f.write_str("DS18S20"),
+            DeviceFamily::Ds1822 => f.write_str("DS1822"),
+            DeviceFamily::Ds2413 => f.write_str("DS2413"),
+            DeviceFamily::Unknown(code) => write!(f, "unknown family 0x{code:02X}"),
+        }
+    }
+}
+
+/// Bits 5-6 of config register: 0 - 9 bits ... 3 - 12 bits.
+fn resolution_from_config(config: u8) -> Resolution {
+    match (config >> 5) & 0b11 {
+        0 => Resolution::Bits9,
+        1 => Resolution::Bits10,
+        2 => Resolution::Bits11,
+        _ => Resolution::Bits12,
+    }
+}
+
+/// DS18B20/DS1822: 1/16°C units.
+fn parse_temperature(scratchpad: &[u8; 9]) -> f32 {
+    let resolution_bits = 9 + ((scratchpad[4] >> 5) & 0b11);
+    // lower bits are undefined for lower resolutions
+    let undefined_mask = !((1i16 << (12 - resolution_bits)) - 1);
+    let raw = i16::from_le_bytes([scratchpad[0], scratchpad[1]]) & undefined_mask;
+    raw as f32 / 16.0
+}
+
+/// DS18S20: 1/2°C units, extended resolution is calculated from count registers.
+fn parse_ds18s20_temperature(scratchpad: &[u8; 9]) -> f32 {
+    let raw = i16::from_le_bytes([scratchpad[0], scratchpad[1]]);
+    let count_remain = scratchpad[6] as f32;
+    let count_per_c = scratchpad[7] as f32;
+    if count_per_c == 0.0 {
+        return raw as f32 / 2.0;
+    }
+    // see "Operation - Measuring Temperature" in the datasheet
+    let truncated = (raw >> 1) as f32;
+    truncated - 0.25 + (count_per_c - count_remain) / count_per_c
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::temp_sensor::family::{resolution_from_config, DeviceFamily};
+    use crate::temp_sensor::Resolution;
+    use one_wire_bus::Address;
+
+    #[test]
+    fn from_address_test() {
+        let family = |code: u64| DeviceFamily::from_address(&Address(0x4B0000000F2D5A00 | code));
+        assert_eq!(family(0x28), DeviceFamily::Ds18b20);
+        assert_eq!(family(0x10), DeviceFamily::Ds18s20);
+        assert_eq!(family(0x22), DeviceFamily::Ds1822);
+        assert_eq!(family(0x3A), DeviceFamily::Ds2413);
+        assert_eq!(family(0x01), DeviceFamily::Unknown(0x01));
+        assert!(!family(0x3A).is_temperature_sensor());
+        assert_eq!(family(0x01).to_string(), "unknown family 0x01");
+    }
+
+    #[test]
+    fn parse_temperature_test() {
+        let ds18b20 = DeviceFamily::Ds18b20;
+        // +25.0625, 12 bits
+        let scratchpad = [0x91, 0x01, 0x4B, 0x46, 0x7F, 0xFF, 0x0F, 0x10, 0x00];
+        assert_eq!(ds18b20.parse_temperature(&scratchpad), 25.0625);
+
+        // -10.125, 12 bits
+        let scratchpad = [0x5E, 0xFF, 0x4B, 0x46, 0x7F, 0xFF, 0x0F, 0x10, 0x00];
+        assert_eq!(ds18b20.parse_temperature(&scratchpad), -10.125);
+
+        // 25.0625 read with 9 bits resolution -> 25.0
+        let scratchpad = [0x91, 0x01, 0x4B, 0x46, 0x1F, 0xFF, 0x0F, 0x10, 0x00];
+        assert_eq!(ds18b20.parse_temperature(&scratchpad), 25.0);
+        assert_eq!(DeviceFamily::Ds1822.parse_temperature(&scratchpad), 25.0);
+    }
+
+    #[test]
+    fn parse_ds18s20_temperature_test() {
+        let ds18s20 = DeviceFamily::Ds18s20;
+        // +25.0: 0x32 half degrees, remain 0x0C of 0x10
+        let scratchpad = [0x32, 0x00, 0x4B, 0x46, 0xFF, 0xFF, 0x0C, 0x10, 0x00];
+        assert_eq!(ds18s20.parse_temperature(&scratchpad), 25.0);
+
+        // +25.3125
+        let scratchpad = [0x32, 0x00, 0x4B, 0x46, 0xFF, 0xFF, 0x07, 0x10, 0x00];
+        assert_eq!(ds18s20.parse_temperature(&scratchpad), 25.3125);
+
+        // -0.5 without count registers
+        let scratchpad = [0xFF, 0xFF, 0x4B, 0x46, 0xFF, 0xFF, 0x00, 0x00, 0x00];
+        assert_eq!(ds18s20.parse_temperature(&scratchpad), -0.5);
+
+        assert_eq!(
+            ds18s20.resolution(&scratchpad) as u8,
+            Resolution::Bits9 as u8
+        );
+        assert_eq!(ds18s20.conversion_time_ms(Some(Resolution::Bits9)), 750);
+    }
+
+    #[test]
+    fn resolution_from_config_test() {
+        for resolution in [
+            Resolution::Bits9,
+            Resolution::Bits10,
+            Resolution::Bits11,
+            Resolution::Bits12,
+        ] {
+            assert_eq!(
+                resolution_from_config(resolution as u8) as u8,
+                resolution as u8
+            );
+        }
+    }
+}
@@ -0,0 +1,433 @@
+//! Temperature history of each sensor in RAM with min/max/mean/trend statistics.
+//!
+//! Samples are taken on the fixed grid of `interval`, a missed sample (failing sensor) is a gap.
+//! History can be saved to NVS downsampled and restored after reboot,
+//! and exported as CSV or JSON for HTTP or the console.
+
+use super::Reading;
+use esp_idf_svc::nvs::{EspNvs, NvsPartitionId};
+use esp_idf_svc::sys::EspError;
+use one_wire_bus::Address;
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+/// NVS namespace of the saved history.
+pub const NVS_NAMESPACE: &str = "temp_history";
+const NVS_KEY: &str = "history";
+/// Bump when the blob layout changes.
+const FORMAT_VERSION: u8 = 1;
+/// Gap in the saved history.
+const NO_VALUE: i16 = i16::MIN;
+
+#[derive(Debug, Clone)]
+pub struct HistoryConfig {
+    /// Samples kept per sensor.
+    pub capacity: usize,
+    /// Time between samples.
+    pub interval: Duration,
+    /// Saved history keeps one averaged sample of each `downsample` samples.
+    pub downsample: usize,
+}
+
+impl Default for HistoryConfig {
+    /// 24 hours with 5 minutes interval, saved with 20 minutes interval.
+    fn default() -> Self {
+        HistoryConfig {
+            capacity: 288,
+            interval: Duration::from_secs(5 * 60),
+            downsample: 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stats {
+    pub min: f32,
+    pub max: f32,
+    pub mean: f32,
+    /// Celsius per hour, least squares slope over the whole window.
+    pub trend_per_hour: f32,
+    /// Samples without gaps.
+    pub count: usize,
+}
+
+#[derive(Debug)]
+pub struct SensorHistory {
+    address: Address,
+    /// The oldest first.
+    samples: VecDeque<Option<f32>>,
+    last_sample_at: Option<Instant>,
+}
+
+impl SensorHistory {
+    fn new(address: Address, capacity: usize) -> Self {
+        SensorHistory {
+            address,
+            samples: VecDeque::with_capacity(capacity),
+            last_sample_at: None,
+        }
+    }
+
+    pub fn address(&self) -> Address {
+        self.address
+    }
+
+    /// Samples from the oldest to the newest, `None` is a gap.
+    pub fn samples(&self) -> impl Iterator<Item = Option<f32>> + '_ {
+        self.samples.iter().copied()
+    }
+
+    fn push(&mut self, value: Option<f32>, capacity: usize) {
+        while self.samples.len() >= capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(value);
+    }
+}
+
+pub struct History {
+    config: HistoryConfig,
+    sensors: Vec<SensorHistory>,
+}
+
+impl History {
+    pub fn new(config: HistoryConfig) -> Self {
+        History {
+            config,
+            sensors: Vec::new(),
+        }
+    }
+
+    pub fn sensors(&self) -> &[SensorHistory] {
+        &self.sensors
+    }
+
+    pub fn get(&self, address: &Address) -> Option<&SensorHistory> {
+        self.sensors.iter().find(|h| h.address == *address)
+    }
+
+    /// Records the measurement round, see [`Self::record`].
+    pub fn record_readings(&mut self, readings: &[Reading], now: Instant) {
+        for reading in readings {
+            self.record(reading.address, reading.temperature.ok(), now);
+        }
+    }
+
+    /// Takes a sample if `interval` passed since the previous one, missed intervals are gaps.
+    /// `None` temperature (failed reading) is a gap too.
+    pub fn record(&mut self, address: Address, temp: Option<f32>, now: Instant) {
+        let capacity = self.config.capacity;
+        let interval = self.config.interval;
+        let history = match self.sensors.iter().position(|h| h.address == address) {
+            Some(idx) => &mut self.sensors[idx],
+            None => {
+                self.sensors.push(SensorHistory::new(address, capacity));
+                self.sensors.last_mut().unwrap()
+            }
+        };
+
+        let Some(last) = history.last_sample_at else {
+            history.push(temp, capacity);
+            history.last_sample_at = Some(now);
+            return;
+        };
+        let elapsed = now.saturating_duration_since(last);
+        if elapsed < interval {
+            return;
+        }
+
+        let steps = (elapsed.as_secs_f64() / interval.as_secs_f64()) as usize;
+        for _ in 1..steps.min(capacity) {
+            history.push(None, capacity);
+        }
+        history.push(temp, capacity);
+        // stay on the grid
+        history.last_sample_at = Some(last + interval * steps as u32);
+    }
+
+    pub fn stats(&self, address: &Address) -> Option<Stats> {
+        self.get(address)
+            .and_then(|h| stats(h.samples(), self.config.interval))
+    }
+
+    /// `address,age_s,temp` rows, the oldest first, gaps are empty.
+    pub fn to_csv(&self) -> String {
+        let mut out = String::from("address,age_s,temp\n");
+        for history in &self.sensors {
+            let len = history.samples.len();
+            for (idx, value) in history.samples().enumerate() {
+                let age = self.config.interval.as_secs() * (len - 1 - idx) as u64;
+                let _ = write!(out, "{:?},{age},", history.address);
+                if let Some(temp) = value {
+                    let _ = write!(out, "{temp:.2}");
+                }
+                out.push('\n');
+            }
+        }
+        out
+    }
+
+    /// `{"interval_s":300,"sensors":[{"address":"..","stats":{..},"samples":[21.5,null]}]}`,
+    /// samples are the oldest first.
+    pub fn to_json(&self) -> String {
+        let mut out = format!(
+            "{{\"interval_s\":{},\"sensors\":[",
+            self.config.interval.as_secs()
+        );
+        for (idx, history) in self.sensors.iter().enumerate() {
+            if idx > 0 {
+                out.push(',');
+            }
+            let _ = write!(out, "{{\"address\":\"{:?}\",\"stats\":", history.address);
+            match stats(history.samples(), self.config.interval) {
+                Some(s) => {
+                    let _ = write!(
+                        out,
+                        "{{\"min\":{:.2},\"max\":{:.2},\"mean\":{:.2},\"trend_per_hour\":{:.3}}}",
+                        s.min, s.max, s.mean, s.trend_per_hour
+                    );
+                }
+                None => out.push_str("null"),
+            }
+            out.push_str(",\"samples\":[");
+            for (idx, value) in history.samples().enumerate() {
+                if idx > 0 {
+                    out.push(',');
+                }
+                match value {
+                    Some(temp) => {
+                        let _ = write!(out, "{temp:.2}");
+                    }
+                    None => out.push_str("null"),
+                }
+            }
+            out.push_str("]}");
+        }
+        out.push_str("]}");
+        out
+    }
+
+    /// Saves downsampled history, NVS wears out, don't save too often.
+    pub fn save<T: NvsPartitionId>(&self, nvs: &mut EspNvs<T>) -> Result<(), EspError> {
+        nvs.set_raw(NVS_KEY, &self.encode())?;
+        Ok(())
+    }
+
+    /// Restores saved history, the time between saving and now is lost.
+    /// Corrupted or incompatible history is logged and skipped.
+    pub fn load<T: NvsPartitionId>(&mut self, nvs: &EspNvs<T>) -> Result<(), EspError> {
+        let mut buf = vec![0u8; nvs.blob_len(NVS_KEY)?.unwrap_or(8)];
+        if let Some(blob) = nvs.get_raw(NVS_KEY, &mut buf)? {
+            match self.decode(blob) {
+                Some(sensors) => self.sensors = sensors,
+                None => log::warn!("Saved temperature history is skipped"),
+            }
+        }
+        Ok(())
+    }
+
+    /// `version, interval_s u32, downsample u8, (address u64, len u16, (centidegrees i16)*)*`,
+    /// little endian.
+    fn encode(&self) -> Vec<u8> {
+        let downsample = self.config.downsample.clamp(1, u8::MAX as usize);
+        let mut blob = vec![FORMAT_VERSION];
+        blob.extend_from_slice(&(self.config.interval.as_secs() as u32).to_le_bytes());
+        blob.push(downsample as u8);
+
+        for history in &self.sensors {
+            let samples = history.samples().collect::<Vec<_>>();
+            // groups are aligned to the newest sample
+            let groups = samples.rchunks(downsample).rev().map(|group| {
+                let values = group.iter().flatten().collect::<Vec<_>>();
+                if values.is_empty() {
+                    NO_VALUE
+                } else {
+                    let mean = values.iter().copied().sum::<f32>() / values.len() as f32;
+                    (mean * 100.0).round() as i16
+                }
+            });
+            let groups = groups.collect::<Vec<_>>();
+
+            blob.extend_from_slice(&history.address.0.to_le_bytes());
+            blob.extend_from_slice(&(groups.len() as u16).to_le_bytes());
+            for value in groups {
+                blob.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        blob
+    }
+
+    /// Each saved sample is repeated `downsample` times to keep the grid.
+    fn decode(&self, blob: &[u8]) -> Option<Vec<SensorHistory>> {
+        let (&version, rest) = blob.split_first()?;
+        if version != FORMAT_VERSION || rest.len() < 5 {
+            return None;
+        }
+        let interval = u32::from_le_bytes(rest[0..4].try_into().ok()?);
+        if interval as u64 != self.config.interval.as_secs() {
+            log::warn!(
+                "Saved history interval is {interval}s, expected {:?}",
+                self.config.interval
+            );
+            return None;
+        }
+        let downsample = rest[4] as usize;
+        let mut rest = &rest[5..];
+
+        let mut sensors = Vec::new();
+        while !rest.is_empty() {
+            if rest.len() < 10 {
+                return None;
+            }
+            let address = u64::from_le_bytes(rest[0..8].try_into().ok()?);
+            let len = u16::from_le_bytes(rest[8..10].try_into().ok()?) as usize;
+            rest = &rest[10..];
+            if rest.len() < len * 2 {
+                return None;
+            }
+
+            let mut history = SensorHistory::new(Address(address), self.config.capacity);
+            for raw in rest[..len * 2].chunks(2) {
+                let raw = i16::from_le_bytes([raw[0], raw[1]]);
+                let value = (raw != NO_VALUE).then_some(raw as f32 / 100.0);
+                for _ in 0..downsample {
+                    history.push(value, self.config.capacity);
+                }
+            }
+            sensors.push(history);
+            rest = &rest[len * 2..];
+        }
+        Some(sensors)
+    }
+}
+
+fn stats(samples: impl Iterator<Item = Option<f32>>, interval: Duration) -> Option<Stats> {
+    let points = samples
+        .enumerate()
+        .filter_map(|(idx, value)| value.map(|v| (idx as f32, v)))
+        .collect::<Vec<_>>();
+    if points.is_empty() {
+        return None;
+    }
+
+    let count = points.len() as f32;
+    let min = points.iter().map(|p| p.1).fold(f32::INFINITY, f32::min);
+    let max = points.iter().map(|p| p.1).fold(f32::NEG_INFINITY, f32::max);
+    let mean = points.iter().map(|p| p.1).sum::<f32>() / count;
+
+    // slope of the least squares line, per sample
+    let mean_x = points.iter().map(|p| p.0).sum::<f32>() / count;
+    let (mut cov, mut var) = (0.0, 0.0);
+    for (x, y) in &points {
+        cov += (x - mean_x) * (y - mean);
+        var += (x - mean_x) * (x - mean_x);
+    }
+    let slope = if var > 0.0 { cov / var } else { 0.0 };
+    let samples_per_hour = 3600.0 / interval.as_secs_f32();
+
+    Some(Stats {
+        min,
+        max,
+        mean,
+        trend_per_hour: slope * samples_per_hour,
+        count: points.len(),
+    })
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::temp_sensor::history::{History, HistoryConfig};
+    use one_wire_bus::Address;
+    use std::time::{Duration, Instant};
+
+    fn config() -> HistoryConfig {
+        HistoryConfig {
+            capacity: 4,
+            interval: Duration::from_secs(60),
+            downsample: 2,
+        }
+    }
+
+    #[test]
+    fn record_test() {
+        let address = Address(0x28);
+        let mut history = History::new(config());
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        history.record(address, Some(20.0), at(0));
+        // too early
+        history.record(address, Some(99.0), at(30));
+        history.record(address, Some(21.0), at(61));
+        // 2 intervals, the first is a gap
+        history.record(address, Some(22.0), at(185));
+        history.record(address, None, at(240));
+        history.record(address, Some(24.0), at(300));
+
+        let samples = history.get(&address).unwrap().samples().collect::<Vec<_>>();
+        assert_eq!(samples, vec![None, Some(22.0), None, Some(24.0)]);
+    }
+
+    #[test]
+    fn stats_test() {
+        let address = Address(0x28);
+        let mut history = History::new(config());
+        let start = Instant::now();
+        for (idx, temp) in [20.0, 21.0, 22.0, 23.0].iter().enumerate() {
+            history.record(
+                address,
+                Some(*temp),
+                start + Duration::from_secs(60 * idx as u64),
+            );
+        }
+
+        let stats = history.stats(&address).unwrap();
+        assert_eq!((stats.min, stats.max, stats.mean), (20.0, 23.0, 21.5));
+        assert_eq!(stats.trend_per_hour, 60.0);
+        assert_eq!(stats.count, 4);
+        assert!(history.stats(&Address(0x10)).is_none());
+    }
+
+    #[test]
+    fn export_test() {
+        let address = Address(0x28);
+        let mut history = History::new(config());
+        let start = Instant::now();
+        history.record(address, Some(20.0), start);
+        history.record(address, None, start + Duration::from_secs(60));
+
+        assert_eq!(
+            history.to_csv(),
+            "address,age_s,temp\n0000000000000028,60,20.00\n0000000000000028,0,\n"
+        );
+        assert_eq!(
+            history.to_json(),
+            "{\"interval_s\":60,\"sensors\":[{\"address\":\"0000000000000028\",\
+             \"stats\":{\"min\":20.00,\"max\":20.00,\"mean\":20.00,\"trend_per_hour\":0.000},\
+             \"samples\":[20.00,null]}]}"
+        );
+    }
+
+    #[test]
+    fn encode_decode_test() {
+        let address = Address(0x28);
+        let mut history = History::new(config());
+        let start = Instant::now();
+        for (idx, temp) in [Some(20.0), Some(21.0), None, None, Some(-5.5)]
+            .iter()
+            .enumerate()
+        {
+            history.record(address, *temp, start + Duration::from_secs(60 * idx as u64));
+        }
+
+        let restored = history.decode(&history.encode()).unwrap();
+        let samples = restored[0].samples().collect::<Vec<_>>();
+        // [21.0, None, None, -5.5] -> [21.0, None] [None, -5.5] -> 21.0, -5.5 (x2 each)
+        assert_eq!(
+            samples,
+            vec![Some(21.0), Some(21.0), Some(-5.5), Some(-5.5)]
+        );
+        assert!(history.decode(&[1, 0]).is_none());
+    }
+}
@@ -0,0 +1,358 @@
+//! User assigned names and calibration offsets of the sensors, stored in NVS.
+//!
+//! Sensors are identified by 64-bit ROM address, so names don't depend on the search order.
+//! The registry is edited with text commands, the same for the serial console and MQTT:
+//!
+//! * `list`
+//! * `name <address> <name>`
+//! * `offset <address> <offset in Celsius>`
+//! * `forget <address>`
+//!
+//! where `<address>` is 16 hex digits as printed in logs, e.g. `4B0000000F2D5A28`.
+
+use esp_idf_svc::nvs::{EspNvs, NvsDefault};
+use esp_idf_svc::sys::EspError;
+use one_wire_bus::Address;
+use std::fmt;
+use std::fmt::Write;
+
+/// NVS namespace of the registry.
+pub const NVS_NAMESPACE: &str = "sensors";
+const NVS_KEY: &str = "registry";
+/// Names are stored with one byte length.
+pub const MAX_NAME_LEN: usize = 32;
+/// Registered sensors, the blob is at most 45 bytes per sensor.
+pub const MAX_SENSORS: usize = 64;
+/// Bump when the blob layout changes.
+const FORMAT_VERSION: u8 = 1;
+
+#[derive(Debug)]
+pub enum RegistryError {
+    Nvs(EspError),
+    /// Stored blob can't be decoded.
+    Corrupted,
+    UnknownSensor(Address),
+    InvalidCommand(String),
+}
+
+impl From<EspError> for RegistryError {
+    fn from(err: EspError) -> Self {
+        RegistryError::Nvs(err)
+    }
+}
+
+impl fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegistryError::Nvs(err) => write!(f, "NVS error: {err}"),
+            RegistryError::Corrupted => f.write_str("stored registry is corrupted"),
+            RegistryError::UnknownSensor(address) => write!(f, "unknown sensor {address:?}"),
+            RegistryError::InvalidCommand(msg) => write!(f, "invalid command: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for RegistryError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SensorEntry {
+    pub address: Address,
+    /// Empty until the user names the sensor.
+    pub name: String,
+    /// Added to every reading, Celsius.
+    pub offset: f32,
+}
+
+/// Changes of the bus found by [`Registry::reconcile`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegistryEvent {
+    /// Sensor was never seen before, it's registered without a name.
+    New(Address),
+    /// Registered sensor is not on the bus.
+    Missing(Address),
+    /// Missing sensor is on the bus again.
+    Returned(Address),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RegistryCommand {
+    List,
+    Name(Address, String),
+    Offset(Address, f32),
+    Forget(Address),
+}
+
+impl RegistryCommand {
+    pub fn parse(line: &str) -> Result<Self, RegistryError> {
+        let invalid = |msg: &str| RegistryError::InvalidCommand(msg.to_string());
+        let mut parts = line.split_whitespace();
+        let cmd = parts.next().ok_or_else(|| invalid("empty command"))?;
+        if cmd == "list" {
+            return Ok(RegistryCommand::List);
+        }
+
+        let address = parts.next().ok_or_else(|| invalid("address is missing"))?;
+        let address = u64::from_str_radix(address, 16)
+            .map(Address)
+            .map_err(|_| invalid("address should be 16 hex digits"))?;
+
+        match cmd {
+            "name" => {
+                let name = parts.collect::<Vec<_>>().join(" ");
+                if name.is_empty() {
+                    return Err(invalid("name is missing"));
+                }
+                if name.len() > MAX_NAME_LEN {
+                    return Err(invalid("name is too long"));
+                }
+                Ok(RegistryCommand::Name(address, name))
+            }
+            "offset" => {
+                let offset = parts
+                    .next()
+                    .and_then(|v| v.parse::<f32>().ok())
+                    .filter(|v| v.is_finite())
+                    .ok_or_else(|| invalid("offset should be a number"))?;
+                Ok(RegistryCommand::Offset(address, offset))
+            }
+            "forget" => Ok(RegistryCommand::Forget(address)),
+            _ => Err(invalid("expected list, name, offset or forget")),
+        }
+    }
+}
+
+pub struct Registry {
+    nvs: EspNvs<NvsDefault>,
+    entries: Vec<SensorEntry>,
+    missing: Vec<Address>,
+}
+
+impl Registry {
+    /// Loads the registry, a corrupted one is logged and replaced with the empty one.
+    pub fn load(nvs: EspNvs<NvsDefault>) -> Result<Self, RegistryError> {
+        let mut buf = vec![0u8; nvs.blob_len(NVS_KEY)?.unwrap_or(1)];
+        let mut entries = match nvs.get_raw(NVS_KEY, &mut buf)? {
+            Some(blob) => decode(blob).unwrap_or_else(|err| {
+                log::error!("Sensor registry: {err}, starting from scratch");
+                Vec::new()
+            }),
+            None => Vec::new(),
+        };
+        entries.truncate(MAX_SENSORS);
+        log::info!("Sensor registry: {} sensors", entries.len());
+        Ok(Registry {
+            nvs,
+            entries,
+            missing: Vec::new(),
+        })
+    }
+
+    pub fn entries(&self) -> &[SensorEntry] {
+        &self.entries
+    }
+
+    pub fn get(&self, address: &Address) -> Option<&SensorEntry> {
+        self.entries.iter().find(|e| e.address == *address)
+    }
+
+    /// Name of the sensor or its address if the sensor isn't named.
+    pub fn name(&self, address: &Address) -> String {
+        match self.get(address) {
+            Some(entry) if !entry.name.is_empty() => entry.name.clone(),
+            _ => format!("{address:?}"),
+        }
+    }
+
+    /// Applies calibration offset of the sensor.
+    pub fn calibrate(&self, address: &Address, temp: f32) -> f32 {
+        temp + self.get(address).map_or(0.0, |e| e.offset)
+    }
+
+    /// Compares registered sensors with sensors `present` on the bus. New sensors are
+    /// registered up to [`MAX_SENSORS`], each missing sensor is reported once until it
+    /// returns.
+    pub fn reconcile(&mut self, present: &[Address]) -> Result<Vec<RegistryEvent>, RegistryError> {
+        let mut events = Vec::new();
+
+        for address in present {
+            if self.get(address).is_none() {
+                if self.entries.len() >= MAX_SENSORS {
+                    log::warn!("Sensor registry is full, {address:?} is not registered");
+                    continue;
+                }
+                self.entries.push(SensorEntry {
+                    address: *address,
+                    name: String::new(),
+                    offset: 0.0,
+                });
+                events.push(RegistryEvent::New(*address));
+            }
+        }
+
+        for entry in &self.entries {
+            let is_present = present.contains(&entry.address);
+            let was_missing = self.missing.contains(&entry.address);
+            if !is_present && !was_missing {
+                events.push(RegistryEvent::Missing(entry.address));
+            } else if is_present && was_missing {
+                events.push(RegistryEvent::Returned(entry.address));
+            }
+        }
+        self.missing = self
+            .entries
+            .iter()
+            .map(|e| e.address)
+            .filter(|a| !present.contains(a))
+            .collect();
+
+        if events.iter().any(|e| matches!(e, RegistryEvent::New(_))) {
+            self.save()?;
+        }
+        Ok(events)
+    }
+
+    /// Executes the text command, returns the text reply.
+    pub fn execute(&mut self, line: &str) -> Result<String, RegistryError> {
+        match RegistryCommand::parse(line)? {
+            RegistryCommand::List => {
+                let mut out = String::new();
+                for entry in &self.entries {
+                    let state = if self.missing.contains(&entry.address) {
+                        " (missing)"
+                    } else {
+                        ""
+                    };
+                    let _ = writeln!(
+                        out,
+                        "{:?} '{}' {:+.2}{state}",
+                        entry.address, entry.name, entry.offset
+                    );
+                }
+                Ok(out)
+            }
+            RegistryCommand::Name(address, name) => {
+                self.entry_mut(&address)?.name = name;
+                self.save()?;
+                Ok("ok".to_string())
+            }
+            RegistryCommand::Offset(address, offset) => {
+                self.entry_mut(&address)?.offset = offset;
+                self.save()?;
+                Ok("ok".to_string())
+            }
+            RegistryCommand::Forget(address) => {
+                self.entry_mut(&address)?;
+                self.entries.retain(|e| e.address != address);
+                self.missing.retain(|a| *a != address);
+                self.save()?;
+                Ok("ok".to_string())
+            }
+        }
+    }
+
+    fn entry_mut(&mut self, address: &Address) -> Result<&mut SensorEntry, RegistryError> {
+        self.entries
+            .iter_mut()
+            .find(|e| e.address == *address)
+            .ok_or(RegistryError::UnknownSensor(*address))
+    }
+
+    fn save(&mut self) -> Result<(), RegistryError> {
+        let entries = &self.entries[..self.entries.len().min(MAX_SENSORS)];
+        self.nvs.set_raw(NVS_KEY, &encode(entries))?;
+        Ok(())
+    }
+}
+
+/// `version, (address u64, offset f32, name len u8, name)*`, little endian.
+fn encode(entries: &[SensorEntry]) -> Vec<u8> {
+    let mut blob = vec![FORMAT_VERSION];
+    for entry in entries {
+        let name = &entry.name.as_bytes()[..entry.name.len().min(MAX_NAME_LEN)];
+        blob.extend_from_slice(&entry.address.0.to_le_bytes());
+        blob.extend_from_slice(&entry.offset.to_le_bytes());
+        blob.push(name.len() as u8);
+        blob.extend_from_slice(name);
+    }
+    blob
+}
+
+fn decode(blob: &[u8]) -> Result<Vec<SensorEntry>, RegistryError> {
+    let (version, mut rest) = blob.split_first().ok_or(RegistryError::Corrupted)?;
+    if *version != FORMAT_VERSION {
+        return Err(RegistryError::Corrupted);
+    }
+
+    let mut entries = Vec::new();
+    while !rest.is_empty() {
+        if rest.len() < 13 {
+            return Err(RegistryError::Corrupted);
+        }
+        let (head, tail) = rest.split_at(13);
+        let address = u64::from_le_bytes(head[0..8].try_into().unwrap());
+        let offset = f32::from_le_bytes(head[8..12].try_into().unwrap());
+        let name_len = head[12] as usize;
+        if tail.len() < name_len {
+            return Err(RegistryError::Corrupted);
+        }
+        let name = std::str::from_utf8(&tail[..name_len]).map_err(|_| RegistryError::Corrupted)?;
+        entries.push(SensorEntry {
+            address: Address(address),
+            name: name.to_string(),
+            offset,
+        });
+        rest = &tail[name_len..];
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::temp_sensor::registry::{decode, encode, RegistryCommand, SensorEntry};
+    use one_wire_bus::Address;
+
+    #[test]
+    fn encode_decode_test() {
+        let entries = vec![
+            SensorEntry {
+                address: Address(0x4B0000000F2D5A28),
+                name: "boiler in".to_string(),
+                offset: -0.25,
+            },
+            SensorEntry {
+                address: Address(0x1A0000000F2D5A28),
+                name: String::new(),
+                offset: 0.0,
+            },
+        ];
+        let blob = encode(&entries);
+        assert_eq!(decode(&blob).unwrap(), entries);
+        assert!(decode(&blob[..blob.len() - 1]).is_err());
+        assert!(decode(&[]).is_err());
+    }
+
+    #[test]
+    fn parse_command_test() {
+        let address = Address(0x4B0000000F2D5A28);
+        assert_eq!(
+            RegistryCommand::parse("list").unwrap(),
+            RegistryCommand::List
+        );
+        assert_eq!(
+            RegistryCommand::parse("name 4B0000000F2D5A28 boiler in").unwrap(),
+            RegistryCommand::Name(address, "boiler in".to_string())
+        );
+        assert_eq!(
+            RegistryCommand::parse("offset 4B0000000F2D5A28 -0.5").unwrap(),
+            RegistryCommand::Offset(address, -0.5)
+        );
+        assert_eq!(
+            RegistryCommand::parse(" forget  4b0000000f2d5a28 ").unwrap(),
+            RegistryCommand::Forget(address)
+        );
+        assert!(RegistryCommand::parse("name 4B0000000F2D5A28").is_err());
+        assert!(RegistryCommand::parse("offset XYZ 1").is_err());
+        assert!(RegistryCommand::parse("offset 4B0000000F2D5A28 NaN").is_err());
+        assert!(RegistryCommand::parse("rename 4B0000000F2D5A28 x").is_err());
+    }
+}
@@ -0,0 +1,114 @@
+//! Temperature acquisition in its own thread.
+//!
+//! The thread owns the bus and publishes each measurement round into the shared snapshot,
+//! other subsystems (HTTP, MQTT, display) read [`TempReadings`] and never touch the bus.
+
+use super::{Sensor, SensorError, SensorStatus, TempSensors};
+use crate::lock_recover;
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+use esp_idf_svc::hal::delay::FreeRtos;
+use one_wire_bus::Address;
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Instant;
+
+/// 1-Wire bit banging and logging, 4K is not enough.
+const STACK_SIZE: usize = 8192;
+
+#[derive(Debug, Clone)]
+pub struct SensorSnapshot {
+    pub address: Address,
+    /// Last successfully read temperature, Celsius. Kept while the sensor is failing.
+    pub temperature: Option<f32>,
+    /// When `temperature` was read.
+    pub read_at: Option<Instant>,
+    /// Error of the last round, `None` if it was successful.
+    pub error: Option<SensorError>,
+    pub status: SensorStatus,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TempSnapshot {
+    pub sensors: Vec<SensorSnapshot>,
+    /// Incremented after each measurement round, readers can skip already seen data.
+    pub round: u32,
+    pub measured_at: Option<Instant>,
+}
+
+impl TempSnapshot {
+    pub fn get(&self, address: &Address) -> Option<&SensorSnapshot> {
+        self.sensors.iter().find(|s| s.address == *address)
+    }
+}
+
+/// Cheap to clone handle to the latest readings.
+#[derive(Debug, Clone, Default)]
+pub struct TempReadings(Arc<Mutex<TempSnapshot>>);
+
+impl TempReadings {
+    /// Copy of the latest readings, the lock is held only while copying.
+    pub fn snapshot(&self) -> TempSnapshot {
+        self.lock().clone()
+    }
+
+    /// Round number of the latest readings, see [`TempSnapshot::round`].
+    pub fn round(&self) -> u32 {
+        self.lock().round
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, TempSnapshot> {
+        lock_recover(&self.0)
+    }
+
+    fn publish(&self, sensors: &[Sensor]) {
+        let now = Instant::now();
+        let mut snapshot = self.lock();
+
+        let previous = std::mem::take(&mut snapshot.sensors);
+        snapshot.sensors = sensors
+            .iter()
+            .map(|sensor| {
+                let address = sensor.address();
+                let read_at = if sensor.last_error().is_none() {
+                    Some(now)
+                } else {
+                    previous
+                        .iter()
+                        .find(|s| s.address == address)
+                        .and_then(|s| s.read_at)
+                };
+                SensorSnapshot {
+                    address,
+                    temperature: sensor.last_temp(),
+                    read_at,
+                    error: sensor.last_error(),
+                    status: sensor.status(),
+                }
+            })
+            .collect();
+        snapshot.round = snapshot.round.wrapping_add(1);
+        snapshot.measured_at = Some(now);
+    }
+}
+
+/// Starts the acquisition thread, it measures sensors every `sample_period` forever.
+pub fn spawn<P, E>(mut sensors: TempSensors<P>) -> std::io::Result<(TempReadings, JoinHandle<()>)>
+where
+    P: InputPin<Error = E> + OutputPin<Error = E> + Send + 'static,
+    E: Debug,
+{
+    let readings = TempReadings::default();
+    let publisher = readings.clone();
+
+    let handle = std::thread::Builder::new()
+        .name("temp_sensors".to_string())
+        .stack_size(STACK_SIZE)
+        .spawn(move || loop {
+            sensors.measure();
+            publisher.publish(sensors.sensors());
+            FreeRtos::delay_ms(sensors.next_measurement_in().as_millis() as u32);
+        })?;
+
+    Ok((readings, handle))
+}
@@ -0,0 +1,454 @@
+//! Heating thermostat driven by temperature readings.
+//!
+//! * on/off control with hysteresis for relays, or PID with anti-windup for PWM (LEDC) heaters
+//! * min on/off times protect relays and compressors from short cycling
+//! * missing or stale temperature switches the output to the fail-safe power
+//!
+//! [`Controller`] is pure logic and doesn't touch hardware, [`Thermostat`] connects it to
+//! [`crate::temp_sensor::task::TempReadings`] and the [`HeaterOutput`].
+
+use crate::temp_sensor::task::TempSnapshot;
+use crate::temp_sensor::SensorStatus;
+use esp_idf_svc::hal::gpio::{Output, OutputPin, PinDriver};
+use esp_idf_svc::hal::ledc::LedcDriver;
+use esp_idf_svc::sys::EspError;
+use one_wire_bus::Address;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ControlMode {
+    /// Full power below `setpoint - hysteresis / 2`, off above `setpoint + hysteresis / 2`.
+    Hysteresis { hysteresis: f32 },
+    /// Continuous power, makes sense only with PWM output.
+    Pid(PidConfig),
+}
+
+#[derive(Debug, Clone)]
+pub struct ThermostatConfig {
+    /// Target temperature, Celsius.
+    pub setpoint: f32,
+    pub mode: ControlMode,
+    /// Heater stays on at least this long once turned on.
+    pub min_on_time: Duration,
+    /// Heater stays off at least this long once turned off.
+    pub min_off_time: Duration,
+    /// Power `0.0..=1.0` while temperature is unknown, min on/off times are ignored.
+    pub fail_safe_power: f32,
+    /// Readings older than this are treated as sensor failure.
+    pub max_sensor_age: Duration,
+}
+
+impl Default for ThermostatConfig {
+    fn default() -> Self {
+        ThermostatConfig {
+            setpoint: 21.0,
+            mode: ControlMode::Hysteresis { hysteresis: 1.0 },
+            min_on_time: Duration::from_secs(60),
+            min_off_time: Duration::from_secs(60),
+            fail_safe_power: 0.0,
+            max_sensor_age: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PidConfig {
+    /// Power per degree of error.
+    pub kp: f32,
+    /// Power per degree of error per second.
+    pub ki: f32,
+    /// Power per degree per second of temperature change.
+    pub kd: f32,
+}
+
+/// PID with output `0.0..=1.0`.
+///
+/// Integral isn't accumulated while the output is saturated in the same direction (anti-windup),
+/// derivative is taken from measurement, so setpoint changes don't kick the output.
+#[derive(Debug)]
+pub struct Pid {
+    config: PidConfig,
+    /// Integral term in the output units.
+    integral: f32,
+    last_measurement: Option<f32>,
+}
+
+impl Pid {
+    pub fn new(config: PidConfig) -> Self {
+        Pid {
+            config,
+            integral: 0.0,
+            last_measurement: None,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.integral = 0.0;
+        self.last_measurement = None;
+    }
+
+    pub fn update(&mut self, setpoint: f32, measurement: f32, dt: Duration) -> f32 {
+        let dt = dt.as_secs_f32();
+        let error = setpoint - measurement;
+        let derivative = match self.last_measurement {
+            Some(last) if dt > 0.0 => -(measurement - last) / dt,
+            _ => 0.0,
+        };
+        self.last_measurement = Some(measurement);
+
+        let proportional = self.config.kp * error + self.config.kd * derivative;
+        let integral = self.integral + self.config.ki * error * dt;
+        let output = proportional + integral;
+        let winding_up = (output > 1.0 && error > 0.0) || (output < 0.0 && error < 0.0);
+        if !winding_up {
+            self.integral = integral.clamp(0.0, 1.0);
+        }
+        (proportional + self.integral).clamp(0.0, 1.0)
+    }
+}
+
+/// Control logic of the thermostat, see [`Self::update`].
+#[derive(Debug)]
+pub struct Controller {
+    config: ThermostatConfig,
+    pid: Option<Pid>,
+    power: f32,
+    switched_at: Option<Instant>,
+    last_update: Option<Instant>,
+    fail_safe: bool,
+}
+
+impl Controller {
+    pub fn new(config: ThermostatConfig) -> Self {
+        let pid = match config.mode {
+            ControlMode::Pid(pid) => Some(Pid::new(pid)),
+            ControlMode::Hysteresis { .. } => None,
+        };
+        Controller {
+            config,
+            pid,
+            power: 0.0,
+            switched_at: None,
+            last_update: None,
+            fail_safe: false,
+        }
+    }
+
+    pub fn setpoint(&self) -> f32 {
+        self.config.setpoint
+    }
+
+    pub fn set_setpoint(&mut self, setpoint: f32) {
+        self.config.setpoint = setpoint;
+    }
+
+    /// Current output power `0.0..=1.0`.
+    pub fn power(&self) -> f32 {
+        self.power
+    }
+
+    /// `true` while the temperature is unknown.
+    pub fn is_fail_safe(&self) -> bool {
+        self.fail_safe
+    }
+
+    /// Should be called periodically, `None` means the temperature is unknown.
+    /// Returns output power `0.0..=1.0`.
+    pub fn update(&mut self, temp: Option<f32>, now: Instant) -> f32 {
+        let dt = self
+            .last_update
+            .map(|last| now.saturating_duration_since(last))
+            .unwrap_or_default();
+        self.last_update = Some(now);
+
+        let Some(temp) = temp.filter(|t| t.is_finite()) else {
+            if !self.fail_safe {
+                log::warn!(
+                    "Temperature is unknown, fail-safe power {}",
+                    self.config.fail_safe_power
+                );
+                self.fail_safe = true;
+            }
+            if let Some(pid) = &mut self.pid {
+                pid.reset();
+            }
+            self.switch(self.config.fail_safe_power, now, true);
+            return self.power;
+        };
+        if self.fail_safe {
+            log::info!("Temperature is back, {temp:.2}");
+            self.fail_safe = false;
+        }
+
+        let setpoint = self.config.setpoint;
+        let power = match (&mut self.pid, self.config.mode) {
+            (Some(pid), _) => pid.update(setpoint, temp, dt),
+            (None, ControlMode::Hysteresis { hysteresis }) => {
+                if temp < setpoint - hysteresis / 2.0 {
+                    1.0
+                } else if temp > setpoint + hysteresis / 2.0 {
+                    0.0
+                } else {
+                    self.power
+                }
+            }
+            (None, ControlMode::Pid(_)) => unreachable!("PID is created for PID mode"),
+        };
+        self.switch(power, now, false);
+        self.power
+    }
+
+    /// Changes power unless min on/off time holds the current state, `force` ignores them.
+    fn switch(&mut self, power: f32, now: Instant, force: bool) {
+        let power = power.clamp(0.0, 1.0);
+        let (on, was_on) = (power > 0.0, self.power > 0.0);
+        if on != was_on {
+            let min_time = if was_on {
+                self.config.min_on_time
+            } else {
+                self.config.min_off_time
+            };
+            let held = self
+                .switched_at
+                .map(|at| now.saturating_duration_since(at) < min_time)
+                .unwrap_or(false);
+            if held && !force {
+                return;
+            }
+            self.switched_at = Some(now);
+        }
+        self.power = power;
+    }
+}
+
+/// Anything that heats with power `0.0..=1.0`.
+pub trait HeaterOutput {
+    fn set_power(&mut self, power: f32) -> Result<(), EspError>;
+}
+
+/// Relay is on for any non-zero power.
+pub struct Relay<'d, T: OutputPin> {
+    pin: PinDriver<'d, T, Output>,
+    /// Most relay modules are switched on by the low level.
+    active_low: bool,
+}
+
+impl<'d, T: OutputPin> Relay<'d, T> {
+    /// Relay is turned off immediately.
+    pub fn new(pin: PinDriver<'d, T, Output>, active_low: bool) -> Result<Self, EspError> {
+        let mut relay = Relay { pin, active_low };
+        relay.set_power(0.0)?;
+        Ok(relay)
+    }
+}
+
+impl<'d, T: OutputPin> HeaterOutput for Relay<'d, T> {
+    fn set_power(&mut self, power: f32) -> Result<(), EspError> {
+        if (power > 0.0) != self.active_low {
+            self.pin.set_high()
+        } else {
+            self.pin.set_low()
+        }
+    }
+}
+
+/// Power is the PWM duty.
+impl<'d> HeaterOutput for LedcDriver<'d> {
+    fn set_power(&mut self, power: f32) -> Result<(), EspError> {
+        let duty = self.get_max_duty() as f32 * power.clamp(0.0, 1.0);
+        self.set_duty(duty.round() as u32)
+    }
+}
+
+/// Thermostat controlled by one sensor from the acquisition thread.
+pub struct Thermostat<O> {
+    controller: Controller,
+    output: O,
+    sensor: Address,
+}
+
+impl<O: HeaterOutput> Thermostat<O> {
+    /// Output is set to the fail-safe power until the first reading.
+    pub fn new(config: ThermostatConfig, sensor: Address, mut output: O) -> Result<Self, EspError> {
+        output.set_power(config.fail_safe_power)?;
+        Ok(Thermostat {
+            controller: Controller::new(config),
+            output,
+            sensor,
+        })
+    }
+
+    pub fn controller(&self) -> &Controller {
+        &self.controller
+    }
+
+    pub fn controller_mut(&mut self) -> &mut Controller {
+        &mut self.controller
+    }
+
+    /// Should be called periodically, at least as often as the sensor is read.
+    /// Returns output power `0.0..=1.0`.
+    pub fn update(&mut self, snapshot: &TempSnapshot, now: Instant) -> Result<f32, EspError> {
+        let max_age = self.controller.config.max_sensor_age;
+        let temp = snapshot
+            .get(&self.sensor)
+            .filter(|sensor| sensor.status != SensorStatus::Offline)
+            .filter(|sensor| {
+                sensor
+                    .read_at
+                    .map(|at| now.saturating_duration_since(at) <= max_age)
+                    .unwrap_or(false)
+            })
+            .and_then(|sensor| sensor.temperature);
+
+        let power = self.controller.update(temp, now);
+        self.output.set_power(power)?;
+        Ok(power)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::thermostat::{ControlMode, Controller, Pid, PidConfig, ThermostatConfig};
+    use std::time::{Duration, Instant};
+
+    /// Room heated by the heater and cooled by the ambient air.
+    struct Room {
+        temp: f32,
+        ambient: f32,
+        /// Degrees per second at full power.
+        heating: f32,
+        /// Fraction of the difference with the ambient lost per second.
+        loss: f32,
+    }
+
+    impl Room {
+        fn step(&mut self, power: f32, dt: f32) {
+            self.temp += (power * self.heating - (self.temp - self.ambient) * self.loss) * dt;
+        }
+    }
+
+    fn room() -> Room {
+        Room {
+            temp: 15.0,
+            ambient: 10.0,
+            heating: 0.05,
+            loss: 0.001,
+        }
+    }
+
+    /// Runs the loop every second, returns temperatures.
+    fn simulate(
+        controller: &mut Controller,
+        room: &mut Room,
+        start: Instant,
+        secs: u64,
+    ) -> Vec<f32> {
+        let mut temps = Vec::new();
+        for sec in 0..secs {
+            let power = controller.update(Some(room.temp), start + Duration::from_secs(sec));
+            room.step(power, 1.0);
+            temps.push(room.temp);
+        }
+        temps
+    }
+
+    #[test]
+    fn hysteresis_test() {
+        let config = ThermostatConfig {
+            min_on_time: Duration::ZERO,
+            min_off_time: Duration::ZERO,
+            ..Default::default()
+        };
+        let mut controller = Controller::new(config);
+        let mut room = room();
+        let temps = simulate(&mut controller, &mut room, Instant::now(), 3 * 3600);
+
+        // after heating up stays in the band
+        let settled = &temps[3600..];
+        let min = settled.iter().copied().fold(f32::INFINITY, f32::min);
+        let max = settled.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        assert!(min > 20.4 && max < 21.6, "{min}..{max}");
+    }
+
+    #[test]
+    fn min_on_off_time_test() {
+        let config = ThermostatConfig {
+            min_on_time: Duration::from_secs(60),
+            min_off_time: Duration::from_secs(120),
+            ..Default::default()
+        };
+        let mut controller = Controller::new(config);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert_eq!(controller.update(Some(19.0), at(0)), 1.0);
+        // too hot, but min on time holds
+        assert_eq!(controller.update(Some(23.0), at(30)), 1.0);
+        assert_eq!(controller.update(Some(23.0), at(60)), 0.0);
+        // too cold, but min off time holds
+        assert_eq!(controller.update(Some(19.0), at(120)), 0.0);
+        assert_eq!(controller.update(Some(19.0), at(180)), 1.0);
+    }
+
+    #[test]
+    fn fail_safe_test() {
+        let config = ThermostatConfig {
+            fail_safe_power: 0.2,
+            ..Default::default()
+        };
+        let mut controller = Controller::new(config);
+        let start = Instant::now();
+
+        assert_eq!(controller.update(Some(25.0), start), 0.0);
+        // ignores min off time
+        assert_eq!(controller.update(None, start + Duration::from_secs(1)), 0.2);
+        assert!(controller.is_fail_safe());
+        assert_eq!(
+            controller.update(Some(f32::NAN), start + Duration::from_secs(2)),
+            0.2
+        );
+        assert_eq!(
+            controller.update(Some(25.0), start + Duration::from_secs(3)),
+            0.2
+        );
+        assert!(!controller.is_fail_safe());
+    }
+
+    #[test]
+    fn pid_test() {
+        let config = ThermostatConfig {
+            mode: ControlMode::Pid(PidConfig {
+                kp: 0.5,
+                ki: 0.002,
+                kd: 5.0,
+            }),
+            min_on_time: Duration::ZERO,
+            min_off_time: Duration::ZERO,
+            ..Default::default()
+        };
+        let mut controller = Controller::new(config);
+        let mut room = room();
+        let temps = simulate(&mut controller, &mut room, Instant::now(), 4 * 3600);
+
+        let max = temps.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        assert!(max < 21.5, "overshoot {max}");
+        let last = *temps.last().unwrap();
+        assert!((last - 21.0).abs() < 0.1, "{last}");
+    }
+
+    #[test]
+    fn pid_anti_windup_test() {
+        let mut pid = Pid::new(PidConfig {
+            kp: 1.0,
+            ki: 0.1,
+            kd: 0.0,
+        });
+        // saturated for a long time
+        for _ in 0..1000 {
+            assert_eq!(pid.update(21.0, 10.0, Duration::from_secs(1)), 1.0);
+        }
+        // integral didn't grow without limit, output drops as soon as the setpoint is passed
+        assert!(pid.update(21.0, 22.0, Duration::from_secs(1)) < 0.1);
+    }
+}
@@ -0,0 +1,566 @@
+//! WiFi station that stays connected.
+//!
+//! * picks the best known network in range, see [`networks`]
+//! * reconnects with exponential backoff and jitter when the AP goes away
+//! * connection state, IP and RSSI are shared with other threads through [`WifiHandle`]
+//! * subscribers are notified on up/down transitions
+//! * access point with a setup form when nothing is known, see [`provisioning`]
+//! * static IP or DHCP hostname, see [`netif`], and mDNS announcement, see [`mdns`]
+//!
+//! [`WifiManager`] wraps `BlockingWifi` and runs in its own thread,
+//! [`asynch::AsyncWifiManager`] does the same for `AsyncWifi` (`embassy` feature).
+//! [`connect_from_config`] is the usual setup: stored networks with one from the config.
+
+use crate::lock_recover;
+use esp_idf_svc::eventloop::{EspSystemEventLoop, EspSystemSubscription};
+use esp_idf_svc::hal::delay::FreeRtos;
+use esp_idf_svc::hal::modem::Modem;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs};
+use esp_idf_svc::sys::{EspError, ESP_ERR_NOT_FOUND};
+use esp_idf_svc::wifi::{BlockingWifi, ClientConfiguration, Configuration, EspWifi, WifiEvent};
+use netif::NetifConfig;
+use networks::{Candidate, KnownNetwork, NetworkStore, NetworksError};
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "embassy")]
+pub mod asynch;
+pub mod mdns;
+pub mod netif;
+pub mod networks;
+pub mod provisioning;
+
+/// How often the manager checks the connection.
+const POLL_PERIOD: Duration = Duration::from_millis(500);
+/// Logging with formatting doesn't fit into 4K.
+const STACK_SIZE: usize = 6144;
+
+#[derive(Debug, Clone)]
+pub struct WifiConfig {
+    /// Networks to choose from, see [`networks::select`].
+    pub networks: Vec<KnownNetwork>,
+    pub backoff: BackoffConfig,
+    /// How often RSSI is refreshed while connected.
+    pub rssi_period: Duration,
+    /// DHCP by default.
+    pub netif: NetifConfig,
+}
+
+impl WifiConfig {
+    pub fn new(networks: Vec<KnownNetwork>) -> Self {
+        WifiConfig {
+            networks,
+            backoff: BackoffConfig::default(),
+            rssi_period: Duration::from_secs(10),
+            netif: NetifConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BackoffConfig {
+    /// Delay after the first failure.
+    pub initial: Duration,
+    /// Delay doubles after each failure up to this.
+    pub max: Duration,
+    /// Delay is randomly changed by this fraction, so devices don't reconnect all at once
+    /// after the AP reboot.
+    pub jitter: f32,
+}
+
+impl Default for BackoffConfig {
+    fn default() -> Self {
+        BackoffConfig {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(5 * 60),
+            jitter: 0.25,
+        }
+    }
+}
+
+/// Exponential backoff with jitter.
+#[derive(Debug)]
+pub struct Backoff {
+    config: BackoffConfig,
+    attempts: u32,
+}
+
+impl Backoff {
+    pub fn new(config: BackoffConfig) -> Self {
+        Backoff {
+            config,
+            attempts: 0,
+        }
+    }
+
+    /// Failed attempts since the last success.
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+    }
+
+    /// Delay before the next attempt, `random` is any random number (`esp_random`).
+    pub fn next_delay(&mut self, random: u32) -> Duration {
+        let exp = self.attempts.min(16);
+        self.attempts = self.attempts.saturating_add(1);
+
+        let delay = self
+            .config
+            .initial
+            .saturating_mul(1 << exp)
+            .min(self.config.max);
+        let jitter = self.config.jitter.clamp(0.0, 1.0) as f64;
+        // -jitter..=jitter
+        let factor = 1.0 - jitter + 2.0 * jitter * (random as f64 / u32::MAX as f64);
+        delay.mul_f64(factor)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WifiState {
+    #[default]
+    Connecting,
+    Connected {
+        ip: Ipv4Addr,
+    },
+    /// Waiting for the next attempt.
+    Disconnected {
+        /// Failed attempts since the last connection.
+        attempts: u32,
+    },
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct WifiStatus {
+    pub state: WifiState,
+    /// Network of the current connection.
+    pub ssid: Option<String>,
+    /// Signal strength of the AP, dBm.
+    pub rssi: Option<i8>,
+    /// Times the connection was lost.
+    pub disconnects: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    Up(Ipv4Addr),
+    Down,
+}
+
+type Subscriber = Box<dyn Fn(ConnectionEvent) + Send>;
+
+/// Cheap to clone handle to the connection status.
+#[derive(Clone, Default)]
+pub struct WifiHandle {
+    status: Arc<Mutex<WifiStatus>>,
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+}
+
+impl WifiHandle {
+    pub fn status(&self) -> WifiStatus {
+        self.lock().clone()
+    }
+
+    pub fn ip(&self) -> Option<Ipv4Addr> {
+        match self.lock().state {
+            WifiState::Connected { ip } => Some(ip),
+            _ => None,
+        }
+    }
+
+    pub fn is_up(&self) -> bool {
+        self.ip().is_some()
+    }
+
+    /// `callback` is called from the manager thread on every up/down transition,
+    /// it should be quick.
+    pub fn subscribe(&self, callback: impl Fn(ConnectionEvent) + Send + 'static) {
+        lock_recover(&self.subscribers).push(Box::new(callback));
+    }
+
+    /// Blocks until connected, returns `false` on timeout.
+    pub fn wait_up(&self, timeout: Duration) -> bool {
+        let start = Instant::now();
+        while !self.is_up() {
+            if start.elapsed() >= timeout {
+                return false;
+            }
+            FreeRtos::delay_ms(100);
+        }
+        true
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, WifiStatus> {
+        lock_recover(&self.status)
+    }
+
+    fn set_connecting(&self) {
+        self.lock().state = WifiState::Connecting;
+    }
+
+    fn set_up(&self, ip: Ipv4Addr, ssid: &str, rssi: Option<i8>) {
+        {
+            let mut status = self.lock();
+            status.state = WifiState::Connected { ip };
+            status.ssid = Some(ssid.to_string());
+            status.rssi = rssi;
+        }
+        self.notify(ConnectionEvent::Up(ip));
+    }
+
+    fn set_down(&self, attempts: u32) {
+        let was_up = {
+            let mut status = self.lock();
+            let was_up = matches!(status.state, WifiState::Connected { .. });
+            if was_up {
+                status.disconnects += 1;
+            }
+            status.state = WifiState::Disconnected { attempts };
+            status.ssid = None;
+            status.rssi = None;
+            was_up
+        };
+        if was_up {
+            self.notify(ConnectionEvent::Down);
+        }
+    }
+
+    fn set_rssi(&self, rssi: Option<i8>) {
+        self.lock().rssi = rssi;
+    }
+
+    fn notify(&self, event: ConnectionEvent) {
+        let subscribers = lock_recover(&self.subscribers);
+        for subscriber in subscribers.iter() {
+            subscriber(event);
+        }
+    }
+}
+
+/// Timing of reconnects and RSSI refreshes, shared by blocking and async managers.
+#[derive(Debug)]
+struct Schedule {
+    backoff: Backoff,
+    rssi_period: Duration,
+    retry_at: Option<Instant>,
+    rssi_at: Option<Instant>,
+}
+
+impl Schedule {
+    fn new(config: &WifiConfig) -> Self {
+        Schedule {
+            backoff: Backoff::new(config.backoff.clone()),
+            rssi_period: config.rssi_period,
+            retry_at: None,
+            rssi_at: None,
+        }
+    }
+
+    fn should_connect(&self, now: Instant) -> bool {
+        self.retry_at.map(|at| now >= at).unwrap_or(true)
+    }
+
+    fn connected(&mut self, now: Instant) {
+        self.backoff.reset();
+        self.retry_at = None;
+        self.rssi_at = Some(now + self.rssi_period);
+    }
+
+    /// Connection failed or lost, returns delay before the next attempt.
+    fn failed(&mut self, now: Instant) -> Duration {
+        let delay = self.backoff.next_delay(random());
+        self.retry_at = Some(now + delay);
+        delay
+    }
+
+    fn should_refresh_rssi(&mut self, now: Instant) -> bool {
+        match self.rssi_at {
+            Some(at) if now >= at => {
+                self.rssi_at = Some(now + self.rssi_period);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+fn random() -> u32 {
+    unsafe { esp_idf_svc::sys::esp_random() }
+}
+
+/// Sets the flag when the station loses the AP, the manager loop picks it up.
+fn subscribe_link_lost(
+    sys_loop: &EspSystemEventLoop,
+) -> Result<(Arc<AtomicBool>, EspSystemSubscription<'static>), EspError> {
+    let link_lost = Arc::new(AtomicBool::new(false));
+    let flag = link_lost.clone();
+    let subscription = sys_loop.subscribe::<WifiEvent, _>(move |event| {
+        if *event == WifiEvent::StaDisconnected {
+            flag.store(true, Ordering::Relaxed);
+        }
+    })?;
+    Ok((link_lost, subscription))
+}
+
+/// Keeps the station connected, see [`Self::spawn`].
+pub struct WifiManager {
+    wifi: BlockingWifi<EspWifi<'static>>,
+    networks: Vec<KnownNetwork>,
+    handle: WifiHandle,
+    schedule: Schedule,
+    link_lost: Arc<AtomicBool>,
+    _subscription: EspSystemSubscription<'static>,
+}
+
+impl WifiManager {
+    /// Starts the station, doesn't connect yet.
+    pub fn new(
+        mut wifi: BlockingWifi<EspWifi<'static>>,
+        sys_loop: &EspSystemEventLoop,
+        config: WifiConfig,
+    ) -> Result<Self, EspError> {
+        let (link_lost, subscription) = subscribe_link_lost(sys_loop)?;
+        netif::apply(wifi.wifi_mut(), &config.netif)?;
+        // the real network is chosen by the scan
+        wifi.set_configuration(&Configuration::Client(ClientConfiguration::default()))?;
+        wifi.start()?;
+        log::info!("Wifi started");
+
+        Ok(WifiManager {
+            wifi,
+            networks: config.networks.clone(),
+            handle: WifiHandle::default(),
+            schedule: Schedule::new(&config),
+            link_lost,
+            _subscription: subscription,
+        })
+    }
+
+    pub fn handle(&self) -> WifiHandle {
+        self.handle.clone()
+    }
+
+    /// Replaces known networks, they are used on the next connect.
+    pub fn set_networks(&mut self, networks: Vec<KnownNetwork>) {
+        self.networks = networks;
+    }
+
+    /// Checks the connection and reconnects if it's time, should be called periodically.
+    pub fn poll(&mut self) {
+        let now = Instant::now();
+        if self.handle.is_up() {
+            let lost = self.link_lost.swap(false, Ordering::Relaxed);
+            if lost || !self.wifi.is_connected().unwrap_or(false) {
+                let delay = self.schedule.failed(now);
+                log::warn!("Wifi connection lost, reconnecting in {delay:?}");
+                self.handle.set_down(self.schedule.backoff.attempts());
+            } else if self.schedule.should_refresh_rssi(now) {
+                let rssi = self.wifi.wifi_mut().driver_mut().get_ap_info().ok();
+                self.handle.set_rssi(rssi.map(|ap| ap.signal_strength));
+            }
+        } else if self.schedule.should_connect(now) {
+            self.handle.set_connecting();
+            match self.connect() {
+                Ok(ip) => {
+                    self.schedule.connected(Instant::now());
+                    log::info!("Wifi is up, ip {ip}");
+                }
+                Err(err) => {
+                    let _ = self.wifi.disconnect();
+                    let delay = self.schedule.failed(Instant::now());
+                    log::warn!("Wifi connection failed: {err}, next attempt in {delay:?}");
+                    self.handle.set_down(self.schedule.backoff.attempts());
+                }
+            }
+        }
+    }
+
+    /// Gives the driver back, e.g. for [`provisioning::run`].
+    pub fn into_wifi(self) -> BlockingWifi<EspWifi<'static>> {
+        self.wifi
+    }
+
+    /// Runs the manager in its own thread forever.
+    pub fn spawn(mut self) -> std::io::Result<(WifiHandle, JoinHandle<()>)> {
+        let handle = self.handle();
+        let thread = std::thread::Builder::new()
+            .name("wifi".to_string())
+            .stack_size(STACK_SIZE)
+            .spawn(move || loop {
+                self.poll();
+                FreeRtos::delay_ms(POLL_PERIOD.as_millis() as u32);
+            })?;
+        Ok((handle, thread))
+    }
+
+    /// Tries known networks in range, the best first.
+    fn connect(&mut self) -> Result<Ipv4Addr, EspError> {
+        let scan = self.wifi.scan()?;
+        let candidates = networks::select(&self.networks, &scan);
+        if candidates.is_empty() {
+            log::warn!("Wifi: no known networks in range");
+        }
+
+        let mut result = Err(EspError::from_infallible::<ESP_ERR_NOT_FOUND>());
+        for candidate in candidates {
+            log_candidate(&candidate);
+            result = self.connect_to(&candidate);
+            match &result {
+                Ok(_) => break,
+                Err(err) => {
+                    log::warn!("Wifi '{}' failed: {err}", candidate.ssid);
+                    let _ = self.wifi.disconnect();
+                }
+            }
+        }
+        result
+    }
+
+    fn connect_to(&mut self, candidate: &Candidate) -> Result<Ipv4Addr, EspError> {
+        self.wifi
+            .set_configuration(&candidate.client_configuration()?)?;
+        self.wifi.connect()?;
+        self.wifi.wait_netif_up()?;
+        self.link_lost.store(false, Ordering::Relaxed);
+
+        let ip = self.wifi.wifi().sta_netif().get_ip_info()?.ip;
+        let rssi = self.wifi.wifi_mut().driver_mut().get_ap_info().ok();
+        self.handle
+            .set_up(ip, &candidate.ssid, rssi.map(|ap| ap.signal_strength));
+        Ok(ip)
+    }
+}
+
+#[derive(Debug)]
+pub enum WifiError {
+    Esp(EspError),
+    Networks(NetworksError),
+    /// The manager thread can't be spawned.
+    Thread(std::io::Error),
+}
+
+impl From<EspError> for WifiError {
+    fn from(err: EspError) -> Self {
+        WifiError::Esp(err)
+    }
+}
+
+impl From<NetworksError> for WifiError {
+    fn from(err: NetworksError) -> Self {
+        WifiError::Networks(err)
+    }
+}
+
+impl From<std::io::Error> for WifiError {
+    fn from(err: std::io::Error) -> Self {
+        WifiError::Thread(err)
+    }
+}
+
+impl fmt::Display for WifiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WifiError::Esp(err) => write!(f, "wifi error: {err}"),
+            WifiError::Networks(err) => write!(f, "{err}"),
+            WifiError::Thread(err) => write!(f, "can't spawn the wifi thread: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for WifiError {}
+
+/// Starts a [`WifiManager`] thread with the networks stored in NVS, `ssid` and `password`
+/// from the config are the default network until something is stored.
+/// Doesn't wait for the connection, see [`WifiHandle::wait_up`].
+pub fn connect_from_config(
+    modem: Modem,
+    sys_loop: &EspSystemEventLoop,
+    nvs: EspDefaultNvsPartition,
+    ssid: &str,
+    password: &str,
+    netif: NetifConfig,
+) -> Result<(WifiHandle, JoinHandle<()>), WifiError> {
+    let wifi = BlockingWifi::wrap(
+        EspWifi::new(modem, sys_loop.clone(), Some(nvs.clone()))?,
+        sys_loop.clone(),
+    )?;
+    let defaults = [KnownNetwork::new(ssid, password, 0)?];
+    let store = NetworkStore::load(EspNvs::new(nvs, networks::NVS_NAMESPACE, true)?, &defaults)?;
+    let config = WifiConfig {
+        netif,
+        ..WifiConfig::new(store.networks().to_vec())
+    };
+    Ok(WifiManager::new(wifi, sys_loop, config)?.spawn()?)
+}
+
+fn log_candidate(candidate: &Candidate) {
+    log::info!(
+        "Wifi connecting to '{}', {:?}, {} dBm",
+        candidate.ssid,
+        candidate.auth_method,
+        candidate.rssi
+    );
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::wifi::{Backoff, BackoffConfig, ConnectionEvent, WifiHandle, WifiState};
+    use std::net::Ipv4Addr;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[test]
+    fn backoff_test() {
+        let mut backoff = Backoff::new(BackoffConfig {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(10),
+            jitter: 0.5,
+        });
+        let middle = u32::MAX / 2;
+
+        let delays = (0..6)
+            .map(|_| backoff.next_delay(middle).as_secs_f32().round() as u32)
+            .collect::<Vec<_>>();
+        assert_eq!(delays, vec![1, 2, 4, 8, 10, 10]);
+        assert_eq!(backoff.attempts(), 6);
+
+        backoff.reset();
+        assert_eq!(backoff.next_delay(0), Duration::from_millis(500));
+        assert_eq!(backoff.next_delay(u32::MAX), Duration::from_secs(3));
+    }
+
+    #[test]
+    fn handle_test() {
+        let handle = WifiHandle::default();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = events.clone();
+        handle.subscribe(move |event| sink.lock().unwrap().push(event));
+
+        let ip = Ipv4Addr::new(192, 168, 1, 10);
+        handle.set_down(1);
+        handle.set_connecting();
+        handle.set_up(ip, "lab", Some(-60));
+        assert_eq!(handle.ip(), Some(ip));
+        assert_eq!(handle.status().ssid.as_deref(), Some("lab"));
+        assert_eq!(handle.status().rssi, Some(-60));
+
+        handle.set_down(0);
+        handle.set_down(1);
+        let status = handle.status();
+        assert_eq!(status.state, WifiState::Disconnected { attempts: 1 });
+        assert_eq!((status.rssi, status.disconnects), (None, 1));
+
+        // down is reported only once, when the connection is lost
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![ConnectionEvent::Up(ip), ConnectionEvent::Down]
+        );
+    }
+}
@@ -0,0 +1,134 @@
+//! [`super::WifiManager`] for `AsyncWifi`, runs as a task of an async executor.
+
+use super::netif;
+use super::networks::{self, Candidate, KnownNetwork};
+use super::{log_candidate, subscribe_link_lost, Schedule, WifiConfig, WifiHandle, POLL_PERIOD};
+use esp_idf_svc::eventloop::{EspSystemEventLoop, EspSystemSubscription};
+use esp_idf_svc::sys::{EspError, ESP_ERR_NOT_FOUND};
+use esp_idf_svc::wifi::{AsyncWifi, ClientConfiguration, Configuration, EspWifi};
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Keeps the station connected, see [`Self::run`].
+pub struct AsyncWifiManager {
+    wifi: AsyncWifi<EspWifi<'static>>,
+    networks: Vec<KnownNetwork>,
+    handle: WifiHandle,
+    schedule: Schedule,
+    link_lost: Arc<AtomicBool>,
+    _subscription: EspSystemSubscription<'static>,
+}
+
+impl AsyncWifiManager {
+    /// Starts the station, doesn't connect yet.
+    pub async fn new(
+        mut wifi: AsyncWifi<EspWifi<'static>>,
+        sys_loop: &EspSystemEventLoop,
+        config: WifiConfig,
+    ) -> Result<Self, EspError> {
+        let (link_lost, subscription) = subscribe_link_lost(sys_loop)?;
+        netif::apply(wifi.wifi_mut(), &config.netif)?;
+        // the real network is chosen by the scan
+        wifi.set_configuration(&Configuration::Client(ClientConfiguration::default()))?;
+        wifi.start().await?;
+        log::info!("Wifi started");
+
+        Ok(AsyncWifiManager {
+            wifi,
+            networks: config.networks.clone(),
+            handle: WifiHandle::default(),
+            schedule: Schedule::new(&config),
+            link_lost,
+            _subscription: subscription,
+        })
+    }
+
+    pub fn handle(&self) -> WifiHandle {
+        self.handle.clone()
+    }
+
+    /// Replaces known networks, they are used on the next connect.
+    pub fn set_networks(&mut self, networks: Vec<KnownNetwork>) {
+        self.networks = networks;
+    }
+
+    /// Checks the connection and reconnects if it's time, see [`super::WifiManager::poll`].
+    pub async fn poll(&mut self) {
+        let now = Instant::now();
+        if self.handle.is_up() {
+            let lost = self.link_lost.swap(false, Ordering::Relaxed);
+            if lost || !self.wifi.is_connected().unwrap_or(false) {
+                let delay = self.schedule.failed(now);
+                log::warn!("Wifi connection lost, reconnecting in {delay:?}");
+                self.handle.set_down(self.schedule.backoff.attempts());
+            } else if self.schedule.should_refresh_rssi(now) {
+                let rssi = self.wifi.wifi_mut().driver_mut().get_ap_info().ok();
+                self.handle.set_rssi(rssi.map(|ap| ap.signal_strength));
+            }
+        } else if self.schedule.should_connect(now) {
+            self.handle.set_connecting();
+            match self.connect().await {
+                Ok(ip) => {
+                    self.schedule.connected(Instant::now());
+                    log::info!("Wifi is up, ip {ip}");
+                }
+                Err(err) => {
+                    let _ = self.wifi.disconnect().await;
+                    let delay = self.schedule.failed(Instant::now());
+                    log::warn!("Wifi connection failed: {err}, next attempt in {delay:?}");
+                    self.handle.set_down(self.schedule.backoff.attempts());
+                }
+            }
+        }
+    }
+
+    /// Keeps the connection forever, spawn it as a separate task.
+    pub async fn run(mut self) {
+        loop {
+            self.poll().await;
+            embassy_time::Timer::after(embassy_time::Duration::from_millis(
+                POLL_PERIOD.as_millis() as u64,
+            ))
+            .await;
+        }
+    }
+
+    /// Tries known networks in range, the best first.
+    async fn connect(&mut self) -> Result<Ipv4Addr, EspError> {
+        let scan = self.wifi.scan().await?;
+        let candidates = networks::select(&self.networks, &scan);
+        if candidates.is_empty() {
+            log::warn!("Wifi: no known networks in range");
+        }
+
+        let mut result = Err(EspError::from_infallible::<ESP_ERR_NOT_FOUND>());
+        for candidate in candidates {
+            log_candidate(&candidate);
+            result = self.connect_to(&candidate).await;
+            match &result {
+                Ok(_) => break,
+                Err(err) => {
+                    log::warn!("Wifi '{}' failed: {err}", candidate.ssid);
+                    let _ = self.wifi.disconnect().await;
+                }
+            }
+        }
+        result
+    }
+
+    async fn connect_to(&mut self, candidate: &Candidate) -> Result<Ipv4Addr, EspError> {
+        self.wifi
+            .set_configuration(&candidate.client_configuration()?)?;
+        self.wifi.connect().await?;
+        self.wifi.wait_netif_up().await?;
+        self.link_lost.store(false, Ordering::Relaxed);
+
+        let ip = self.wifi.wifi().sta_netif().get_ip_info()?.ip;
+        let rssi = self.wifi.wifi_mut().driver_mut().get_ap_info().ok();
+        self.handle
+            .set_up(ip, &candidate.ssid, rssi.map(|ap| ap.signal_strength));
+        Ok(ip)
+    }
+}
@@ -0,0 +1,99 @@
+//! mDNS announcement, the board answers as `<hostname>.local` and lists its services.
+//!
+//! ESP-IDF 5 ships mDNS as the `espressif/mdns` component, it's pulled in by
+//! `package.metadata.esp-idf-sys.extra_components` in `Cargo.toml`.
+
+use esp_idf_svc::mdns::EspMdns;
+use esp_idf_svc::sys::EspError;
+
+/// Service record, e.g. `_http._tcp` on port 80.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Service {
+    /// Defaults to the instance name of the device.
+    pub instance_name: Option<String>,
+    /// With the leading underscore, e.g. `_http`.
+    pub service_type: String,
+    /// `_tcp` or `_udp`.
+    pub proto: String,
+    pub port: u16,
+    pub txt: Vec<(String, String)>,
+}
+
+impl Service {
+    /// Leading underscores are added when missing.
+    pub fn new(service_type: &str, proto: &str, port: u16) -> Self {
+        Service {
+            instance_name: None,
+            service_type: underscored(service_type),
+            proto: underscored(proto),
+            port,
+            txt: Vec::new(),
+        }
+    }
+
+    pub fn http(port: u16) -> Self {
+        Service::new("_http", "_tcp", port)
+    }
+
+    pub fn with_txt(mut self, key: &str, value: &str) -> Self {
+        self.txt.push((key.to_string(), value.to_string()));
+        self
+    }
+}
+
+/// Starts the responder, the announcement stops when the returned value is dropped.
+pub fn advertise(
+    hostname: &str,
+    instance_name: &str,
+    services: &[Service],
+) -> Result<EspMdns, EspError> {
+    let mut mdns = EspMdns::take()?;
+    mdns.set_hostname(hostname)?;
+    mdns.set_instance_name(instance_name)?;
+    for service in services {
+        let txt = service
+            .txt
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect::<Vec<_>>();
+        mdns.add_service(
+            service.instance_name.as_deref(),
+            &service.service_type,
+            &service.proto,
+            service.port,
+            &txt,
+        )?;
+        log::info!(
+            "mDNS: {}.{} on {hostname}.local:{}",
+            service.service_type,
+            service.proto,
+            service.port
+        );
+    }
+    Ok(mdns)
+}
+
+fn underscored(name: &str) -> String {
+    if name.starts_with('_') {
+        name.to_string()
+    } else {
+        format!("_{name}")
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::wifi::mdns::Service;
+
+    #[test]
+    fn service_test() {
+        assert_eq!(Service::new("http", "tcp", 80), Service::http(80));
+        let service = Service::new("_sensors", "_udp", 5683).with_txt("board", "esp32-c3");
+        assert_eq!(service.service_type, "_sensors");
+        assert_eq!(service.proto, "_udp");
+        assert_eq!(
+            service.txt,
+            vec![("board".to_string(), "esp32-c3".to_string())]
+        );
+    }
+}
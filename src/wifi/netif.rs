@@ -0,0 +1,199 @@
+//! Station addressing: DHCP with a hostname or a static IPv4.
+//!
+//! The router shows the hostname in its client list, the same name is used for mDNS,
+//! see [`super::mdns`].
+
+use esp_idf_svc::ipv4::{
+    self, ClientConfiguration as IpClientConfiguration, ClientSettings, DHCPClientSettings, Mask,
+    Subnet,
+};
+use esp_idf_svc::netif::{EspNetif, NetifConfiguration, NetifStack};
+use esp_idf_svc::sys::{EspError, ESP_ERR_INVALID_ARG};
+use esp_idf_svc::wifi::EspWifi;
+use std::fmt;
+use std::net::Ipv4Addr;
+
+/// DHCP option 12 of ESP-IDF is limited to this.
+pub const MAX_HOSTNAME_LEN: usize = 30;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NetifError {
+    InvalidHostname(&'static str),
+    InvalidAddress(&'static str),
+}
+
+impl fmt::Display for NetifError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetifError::InvalidHostname(msg) => write!(f, "invalid hostname: {msg}"),
+            NetifError::InvalidAddress(msg) => write!(f, "invalid address: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for NetifError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StaticIp {
+    pub ip: Ipv4Addr,
+    pub gateway: Ipv4Addr,
+    pub mask: Mask,
+    pub dns: Option<Ipv4Addr>,
+    pub secondary_dns: Option<Ipv4Addr>,
+}
+
+impl StaticIp {
+    /// `netmask` is dotted, e.g. `255.255.255.0`, the gateway should be in the same subnet.
+    pub fn new(
+        ip: Ipv4Addr,
+        gateway: Ipv4Addr,
+        netmask: Ipv4Addr,
+        dns: Option<Ipv4Addr>,
+    ) -> Result<Self, NetifError> {
+        let mask = Mask::try_from(netmask)
+            .map_err(|_| NetifError::InvalidAddress("netmask should be contiguous"))?;
+        if mask.0 == 0 || mask.0 > 30 {
+            return Err(NetifError::InvalidAddress("netmask should be /1 - /30"));
+        }
+        let mask_bits = u32::from(netmask);
+        let host = u32::from(ip) & !mask_bits;
+        if host == 0 || host == !mask_bits {
+            return Err(NetifError::InvalidAddress(
+                "ip is the network or broadcast address",
+            ));
+        }
+        if u32::from(ip) & mask_bits != u32::from(gateway) & mask_bits || ip == gateway {
+            return Err(NetifError::InvalidAddress(
+                "gateway should be another address of the subnet",
+            ));
+        }
+        Ok(StaticIp {
+            ip,
+            gateway,
+            mask,
+            dns,
+            secondary_dns: None,
+        })
+    }
+}
+
+/// Default is DHCP without a hostname, the netif isn't touched then.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NetifConfig {
+    /// DHCP hostname, without `.local`.
+    pub hostname: Option<String>,
+    /// `None` for DHCP.
+    pub static_ip: Option<StaticIp>,
+}
+
+impl NetifConfig {
+    pub fn new(hostname: Option<&str>, static_ip: Option<StaticIp>) -> Result<Self, NetifError> {
+        if let Some(hostname) = hostname {
+            validate_hostname(hostname)?;
+        }
+        Ok(NetifConfig {
+            hostname: hostname.map(str::to_string),
+            static_ip,
+        })
+    }
+
+    /// Configuration of the station netif.
+    pub fn sta_configuration(&self) -> Result<NetifConfiguration, EspError> {
+        // too long hostname
+        let invalid = |_| EspError::from_infallible::<ESP_ERR_INVALID_ARG>();
+        let client = match &self.static_ip {
+            Some(ip) => IpClientConfiguration::Fixed(ClientSettings {
+                ip: ip.ip,
+                subnet: Subnet {
+                    gateway: ip.gateway,
+                    mask: ip.mask,
+                },
+                dns: ip.dns,
+                secondary_dns: ip.secondary_dns,
+            }),
+            None => IpClientConfiguration::DHCP(DHCPClientSettings {
+                hostname: match &self.hostname {
+                    Some(hostname) => Some(hostname.parse().map_err(invalid)?),
+                    None => None,
+                },
+            }),
+        };
+        Ok(NetifConfiguration {
+            ip_configuration: ipv4::Configuration::Client(client),
+            ..NetifConfiguration::wifi_default_client()
+        })
+    }
+}
+
+/// Replaces the station netif, call it before the wifi is started.
+pub fn apply(wifi: &mut EspWifi<'_>, config: &NetifConfig) -> Result<(), EspError> {
+    if *config == NetifConfig::default() {
+        return Ok(());
+    }
+    let sta = EspNetif::new_with_conf(&config.sta_configuration()?)?;
+    let ap = EspNetif::new(NetifStack::Ap)?;
+    wifi.swap_netif(sta, ap)?;
+    match &config.static_ip {
+        Some(ip) => log::info!(
+            "Wifi: static ip {}/{}, gateway {}",
+            ip.ip,
+            ip.mask,
+            ip.gateway
+        ),
+        None => log::info!("Wifi: DHCP hostname {:?}", config.hostname),
+    }
+    Ok(())
+}
+
+/// RFC 1123 label: letters, digits and `-`, not at the ends.
+fn validate_hostname(hostname: &str) -> Result<(), NetifError> {
+    if hostname.is_empty() || hostname.len() > MAX_HOSTNAME_LEN {
+        return Err(NetifError::InvalidHostname("should be 1-30 characters"));
+    }
+    if !hostname
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+    {
+        return Err(NetifError::InvalidHostname(
+            "only letters, digits and '-' are allowed",
+        ));
+    }
+    if hostname.starts_with('-') || hostname.ends_with('-') {
+        return Err(NetifError::InvalidHostname("can't start or end with '-'"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::wifi::netif::{NetifConfig, StaticIp};
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn static_ip_test() {
+        let ip = |s: &str| s.parse::<Ipv4Addr>().unwrap();
+        let mask = ip("255.255.255.0");
+
+        let config = StaticIp::new(ip("192.168.1.50"), ip("192.168.1.1"), mask, None).unwrap();
+        assert_eq!(config.mask.0, 24);
+        // gateway in another subnet
+        assert!(StaticIp::new(ip("192.168.1.50"), ip("192.168.2.1"), mask, None).is_err());
+        assert!(StaticIp::new(ip("192.168.1.1"), ip("192.168.1.1"), mask, None).is_err());
+        // network and broadcast
+        assert!(StaticIp::new(ip("192.168.1.0"), ip("192.168.1.1"), mask, None).is_err());
+        assert!(StaticIp::new(ip("192.168.1.255"), ip("192.168.1.1"), mask, None).is_err());
+        // holes in the mask
+        let holes = ip("255.0.255.0");
+        assert!(StaticIp::new(ip("192.168.1.50"), ip("192.168.1.1"), holes, None).is_err());
+    }
+
+    #[test]
+    fn hostname_test() {
+        assert!(NetifConfig::new(Some("lab-sensor-1"), None).is_ok());
+        assert!(NetifConfig::new(None, None).is_ok());
+        assert!(NetifConfig::new(Some(""), None).is_err());
+        assert!(NetifConfig::new(Some("-lab"), None).is_err());
+        assert!(NetifConfig::new(Some("lab.local"), None).is_err());
+        assert!(NetifConfig::new(Some(&"a".repeat(31)), None).is_err());
+    }
+}
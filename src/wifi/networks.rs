@@ -0,0 +1,328 @@
+//! Known WiFi networks with priorities, stored in NVS.
+//!
+//! On connect the manager scans the air, orders known networks found by priority and
+//! signal strength and tries them one by one. Auth method is taken from the scan result,
+//! so the same list works for open, WPA2 and WPA3 networks.
+
+use esp_idf_svc::nvs::{EspNvs, NvsDefault};
+use esp_idf_svc::sys::{EspError, ESP_ERR_INVALID_ARG};
+use esp_idf_svc::wifi::{AccessPointInfo, AuthMethod, ClientConfiguration, Configuration};
+use std::fmt;
+
+/// NVS namespace of the networks.
+pub const NVS_NAMESPACE: &str = "wifi";
+const NVS_KEY: &str = "networks";
+pub const MAX_SSID_LEN: usize = 32;
+pub const MAX_PASSWORD_LEN: usize = 64;
+/// Stored networks, the blob is at most 100 bytes per network.
+pub const MAX_NETWORKS: usize = 16;
+/// Bump when the blob layout changes.
+const FORMAT_VERSION: u8 = 1;
+
+#[derive(Debug)]
+pub enum NetworksError {
+    Nvs(EspError),
+    /// Stored blob can't be decoded.
+    Corrupted,
+    InvalidNetwork(&'static str),
+}
+
+impl From<EspError> for NetworksError {
+    fn from(err: EspError) -> Self {
+        NetworksError::Nvs(err)
+    }
+}
+
+impl fmt::Display for NetworksError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetworksError::Nvs(err) => write!(f, "NVS error: {err}"),
+            NetworksError::Corrupted => f.write_str("stored networks are corrupted"),
+            NetworksError::InvalidNetwork(msg) => write!(f, "invalid network: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for NetworksError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KnownNetwork {
+    pub ssid: String,
+    /// Empty for open networks.
+    pub password: String,
+    /// Higher is preferred, signal strength decides between equal priorities.
+    pub priority: u8,
+}
+
+impl KnownNetwork {
+    pub fn new(ssid: &str, password: &str, priority: u8) -> Result<Self, NetworksError> {
+        if ssid.is_empty() || ssid.len() > MAX_SSID_LEN {
+            return Err(NetworksError::InvalidNetwork("ssid should be 1-32 bytes"));
+        }
+        if password.len() > MAX_PASSWORD_LEN {
+            return Err(NetworksError::InvalidNetwork("password is too long"));
+        }
+        Ok(KnownNetwork {
+            ssid: ssid.to_string(),
+            password: password.to_string(),
+            priority,
+        })
+    }
+}
+
+pub struct NetworkStore {
+    nvs: EspNvs<NvsDefault>,
+    networks: Vec<KnownNetwork>,
+}
+
+impl NetworkStore {
+    /// Loads stored networks, `defaults` (usually from `cfg.toml`) are stored on the first boot.
+    /// Corrupted networks are logged and replaced with `defaults`.
+    pub fn load(nvs: EspNvs<NvsDefault>, defaults: &[KnownNetwork]) -> Result<Self, NetworksError> {
+        let mut buf = vec![0u8; nvs.blob_len(NVS_KEY)?.unwrap_or(1)];
+        let stored = match nvs.get_raw(NVS_KEY, &mut buf)? {
+            Some(blob) => decode(blob).map(Some).unwrap_or_else(|err| {
+                log::error!("Wifi networks: {err}, using defaults");
+                None
+            }),
+            None => None,
+        };
+
+        let mut store = NetworkStore {
+            nvs,
+            networks: stored.clone().unwrap_or_default(),
+        };
+        store.networks.truncate(MAX_NETWORKS);
+        if stored.is_none() {
+            store.networks = defaults.iter().take(MAX_NETWORKS).cloned().collect();
+            store.save()?;
+        }
+        log::info!("Wifi networks: {} known", store.networks.len());
+        Ok(store)
+    }
+
+    pub fn networks(&self) -> &[KnownNetwork] {
+        &self.networks
+    }
+
+    /// Adds the network or replaces the one with the same ssid, up to [`MAX_NETWORKS`].
+    pub fn add(&mut self, network: KnownNetwork) -> Result<(), NetworksError> {
+        let known = self.networks.iter().any(|n| n.ssid == network.ssid);
+        if !known && self.networks.len() >= MAX_NETWORKS {
+            return Err(NetworksError::InvalidNetwork(
+                "too many networks, remove one first",
+            ));
+        }
+        self.networks.retain(|n| n.ssid != network.ssid);
+        self.networks.push(network);
+        self.save()
+    }
+
+    /// Returns `false` if the network isn't known.
+    pub fn remove(&mut self, ssid: &str) -> Result<bool, NetworksError> {
+        let len = self.networks.len();
+        self.networks.retain(|n| n.ssid != ssid);
+        if self.networks.len() == len {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    fn save(&mut self) -> Result<(), NetworksError> {
+        let networks = &self.networks[..self.networks.len().min(MAX_NETWORKS)];
+        self.nvs.set_raw(NVS_KEY, &encode(networks))?;
+        Ok(())
+    }
+}
+
+/// Known network found by the scan.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candidate {
+    pub ssid: String,
+    pub password: String,
+    pub priority: u8,
+    /// Auth method to connect with, see [`station_auth`].
+    pub auth_method: AuthMethod,
+    /// The strongest AP of the network.
+    pub bssid: [u8; 6],
+    pub channel: u8,
+    pub rssi: i8,
+}
+
+impl Candidate {
+    /// Station configuration pinned to the found AP and channel.
+    pub fn client_configuration(&self) -> Result<Configuration, EspError> {
+        // too long ssid or password
+        let invalid = |_| EspError::from_infallible::<ESP_ERR_INVALID_ARG>();
+        Ok(Configuration::Client(ClientConfiguration {
+            ssid: self.ssid.parse().map_err(invalid)?,
+            bssid: Some(self.bssid),
+            auth_method: self.auth_method,
+            password: self.password.parse().map_err(invalid)?,
+            channel: Some(self.channel),
+        }))
+    }
+}
+
+/// Auth method of the station for the scanned AP, `None` if it isn't supported.
+///
+/// Station's auth method is the weakest accepted one, so mixed networks are joined
+/// with the older method, the driver negotiates the stronger one when it can.
+pub fn station_auth(scanned: AuthMethod) -> Option<AuthMethod> {
+    match scanned {
+        AuthMethod::None => Some(AuthMethod::None),
+        AuthMethod::WEP => Some(AuthMethod::WEP),
+        AuthMethod::WPA | AuthMethod::WPAWPA2Personal => Some(AuthMethod::WPA),
+        AuthMethod::WPA2Personal | AuthMethod::WPA2WPA3Personal => Some(AuthMethod::WPA2Personal),
+        AuthMethod::WPA3Personal => Some(AuthMethod::WPA3Personal),
+        AuthMethod::WPA2Enterprise | AuthMethod::WAPIPersonal => None,
+    }
+}
+
+/// Known networks found by the scan, the best first.
+pub fn select(known: &[KnownNetwork], scan: &[AccessPointInfo]) -> Vec<Candidate> {
+    let mut candidates: Vec<Candidate> = Vec::new();
+    for ap in scan {
+        let Some(network) = known.iter().find(|n| n.ssid == ap.ssid.as_str()) else {
+            continue;
+        };
+        let Some(auth_method) = station_auth(ap.auth_method) else {
+            log::warn!("Wifi '{}': {:?} is not supported", ap.ssid, ap.auth_method);
+            continue;
+        };
+        if auth_method != AuthMethod::None && network.password.is_empty() {
+            log::warn!("Wifi '{}' is protected, but the password is empty", ap.ssid);
+            continue;
+        }
+
+        let candidate = Candidate {
+            ssid: network.ssid.clone(),
+            password: network.password.clone(),
+            priority: network.priority,
+            auth_method,
+            bssid: ap.bssid,
+            channel: ap.channel,
+            rssi: ap.signal_strength,
+        };
+        // several APs of the same network, keep the strongest
+        match candidates.iter_mut().find(|c| c.ssid == candidate.ssid) {
+            Some(existing) if existing.rssi < candidate.rssi => *existing = candidate,
+            Some(_) => {}
+            None => candidates.push(candidate),
+        }
+    }
+
+    candidates.sort_by(|a, b| b.priority.cmp(&a.priority).then(b.rssi.cmp(&a.rssi)));
+    candidates
+}
+
+/// `version, (priority u8, ssid len u8, ssid, password len u8, password)*`.
+fn encode(networks: &[KnownNetwork]) -> Vec<u8> {
+    let mut blob = vec![FORMAT_VERSION];
+    for network in networks {
+        let ssid = &network.ssid.as_bytes()[..network.ssid.len().min(MAX_SSID_LEN)];
+        let password = &network.password.as_bytes()[..network.password.len().min(MAX_PASSWORD_LEN)];
+        blob.push(network.priority);
+        blob.push(ssid.len() as u8);
+        blob.extend_from_slice(ssid);
+        blob.push(password.len() as u8);
+        blob.extend_from_slice(password);
+    }
+    blob
+}
+
+fn decode(blob: &[u8]) -> Result<Vec<KnownNetwork>, NetworksError> {
+    let (version, mut rest) = blob.split_first().ok_or(NetworksError::Corrupted)?;
+    if *version != FORMAT_VERSION {
+        return Err(NetworksError::Corrupted);
+    }
+
+    // length prefixed string
+    fn take_str<'a>(rest: &mut &'a [u8]) -> Result<&'a str, NetworksError> {
+        let (len, tail) = rest.split_first().ok_or(NetworksError::Corrupted)?;
+        let len = *len as usize;
+        if tail.len() < len {
+            return Err(NetworksError::Corrupted);
+        }
+        let value = std::str::from_utf8(&tail[..len]).map_err(|_| NetworksError::Corrupted)?;
+        *rest = &tail[len..];
+        Ok(value)
+    }
+
+    let mut networks = Vec::new();
+    while let Some((priority, tail)) = rest.split_first() {
+        rest = tail;
+        let ssid = take_str(&mut rest)?;
+        let password = take_str(&mut rest)?;
+        networks.push(KnownNetwork {
+            ssid: ssid.to_string(),
+            password: password.to_string(),
+            priority: *priority,
+        });
+    }
+    Ok(networks)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::wifi::networks::{decode, encode, select, KnownNetwork};
+    use esp_idf_svc::wifi::{AccessPointInfo, AuthMethod};
+
+    fn ap(ssid: &str, auth_method: AuthMethod, rssi: i8, bssid: u8) -> AccessPointInfo {
+        AccessPointInfo {
+            ssid: ssid.into(),
+            bssid: [bssid; 6],
+            channel: 6,
+            signal_strength: rssi,
+            auth_method,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn encode_decode_test() {
+        let networks = vec![
+            KnownNetwork::new("lab", "secret password", 10).unwrap(),
+            KnownNetwork::new("guest", "", 0).unwrap(),
+        ];
+        let blob = encode(&networks);
+        assert_eq!(decode(&blob).unwrap(), networks);
+        assert!(decode(&blob[..blob.len() - 1]).is_err());
+        assert!(decode(&[]).is_err());
+        assert!(KnownNetwork::new("", "", 0).is_err());
+    }
+
+    #[test]
+    fn select_test() {
+        let known = vec![
+            KnownNetwork::new("lab", "secret", 10).unwrap(),
+            KnownNetwork::new("home", "secret", 5).unwrap(),
+            KnownNetwork::new("guest", "", 5).unwrap(),
+            KnownNetwork::new("office", "", 20).unwrap(),
+        ];
+        let scan = vec![
+            ap("guest", AuthMethod::None, -50, 1),
+            ap("home", AuthMethod::WPA2WPA3Personal, -70, 2),
+            ap("unknown", AuthMethod::WPA2Personal, -30, 3),
+            ap("lab", AuthMethod::WPA3Personal, -80, 4),
+            ap("lab", AuthMethod::WPA3Personal, -60, 5),
+            // protected, no password
+            ap("office", AuthMethod::WPA2Personal, -40, 6),
+        ];
+
+        let candidates = select(&known, &scan);
+        let order = candidates
+            .iter()
+            .map(|c| (c.ssid.as_str(), c.auth_method, c.bssid[0]))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            order,
+            vec![
+                ("lab", AuthMethod::WPA3Personal, 5),
+                ("guest", AuthMethod::None, 1),
+                ("home", AuthMethod::WPA2Personal, 2),
+            ]
+        );
+    }
+}
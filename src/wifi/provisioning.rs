@@ -0,0 +1,366 @@
+//! First-time WiFi setup without rebuilding the firmware.
+//!
+//! The board starts its own access point with a small web form (`http://192.168.71.1/`)
+//! that lists networks in range. Submitted network is stored into [`NetworkStore`], then
+//! the board should reboot into station mode. With `captive_dns` every DNS name resolves
+//! to the board, so phones open the form by themselves.
+
+use super::networks::{KnownNetwork, NetworkStore, NetworksError};
+use super::{WifiState, WifiStatus};
+use crate::lock_recover;
+use embedded_svc::io::Write;
+use esp_idf_svc::hal::delay::FreeRtos;
+use esp_idf_svc::http::server::{Configuration as HttpConfiguration, EspHttpServer};
+use esp_idf_svc::http::Method;
+use esp_idf_svc::sys::{EspError, ESP_ERR_INVALID_ARG};
+use esp_idf_svc::wifi::{
+    AccessPointConfiguration, AccessPointInfo, AuthMethod, BlockingWifi, ClientConfiguration,
+    Configuration, EspWifi,
+};
+use std::fmt::Write as _;
+use std::net::{Ipv4Addr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Longer form is rejected.
+const MAX_FORM_LEN: usize = 512;
+const DNS_TTL_SECS: u32 = 60;
+
+#[derive(Debug, Clone)]
+pub struct ProvisioningConfig {
+    pub ap_ssid: String,
+    /// Empty for the open access point, otherwise at least 8 characters.
+    pub ap_password: String,
+    pub channel: u8,
+    /// Answer every DNS query with the board address.
+    pub captive_dns: bool,
+    /// Failed attempts to connect to known networks before provisioning starts.
+    pub max_failures: u32,
+}
+
+impl Default for ProvisioningConfig {
+    fn default() -> Self {
+        ProvisioningConfig {
+            ap_ssid: "esp32-c3-setup".to_string(),
+            ap_password: String::new(),
+            channel: 1,
+            captive_dns: true,
+            max_failures: 5,
+        }
+    }
+}
+
+/// Provisioning is needed when no networks are known or the known ones keep failing.
+pub fn is_needed(networks: &[KnownNetwork], status: &WifiStatus, max_failures: u32) -> bool {
+    networks.is_empty()
+        || matches!(status.state, WifiState::Disconnected { attempts } if attempts >= max_failures)
+}
+
+/// Serves the form until a network is submitted and stored, then returns.
+/// The station is started again, but not connected, reboot after this.
+pub fn run(
+    wifi: &mut BlockingWifi<EspWifi<'static>>,
+    store: NetworkStore,
+    config: &ProvisioningConfig,
+) -> Result<(), EspError> {
+    let invalid = |_| EspError::from_infallible::<ESP_ERR_INVALID_ARG>();
+    let ap = AccessPointConfiguration {
+        ssid: config.ap_ssid.parse().map_err(invalid)?,
+        password: config.ap_password.parse().map_err(invalid)?,
+        auth_method: if config.ap_password.is_empty() {
+            AuthMethod::None
+        } else {
+            AuthMethod::WPA2Personal
+        },
+        channel: config.channel,
+        ..Default::default()
+    };
+    // the station is kept for scanning
+    let _ = wifi.stop();
+    wifi.set_configuration(&Configuration::Mixed(ClientConfiguration::default(), ap))?;
+    wifi.start()?;
+
+    let scan = wifi.scan().unwrap_or_else(|err| {
+        log::warn!("Provisioning: scan failed: {err}");
+        Vec::new()
+    });
+    let ip = wifi.wifi().ap_netif().get_ip_info()?.ip;
+    log::info!(
+        "Provisioning: connect to '{}' and open http://{ip}/",
+        config.ap_ssid
+    );
+
+    let saved = Arc::new(AtomicBool::new(false));
+    let store = Arc::new(Mutex::new(store));
+    let page = form_page(&scan);
+    let portal = format!("http://{ip}/");
+
+    let mut server = EspHttpServer::new(&HttpConfiguration {
+        uri_match_wildcard: true,
+        ..Default::default()
+    })
+    .map_err(|err| err.0)?;
+    server.fn_handler("/", Method::Get, move |req| {
+        req.into_ok_response()?.write_all(page.as_bytes())?;
+        Ok(())
+    })?;
+    let done = saved.clone();
+    server.fn_handler("/save", Method::Post, move |mut req| {
+        let mut buf = [0u8; MAX_FORM_LEN];
+        let len = embedded_svc::utils::io::try_read_full(&mut req, &mut buf).map_err(|e| e.0)?;
+        let network = match parse_form(&buf[..len]) {
+            Ok(network) => network,
+            Err(err) => {
+                req.into_status_response(400)?
+                    .write_all(err.to_string().as_bytes())?;
+                return Ok(());
+            }
+        };
+        log::info!("Provisioning: network '{}' saved", network.ssid);
+        lock_recover(&store).add(network)?;
+        req.into_ok_response()?
+            .write_all(b"Saved, the board reboots and joins the network.")?;
+        done.store(true, Ordering::Relaxed);
+        Ok(())
+    })?;
+    // captive portal checks of phones and laptops land on the form
+    server.fn_handler("/*", Method::Get, move |req| {
+        req.into_response(302, Some("Found"), &[("Location", portal.as_str())])?;
+        Ok(())
+    })?;
+
+    // the form works without DNS too, its failures are only logged
+    let dns = if config.captive_dns {
+        let stop = saved.clone();
+        std::thread::Builder::new()
+            .name("captive_dns".to_string())
+            .stack_size(4096)
+            .spawn(move || {
+                if let Err(err) = serve_dns(ip, &stop) {
+                    log::warn!("Provisioning: DNS failed: {err}");
+                }
+            })
+            .map_err(|err| log::warn!("Provisioning: DNS isn't started: {err}"))
+            .ok()
+    } else {
+        None
+    };
+
+    while !saved.load(Ordering::Relaxed) {
+        FreeRtos::delay_ms(200);
+    }
+    // let the response go out
+    FreeRtos::delay_ms(1000);
+    drop(server);
+    if let Some(dns) = dns {
+        let _ = dns.join();
+    }
+    Ok(())
+}
+
+/// Answers DNS queries until `stop` is set.
+fn serve_dns(ip: Ipv4Addr, stop: &AtomicBool) -> std::io::Result<()> {
+    let socket = UdpSocket::bind("0.0.0.0:53")?;
+    socket.set_read_timeout(Some(Duration::from_millis(500)))?;
+    let mut buf = [0u8; 512];
+    while !stop.load(Ordering::Relaxed) {
+        let Ok((len, from)) = socket.recv_from(&mut buf) else {
+            continue;
+        };
+        if let Some(reply) = dns_reply(&buf[..len], ip) {
+            socket.send_to(&reply, from)?;
+        }
+    }
+    Ok(())
+}
+
+/// Answers A question of the standard query with `ip`, other questions get empty answer.
+/// `None` if the packet isn't a query.
+fn dns_reply(query: &[u8], ip: Ipv4Addr) -> Option<Vec<u8>> {
+    const HEADER_LEN: usize = 12;
+    const TYPE_A: u16 = 1;
+
+    if query.len() < HEADER_LEN {
+        return None;
+    }
+    let is_response = query[2] & 0x80 != 0;
+    let opcode = (query[2] >> 3) & 0x0F;
+    let questions = u16::from_be_bytes([query[4], query[5]]);
+    if is_response || opcode != 0 || questions != 1 {
+        return None;
+    }
+
+    // name is a sequence of length prefixed labels
+    let mut pos = HEADER_LEN;
+    loop {
+        let len = *query.get(pos)? as usize;
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+        if len & 0xC0 != 0 {
+            // compression isn't allowed in the question
+            return None;
+        }
+        pos += len;
+    }
+    let qtype = u16::from_be_bytes([*query.get(pos)?, *query.get(pos + 1)?]);
+    let end = pos + 4;
+    if query.len() < end {
+        return None;
+    }
+
+    let mut reply = query[..end].to_vec();
+    // response, authoritative, keep recursion desired
+    reply[2] = 0x84 | (query[2] & 0x01);
+    reply[3] = 0;
+    let answers: u16 = if qtype == TYPE_A { 1 } else { 0 };
+    reply[6..8].copy_from_slice(&answers.to_be_bytes());
+    reply[8..12].fill(0);
+    if qtype == TYPE_A {
+        // pointer to the name in the question
+        reply.extend_from_slice(&[0xC0, HEADER_LEN as u8]);
+        reply.extend_from_slice(&TYPE_A.to_be_bytes());
+        reply.extend_from_slice(&1u16.to_be_bytes());
+        reply.extend_from_slice(&DNS_TTL_SECS.to_be_bytes());
+        reply.extend_from_slice(&4u16.to_be_bytes());
+        reply.extend_from_slice(&ip.octets());
+    }
+    Some(reply)
+}
+
+/// `ssid=..&password=..&priority=..`, url encoded, priority is optional.
+fn parse_form(body: &[u8]) -> Result<KnownNetwork, NetworksError> {
+    let (mut ssid, mut password, mut priority) = (String::new(), String::new(), 0);
+    for (key, value) in url::form_urlencoded::parse(body) {
+        match key.as_ref() {
+            "ssid" => ssid = value.trim().to_string(),
+            "password" => password = value.into_owned(),
+            "priority" if !value.is_empty() => {
+                priority = value
+                    .parse()
+                    .map_err(|_| NetworksError::InvalidNetwork("priority should be 0-255"))?;
+            }
+            _ => {}
+        }
+    }
+    KnownNetwork::new(&ssid, &password, priority)
+}
+
+fn form_page(scan: &[AccessPointInfo]) -> String {
+    let mut page = String::from(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\">\
+         <meta name=\"viewport\" content=\"width=device-width\">\
+         <title>WiFi setup</title></head><body><h1>WiFi setup</h1>\
+         <form method=\"post\" action=\"/save\">\
+         <p><input name=\"ssid\" list=\"networks\" placeholder=\"Network\" required></p>\
+         <p><input name=\"password\" type=\"password\" placeholder=\"Password\"></p>\
+         <p><input name=\"priority\" type=\"number\" min=\"0\" max=\"255\" placeholder=\"Priority\"></p>\
+         <p><button>Save</button></p></form><datalist id=\"networks\">",
+    );
+    // the strongest AP of each network, the strongest network first
+    let mut networks = scan.iter().collect::<Vec<_>>();
+    networks.sort_by(|a, b| {
+        a.ssid
+            .cmp(&b.ssid)
+            .then(b.signal_strength.cmp(&a.signal_strength))
+    });
+    networks.dedup_by(|a, b| a.ssid == b.ssid);
+    networks.sort_by_key(|ap| std::cmp::Reverse(ap.signal_strength));
+    for ap in networks.iter().filter(|ap| !ap.ssid.is_empty()) {
+        let _ = write!(
+            page,
+            "<option value=\"{0}\">{0} ({1} dBm)</option>",
+            html_escape(&ap.ssid),
+            ap.signal_strength
+        );
+    }
+    page.push_str("</datalist></body></html>");
+    page
+}
+
+fn html_escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::wifi::networks::KnownNetwork;
+    use crate::wifi::provisioning::{dns_reply, html_escape, is_needed, parse_form};
+    use crate::wifi::{WifiState, WifiStatus};
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn is_needed_test() {
+        let known = vec![KnownNetwork::new("lab", "secret", 0).unwrap()];
+        let failing = |attempts| WifiStatus {
+            state: WifiState::Disconnected { attempts },
+            ..Default::default()
+        };
+        assert!(is_needed(&[], &WifiStatus::default(), 5));
+        assert!(!is_needed(&known, &WifiStatus::default(), 5));
+        assert!(!is_needed(&known, &failing(4), 5));
+        assert!(is_needed(&known, &failing(5), 5));
+    }
+
+    #[test]
+    fn parse_form_test() {
+        assert_eq!(
+            parse_form(b"ssid=My+Lab&password=p%40ss%26word&priority=").unwrap(),
+            KnownNetwork::new("My Lab", "p@ss&word", 0).unwrap()
+        );
+        assert_eq!(parse_form(b"ssid=lab&priority=7").unwrap().priority, 7);
+        assert!(parse_form(b"password=secret").is_err());
+        assert!(parse_form(b"ssid=lab&priority=300").is_err());
+    }
+
+    #[test]
+    fn dns_reply_test() {
+        let ip = Ipv4Addr::new(192, 168, 71, 1);
+        // id 0x1234, recursion desired, A question for "a.io"
+        let query = [
+            0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0, 1, b'a', 2, b'i', b'o', 0, 0, 1, 0, 1,
+        ];
+        let reply = dns_reply(&query, ip).unwrap();
+        assert_eq!(&reply[..4], &[0x12, 0x34, 0x85, 0x00]);
+        assert_eq!(&reply[4..12], &[0, 1, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(&reply[12..22], &query[12..]);
+        assert_eq!(
+            &reply[22..],
+            &[0xC0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 168, 71, 1]
+        );
+
+        // AAAA question, no answers
+        let mut aaaa = query;
+        aaaa[19] = 28;
+        let reply = dns_reply(&aaaa, ip).unwrap();
+        assert_eq!(&reply[6..8], &[0, 0]);
+        assert_eq!(reply.len(), query.len());
+
+        // response and truncated packets are ignored
+        let mut response = query;
+        response[2] |= 0x80;
+        assert!(dns_reply(&response, ip).is_none());
+        assert!(dns_reply(&query[..20], ip).is_none());
+    }
+
+    #[test]
+    fn html_escape_test() {
+        assert_eq!(
+            html_escape("<a href=\"x\">&'"),
+            "&lt;a href=&quot;x&quot;&gt;&amp;&#39;"
+        );
+    }
+}